@@ -0,0 +1,172 @@
+use uuid::Uuid;
+
+use common::{ClientMessage, ServerMessage, PeekedObject};
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use {Client, Error, Result, QueueId};
+
+/// An object looked at in its queue, without reading it.
+pub struct Peeked {
+    pub id: Uuid,
+    pub data: Vec<u8>,
+
+    /// The number of times this object has been read so far.
+    pub deliveries: u32,
+
+    /// The priority this object was sent with.
+    pub priority: u8,
+
+    /// When this object expires, in milliseconds since the Unix epoch.
+    pub expires: Option<u64>,
+
+    /// When this object became ready to read, in milliseconds since the
+    /// Unix epoch.
+    pub enqueued: u64
+}
+
+/// Looks through the objects in a queue a page at a time, in the order they
+/// would be read, without reading them.
+///
+/// Created using `Client::browse`. Each page starts after the last object
+/// of the previous page, so objects read or sent while browsing do not
+/// shift the objects returned, and none are skipped. If that object is read
+/// before the next page is fetched, browsing ends with `Error::CursorLost`.
+pub struct Browser<'a, S: Read + Write + 'a> {
+    client: &'a mut Client<S>,
+    queue: QueueId<'static>,
+
+    /// The most objects to fetch at once.
+    page: u32,
+
+    /// The id of the last object fetched, which the next page starts after.
+    after: Option<Uuid>,
+
+    /// The objects fetched but not yet returned.
+    fetched: VecDeque<Peeked>,
+
+    /// Set once the end of the queue is reached, or fetching fails.
+    done: bool
+}
+
+impl<S: Read + Write> Client<S> {
+    /// Look at up to `count` objects, at most `MAX_BATCH`, in an existing
+    /// queue, in the order they would be read, after skipping the first
+    /// `offset`.
+    ///
+    /// The objects stay in the queue and their deliveries are not counted.
+    pub fn peek(&mut self, queue: QueueId, offset: u32, count: u32) -> Result<Vec<Peeked>> {
+        let message = ClientMessage::Peek(queue.0.clone(), offset, count);
+        let (objects, _) = try!(self.peek_message(&queue, message));
+        Ok(objects)
+    }
+
+    /// Browse an existing queue, fetching `page` objects, at most `MAX_BATCH`,
+    /// at a time.
+    pub fn browse<'a>(&'a mut self, queue: QueueId, page: u32) -> Browser<'a, S> {
+        Browser {
+            client: self,
+            queue: QueueId(queue.0.to_owned()),
+            page: page,
+            after: None,
+            fetched: VecDeque::new(),
+            done: false
+        }
+    }
+
+    /// Send a Peek or Browse message, and interpret the response, along with
+    /// the id of the last object in it.
+    ///
+    /// Large objects are fetched in chunks, which must happen before the next
+    /// Peek or Browse, and are left out if the server no longer has them.
+    fn peek_message(&mut self, queue: &QueueId, message: ClientMessage)
+            -> Result<(Vec<Peeked>, Option<Uuid>)> {
+        let objects = match try!(self.send_message(message)) {
+            ServerMessage::Peeked(objects) => objects,
+            ServerMessage::NoSuchEntity =>
+                return Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            ServerMessage::CursorLost => return Err(Error::CursorLost),
+            _ => panic!("Received incorrect message from the server.")
+        };
+
+        let last = objects.last().map(|object| object.id);
+        let mut peeked = Vec::with_capacity(objects.len());
+
+        for object in objects {
+            let PeekedObject { id, data, len, deliveries, priority, expires, enqueued } = object;
+
+            let data = match data {
+                Some(data) => data,
+                None => match try!(self.download_peeked(queue, id, len)) {
+                    Some(data) => data,
+                    None => continue
+                }
+            };
+
+            peeked.push(Peeked {
+                id: id,
+                data: data,
+                deliveries: deliveries,
+                priority: priority,
+                expires: expires,
+                enqueued: enqueued
+            });
+        }
+
+        Ok((peeked, last))
+    }
+
+    /// Fetch a large peeked object in chunks, or None if the server no longer
+    /// has it.
+    fn download_peeked(&mut self, queue: &QueueId, id: Uuid, len: u64) -> Result<Option<Vec<u8>>> {
+        let mut data = Vec::with_capacity(len as usize);
+
+        while (data.len() as u64) < len {
+            let message = ClientMessage::PeekChunk(queue.0.clone(), id, data.len() as u64);
+            match try!(self.send_message(message)) {
+                ServerMessage::Chunk(chunk) => {
+                    let chunk = chunk.take();
+                    if chunk.len() == 0 { return Ok(None) }
+                    data.extend(chunk.into_iter());
+                },
+                ServerMessage::NoSuchEntity => return Ok(None),
+                _ => panic!("Received incorrect message from the server.")
+            }
+        }
+
+        Ok(Some(data))
+    }
+}
+
+impl<'a, S: Read + Write> Browser<'a, S> {
+    /// Fetch the next page of objects.
+    fn fetch(&mut self) -> Result<()> {
+        let message = ClientMessage::Browse(self.queue.0.clone(), self.after, self.page);
+        let (objects, last) = try!(self.client.peek_message(&self.queue, message));
+
+        match last {
+            Some(last) => self.after = Some(last),
+            None => self.done = true
+        }
+
+        self.fetched.extend(objects.into_iter());
+        Ok(())
+    }
+}
+
+impl<'a, S: Read + Write> Iterator for Browser<'a, S> {
+    type Item = Result<Peeked>;
+
+    fn next(&mut self) -> Option<Result<Peeked>> {
+        // Every object on a page may have been left out while it was fetched.
+        while self.fetched.is_empty() && !self.done {
+            if let Err(e) = self.fetch() {
+                self.done = true;
+                return Some(Err(e))
+            }
+        }
+
+        self.fetched.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,144 @@
+use uuid::Uuid;
+
+use common::{ClientMessage, ServerMessage, RequestId};
+
+use std::io::{Read, Write};
+
+use {Client, Error, Result, Message, QueueId};
+
+/// Receives objects which the server pushes from a queue as they arrive,
+/// instead of reading them one request at a time.
+///
+/// Created using `Client::subscribe`. The Client cannot be used for other
+/// requests until the Consumer is dropped or unsubscribed, which stops the
+/// subscription.
+pub struct Consumer<'a, S: Read + Write + 'a> {
+    client: &'a mut Client<S>,
+    queue: QueueId<'static>,
+
+    /// The id of the Subscribe request, which every pushed object carries.
+    subscription: RequestId,
+
+    /// Cleared once the subscription is stopped.
+    subscribed: bool
+}
+
+impl<S: Read + Write> Client<S> {
+    /// Subscribe to an existing queue, so the server pushes objects from it
+    /// as they arrive, each read as if by `read_ms` with the given timeout.
+    ///
+    /// At most `prefetch` objects are pushed which have not been confirmed,
+    /// nacked, or had their timeout elapse, and `prefetch` must be at least
+    /// 1, or `Error::BadRequest` is returned. The Consumer should be used to
+    /// confirm or nack objects, since objects confirmed from another Client
+    /// only make room for more when their timeout elapses.
+    pub fn subscribe<'a>(&'a mut self, queue: QueueId, prefetch: u32,
+                         timeout: u64) -> Result<Consumer<'a, S>> {
+        let message = ClientMessage::Subscribe(queue.0.clone(), prefetch, timeout);
+        let request = try!(self.pipeline.send(&message));
+
+        match try!(self.pipeline.receive_for(request)) {
+            ServerMessage::Subscribed => Ok(Consumer {
+                client: self,
+                queue: QueueId(queue.0.to_owned()),
+                subscription: request,
+                subscribed: true
+            }),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            ServerMessage::BadRequest => Err(Error::BadRequest),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+}
+
+impl<'a, S: Read + Write> Consumer<'a, S> {
+    /// Wait for the next object to be pushed.
+    ///
+    /// If the queue is deleted, the subscription ends and `Error::NoQueue`
+    /// is returned.
+    pub fn receive(&mut self) -> Result<Message> {
+        loop {
+            let (request, message) = try!(self.client.pipeline.receive_push());
+
+            // Pushed to an earlier subscription on this connection.
+            if request != self.subscription {
+                try!(self.give_back(message));
+                continue
+            }
+
+            return match message {
+                ServerMessage::Read(id, data, deliveries) =>
+                    Ok(Message { id: id, data: data.take(), deliveries: deliveries }),
+                ServerMessage::ReadChunked(id, len, deliveries) => {
+                    let data = try!(self.client.download(id, len));
+                    Ok(Message { id: id, data: data, deliveries: deliveries })
+                },
+                ServerMessage::NoSuchEntity => {
+                    self.subscribed = false;
+                    Err(Error::NoQueue(self.queue.clone()))
+                },
+                _ => panic!("Received incorrect message from the server.")
+            }
+        }
+    }
+
+    /// Confirm a pushed object, making room for another to be pushed.
+    pub fn confirm(&mut self, entity_id: Uuid) -> Result<()> {
+        self.client.confirm(entity_id)
+    }
+
+    /// Give back a pushed object without processing it, as if by
+    /// `Client::nack`, making room for another to be pushed.
+    pub fn nack(&mut self, entity_id: Uuid, delay: u64) -> Result<()> {
+        self.client.nack(entity_id, delay)
+    }
+
+    /// Extend the timeout on a pushed object, as if by `Client::extend_lease`.
+    pub fn extend_lease(&mut self, entity_id: Uuid, timeout: u64) -> Result<()> {
+        self.client.extend_lease(entity_id, timeout)
+    }
+
+    /// Stop the subscription, giving back any objects which were pushed but
+    /// not yet received.
+    pub fn unsubscribe(mut self) -> Result<()> {
+        self.subscribed = false;
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        match try!(self.client.send_message(ClientMessage::Unsubscribe(self.queue.0.clone()))) {
+            // The subscription already ended because the queue was deleted.
+            ServerMessage::Unsubscribed | ServerMessage::NoSuchEntity => {},
+            _ => panic!("Received incorrect message from the server.")
+        }
+
+        // Nothing more is pushed after the server answers, so every object
+        // which was pushed has arrived by now.
+        for (_, message) in self.client.pipeline.take_pushed() {
+            try!(self.give_back(message));
+        }
+
+        Ok(())
+    }
+
+    /// Nack a pushed object which will not be received.
+    fn give_back(&mut self, message: ServerMessage) -> Result<()> {
+        let id = match message {
+            ServerMessage::Read(id, _, _) | ServerMessage::ReadChunked(id, _, _) => id,
+            _ => return Ok(())
+        };
+
+        match self.client.nack(id, 0) {
+            // Already requeued because its timeout elapsed.
+            Ok(()) | Err(Error::NoObject(_)) => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl<'a, S: Read + Write> Drop for Consumer<'a, S> {
+    fn drop(&mut self) {
+        if self.subscribed { let _ = self.stop(); }
+    }
+}
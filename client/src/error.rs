@@ -1,5 +1,5 @@
 use uuid::Uuid;
-use common::{DecodingError, EncodingError};
+use common::{DecodingError, EncodingError, ProtocolError};
 use std::io;
 
 use {QueueId};
@@ -10,10 +10,24 @@ pub enum Error {
     Decoding(DecodingError),
     NoQueue(QueueId<'static>),
     NoObject(Uuid),
+    Leased(Uuid),
+    NoTopic(String),
+    NotBound,
     Requeued,
     Empty,
     Full(Uuid, Vec<u8>),
+    BatchFull(Vec<Uuid>),
+    NotConfirmed(Vec<Uuid>),
+    PublishFull(Vec<Uuid>, Vec<QueueId<'static>>),
+    TooLarge,
+    OverBudget,
+    TimeoutTooShort(u64),
     NoResponseExpected,
+    UnexpectedResponse,
+    BadRequest,
+    CursorLost,
+    Disconnected,
+    Protocol(ProtocolError),
     Io(io::Error)
 }
 
@@ -27,6 +41,16 @@ impl From<EncodingError> for Error {
     fn from(err: EncodingError) -> Error { Error::Encoding(err) }
 }
 
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Error {
+        match err {
+            ProtocolError::Decoding(err) => Error::Decoding(err),
+            ProtocolError::Io(err) => Error::Io(err),
+            err => Error::Protocol(err)
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error { Error::Io(err) }
 }
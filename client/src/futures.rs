@@ -0,0 +1,322 @@
+use eventual::{Async, AsyncError, Future, Complete};
+use uuid::Uuid;
+
+use common::{ClientMessage, ServerMessage, StrBox, SliceBox, RequestId, CHUNK_LEN};
+
+use std::collections::HashMap;
+use std::net::{ToSocketAddrs, TcpStream, Shutdown};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{cmp, mem, thread};
+
+use {Error, Result, Message, QueueId, Pipeline};
+use pipeline;
+
+/// A Client whose operations return Futures instead of blocking.
+///
+/// All operations share one connection. Responses are read by a background
+/// thread, and the Future of each request is completed on a second thread as
+/// its response arrives, so any number of operations can be in flight at
+/// once. Callbacks on the Futures run on that second thread, and may send
+/// more requests without holding up the responses to other requests.
+///
+/// AsyncClient can be cloned, and the clones shared between threads. The
+/// connection is closed when the last clone is dropped.
+#[derive(Clone)]
+pub struct AsyncClient {
+    inner: Arc<Inner>
+}
+
+struct Inner {
+    writer: Mutex<Writer>,
+
+    /// Shared with the background reader.
+    pending: Arc<Mutex<Pending>>,
+
+    /// The features agreed on with the server in the handshake.
+    features: Vec<String>,
+
+    /// Completed once the last upload started on this connection is over,
+    /// since the server only takes one upload at a time.
+    uploads: Mutex<Future<(), ()>>
+}
+
+struct Writer {
+    stream: TcpStream,
+
+    /// The id of the next request.
+    next: RequestId,
+
+    /// The longest message the server accepts.
+    max_message_len: u64
+}
+
+struct Pending {
+    /// The requests waiting for a response.
+    waiting: HashMap<RequestId, Complete<ServerMessage<'static>, Error>>,
+
+    /// Set once the connection is closed, and no more responses can arrive.
+    closed: bool
+}
+
+impl AsyncClient {
+    /// Connect to an existing server, and start reading responses on a
+    /// background thread.
+    pub fn connect<T: ToSocketAddrs>(addr: T) -> Result<AsyncClient> {
+        let mut pipeline = Pipeline::new(try!(TcpStream::connect(addr)));
+        try!(pipeline.handshake());
+
+        let features = pipeline.features().to_vec();
+        let max_message_len = pipeline.max_message_len();
+        let stream = pipeline.into_stream();
+        let reader = try!(stream.try_clone());
+
+        let pending = Arc::new(Mutex::new(Pending {
+            waiting: HashMap::new(),
+            closed: false
+        }));
+
+        let reader_pending = pending.clone();
+        thread::spawn(move || read(reader, reader_pending));
+
+        Ok(AsyncClient {
+            inner: Arc::new(Inner {
+                writer: Mutex::new(Writer {
+                    stream: stream,
+                    next: 0,
+                    max_message_len: max_message_len
+                }),
+                pending: pending,
+                features: features,
+                uploads: Mutex::new(Future::of(()))
+            })
+        })
+    }
+
+    /// The optional features of the protocol the server agreed to use.
+    pub fn features(&self) -> &[String] { &self.inner.features }
+
+    /// Create a new queue.
+    pub fn create(&self, queue_name: &str) -> Future<QueueId<'static>, Error> {
+        let name = QueueId::from(queue_name.to_string());
+        self.inner.request(&ClientMessage::CreateQueue(StrBox::new(queue_name)))
+            .and_then(move |response| match response {
+                ServerMessage::QueueCreated => Future::of(name),
+                _ => Future::error(Error::UnexpectedResponse)
+            })
+    }
+
+    /// Delete an existing queue.
+    pub fn delete(&self, queue: QueueId) -> Future<(), Error> {
+        let name = QueueId(queue.0.to_owned());
+        self.inner.request(&ClientMessage::DeleteQueue(queue.0.clone()))
+            .and_then(move |response| match response {
+                ServerMessage::QueueDeleted => Future::of(()),
+                ServerMessage::NoSuchEntity => Future::error(Error::NoQueue(name)),
+                _ => Future::error(Error::UnexpectedResponse)
+            })
+    }
+
+    /// Send an object to an existing queue on the server.
+    ///
+    /// Objects larger than `CHUNK_LEN` are uploaded in chunks, after any
+    /// other uploads sent on this connection.
+    pub fn send(&self, queue: QueueId, data: &[u8]) -> Future<Uuid, Error> {
+        if data.len() > CHUNK_LEN { return self.upload(queue, data) }
+
+        let name = QueueId(queue.0.to_owned());
+        self.inner.request(&ClientMessage::Enqueue(queue.0.clone(), SliceBox::new(data)))
+            .and_then(move |response| match response {
+                ServerMessage::ObjectQueued(id) => Future::of(id),
+                ServerMessage::Full(id, data) => Future::error(Error::Full(id, data.take())),
+                ServerMessage::OverBudget => Future::error(Error::OverBudget),
+                ServerMessage::NoSuchEntity => Future::error(Error::NoQueue(name)),
+                _ => Future::error(Error::UnexpectedResponse)
+            })
+    }
+
+    /// Request an object from an existing queue.
+    ///
+    /// Timeouts are given in milliseconds, as for `Client::read_ms`.
+    pub fn read_ms(&self, queue: QueueId, timeout: u64) -> Future<Message, Error> {
+        let name = QueueId(queue.0.to_owned());
+        let inner = self.inner.clone();
+
+        self.inner.request(&ClientMessage::Read(queue.0.clone(), timeout))
+            .and_then(move |response| match response {
+                ServerMessage::Read(id, data, deliveries) =>
+                    Future::of(Message { id: id, data: data.take(), deliveries: deliveries }),
+                ServerMessage::ReadChunked(id, len, deliveries) =>
+                    download(inner, id, Vec::with_capacity(len as usize), len).map(move |data| {
+                        Message { id: id, data: data, deliveries: deliveries }
+                    }),
+                ServerMessage::Empty => Future::error(Error::Empty),
+                ServerMessage::NoSuchEntity => Future::error(Error::NoQueue(name)),
+                _ => Future::error(Error::UnexpectedResponse)
+            })
+    }
+
+    /// Confirm that we have processed a message to the point that it should not
+    /// be requeued.
+    pub fn confirm(&self, entity_id: Uuid) -> Future<(), Error> {
+        self.inner.request(&ClientMessage::Confirm(entity_id))
+            .and_then(move |response| match response {
+                ServerMessage::Confirmed => Future::of(()),
+                ServerMessage::Requeued => Future::error(Error::Requeued),
+                ServerMessage::Full(id, data) => Future::error(Error::Full(id, data.take())),
+                ServerMessage::NoSuchEntity => Future::error(Error::NoObject(entity_id)),
+                _ => Future::error(Error::UnexpectedResponse)
+            })
+    }
+}
+
+impl AsyncClient {
+    /// Upload a large object in chunks, then enqueue it, once the upload
+    /// sent before it is over.
+    fn upload(&self, queue: QueueId, data: &[u8]) -> Future<Uuid, Error> {
+        let (done, next) = Future::pair();
+        let previous = mem::replace(&mut *self.inner.uploads.lock().unwrap(), next);
+
+        let (complete, uploaded) = Future::pair();
+        let inner = self.inner.clone();
+        let name = QueueId(queue.0.to_owned());
+        let data = data.to_vec();
+
+        // Whether the previous upload succeeded or not, it no longer uses
+        // the connection.
+        previous.receive(move |_| {
+            upload(inner, name, data, 0).receive(move |result| {
+                done.complete(());
+                match result {
+                    Ok(id) => complete.complete(id),
+                    Err(AsyncError::Failed(e)) => complete.fail(e),
+
+                    // Dropping `complete` aborts the upload's Future too.
+                    Err(AsyncError::Aborted) => {}
+                }
+            })
+        });
+
+        uploaded
+    }
+}
+
+impl Inner {
+    /// Send a request, returning a Future of its response.
+    fn request(&self, message: &ClientMessage) -> Future<ServerMessage<'static>, Error> {
+        if let Err(e) = pipeline::agreed(&self.features, message) { return Future::error(e) }
+
+        let mut guard = self.writer.lock().unwrap();
+        let writer = &mut *guard;
+
+        let request = writer.next;
+        writer.next = writer.next.wrapping_add(1);
+
+        // Register the request before sending it, so the reader can not
+        // receive the response before we are waiting for it.
+        let (complete, future) = Future::pair();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed { return Future::error(Error::Disconnected) }
+            pending.waiting.insert(request, complete);
+        }
+
+        if let Err(e) = message.encode_to(&mut writer.stream, request, writer.max_message_len) {
+            if let Some(complete) = self.pending.lock().unwrap().waiting.remove(&request) {
+                complete.fail(Error::from(e));
+            }
+        }
+
+        future
+    }
+}
+
+impl Drop for Inner {
+    /// Close the connection, which also stops the background reader.
+    fn drop(&mut self) {
+        let _ = self.writer.lock().unwrap().stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Fetch the rest of a large read object in chunks, one at a time.
+fn download(inner: Arc<Inner>, id: Uuid, mut data: Vec<u8>, len: u64) -> Future<Vec<u8>, Error> {
+    if data.len() as u64 >= len { return Future::of(data) }
+
+    inner.request(&ClientMessage::ReadChunk(id, data.len() as u64))
+        .and_then(move |response| match response {
+            ServerMessage::Chunk(chunk) => {
+                let chunk = chunk.take();
+                if chunk.len() == 0 { return Future::error(Error::NoObject(id)) }
+
+                data.extend(chunk.into_iter());
+                download(inner, id, data, len)
+            },
+            ServerMessage::NoSuchEntity => Future::error(Error::NoObject(id)),
+            _ => Future::error(Error::UnexpectedResponse)
+        })
+}
+
+/// Upload the rest of a large object in chunks, one at a time, starting at
+/// `offset`, then enqueue it.
+fn upload(inner: Arc<Inner>, queue: QueueId<'static>, data: Vec<u8>,
+          offset: usize) -> Future<Uuid, Error> {
+    if offset >= data.len() {
+        let enqueued = inner.request(&ClientMessage::EnqueueUpload(queue.0.clone()));
+        return enqueued.and_then(move |response| match response {
+            ServerMessage::ObjectQueued(id) => Future::of(id),
+
+            // The server does not send large objects back when the queue is
+            // full.
+            ServerMessage::Full(id, _) => Future::error(Error::Full(id, data)),
+            ServerMessage::OverBudget => Future::error(Error::OverBudget),
+            ServerMessage::NoSuchEntity => Future::error(Error::NoQueue(queue)),
+            _ => Future::error(Error::UnexpectedResponse)
+        })
+    }
+
+    let end = cmp::min(offset + CHUNK_LEN, data.len());
+    let received = inner.request(&ClientMessage::Chunk(SliceBox::new(&data[offset..end])));
+    received.and_then(move |response| match response {
+        ServerMessage::ChunkReceived => upload(inner, queue, data, end),
+        ServerMessage::TooLarge => Future::error(Error::TooLarge),
+        ServerMessage::OverBudget => Future::error(Error::OverBudget),
+        _ => Future::error(Error::UnexpectedResponse)
+    })
+}
+
+/// Read responses until the connection is closed, and pass each one to the
+/// completer thread along with the request waiting for it.
+fn read(mut stream: TcpStream, pending: Arc<Mutex<Pending>>) {
+    // Callbacks on the Futures may send requests, which can block until the
+    // server reads them, so they must not hold up reading its responses.
+    let (completions, received) = mpsc::channel();
+    thread::spawn(move || complete(received));
+
+    while let Ok((message, request)) = ServerMessage::decode_from(&mut stream) {
+        let complete = pending.lock().unwrap().waiting.remove(&request);
+        if let Some(complete) = complete {
+            let _ = completions.send((complete, Ok(message)));
+        }
+    }
+
+    let waiting = {
+        let mut pending = pending.lock().unwrap();
+        pending.closed = true;
+        mem::replace(&mut pending.waiting, HashMap::new())
+    };
+
+    for (_, complete) in waiting {
+        let _ = completions.send((complete, Err(Error::Disconnected)));
+    }
+}
+
+/// Complete the Future of each request with its response, in the order the
+/// responses arrived.
+fn complete(received: mpsc::Receiver<(Complete<ServerMessage<'static>, Error>,
+                                      Result<ServerMessage<'static>>)>) {
+    for (complete, response) in received {
+        match response {
+            Ok(message) => complete.complete(message),
+            Err(e) => complete.fail(e)
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use uuid::Uuid;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use {Client, Result, Error};
+
+/// Keeps the lease on a read message alive by extending it in the
+/// background, for as long as the guard is alive.
+///
+/// Created using `Client::keep_alive`. The lease is extended over a separate
+/// connection, so the Client can still be used while the guard is alive. The
+/// message stays leased to the connection it was read on, so closing the
+/// separate connection does not requeue it.
+pub struct LeaseGuard {
+    /// Set when the guard is dropped, to stop extending the lease.
+    stop: Arc<AtomicBool>,
+
+    /// Set if an extension was refused, because the message was already
+    /// confirmed or requeued.
+    lost: Arc<AtomicBool>,
+
+    /// Joined when the guard is dropped.
+    thread: Option<JoinHandle<()>>
+}
+
+/// The shortest timeout a lease can be kept alive with, in milliseconds,
+/// since it is extended halfway through.
+pub const MIN_KEEP_ALIVE_MS: u64 = 2;
+
+impl LeaseGuard {
+    /// Start extending the lease on the message with this id, read from the
+    /// server at `addr`, by `timeout` milliseconds at a time.
+    pub fn start(addr: SocketAddr, id: Uuid, timeout: u64) -> Result<LeaseGuard> {
+        if timeout < MIN_KEEP_ALIVE_MS { return Err(Error::TimeoutTooShort(timeout)) }
+
+        let mut client = try!(Client::connect(addr));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let lost = Arc::new(AtomicBool::new(false));
+        let (thread_stop, thread_lost) = (stop.clone(), lost.clone());
+
+        let thread = thread::spawn(move || {
+            loop {
+                // Extend the lease halfway through, so a slow round trip to
+                // the server does not let it lapse.
+                thread::park_timeout_ms((timeout / 2) as u32);
+                if thread_stop.load(Ordering::SeqCst) { break }
+
+                if client.extend_lease(id.clone(), timeout).is_err() {
+                    thread_lost.store(true, Ordering::SeqCst);
+                    break
+                }
+            }
+        });
+
+        Ok(LeaseGuard { stop: stop, lost: lost, thread: Some(thread) })
+    }
+
+    /// Whether the lease was lost, because the message was requeued or
+    /// confirmed before it could be extended.
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for LeaseGuard {
+    /// Stop extending the lease, waiting for any extension in progress.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
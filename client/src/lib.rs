@@ -4,22 +4,51 @@
 //! is feasible to use a new thread for each Client on the clients, but not
 //! feasible to use a new thread for each client on the server.
 //!
+//! For many concurrent operations over a single connection, AsyncClient
+//! returns a Future for each operation instead of blocking.
+//!
+//! To have the server push objects as they arrive instead of reading them
+//! one at a time, subscribe to a queue with `Client::subscribe`.
+//!
+//! To look at the objects in a queue without reading them, use `Client::peek`
+//! or `Client::browse`.
+//!
 
 extern crate dbqueue_common as common;
 extern crate uuid;
+extern crate eventual;
 
 pub use common::{EncodingError, DecodingError};
 pub use error::{Error, Result};
 pub use pipeline::{Pipeline, ResponseIter};
+pub use lease::{LeaseGuard, MIN_KEEP_ALIVE_MS};
+pub use futures::AsyncClient;
+pub use consumer::Consumer;
+pub use browse::{Browser, Peeked};
 
-use common::{ClientMessage, ServerMessage, StrBox, SliceBox};
+use common::{ClientMessage, ServerMessage, StrBox, SliceBox, CHUNK_LEN, MAX_BATCH};
+
+pub use common::{QueueOptions, QueueStats, MemoryStats, Overflow, RequestId, MAX_BATCH};
 
 use uuid::Uuid;
 use std::net::{ToSocketAddrs, TcpStream};
-use std::io::{self, Read, Write};
+use std::io::{Read, Write};
+use std::mem;
 
 mod error;
 mod pipeline;
+mod lease;
+mod futures;
+mod consumer;
+mod browse;
+
+/// An upper bound on the encoded size of an EnqueueBatch message without
+/// its queue name and objects.
+const ENQUEUE_BATCH_OVERHEAD: usize = 64;
+
+/// An upper bound on the encoded size of each object in an EnqueueBatch
+/// message, besides its data.
+const BATCHED_OBJECT_OVERHEAD: usize = 8;
 
 pub struct Client<S: Read + Write = TcpStream> {
     pipeline: Pipeline<S>
@@ -27,7 +56,10 @@ pub struct Client<S: Read + Write = TcpStream> {
 
 pub struct Message {
     pub id: Uuid,
-    pub data: Vec<u8>
+    pub data: Vec<u8>,
+
+    /// The number of times this message has been read, including this time.
+    pub deliveries: u32
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash)]
@@ -45,17 +77,32 @@ impl<'a> From<&'a str> for QueueId<'a> {
 
 impl Client {
     /// Connect to an existing server, so we can start sending messages.
-    pub fn connect<T: ToSocketAddrs>(addr: T) -> io::Result<Client> {
-        Ok(Client::new(try!(TcpStream::connect(addr))))
+    pub fn connect<T: ToSocketAddrs>(addr: T) -> Result<Client> {
+        Client::new(try!(TcpStream::connect(addr)))
+    }
+
+    /// Keep the lease on a read message alive for as long as the returned
+    /// guard is alive, by extending it by `timeout` milliseconds at a time.
+    ///
+    /// The lease is extended over a new connection to the same server. The
+    /// timeout must be at least `MIN_KEEP_ALIVE_MS`.
+    pub fn keep_alive(&self, entity_id: Uuid, timeout: u64) -> Result<LeaseGuard> {
+        LeaseGuard::start(try!(self.pipeline.stream().peer_addr()), entity_id, timeout)
     }
 }
 
 impl<S: Read + Write> Client<S> {
-    /// Create a new Client which reads and writes from the passed stream.
-    pub fn new(stream: S) -> Client<S> {
-        Client { pipeline: Pipeline::new(stream) }
+    /// Create a new Client which reads and writes from the passed stream,
+    /// and perform the handshake with the server.
+    pub fn new(stream: S) -> Result<Client<S>> {
+        let mut pipeline = Pipeline::new(stream);
+        try!(pipeline.handshake());
+        Ok(Client { pipeline: pipeline })
     }
 
+    /// The optional features of the protocol the server agreed to use.
+    pub fn features(&self) -> &[String] { self.pipeline.features() }
+
     /// Create a new queue.
     pub fn create<'a>(&mut self, queue_name: &'a str) -> Result<QueueId<'a>> {
         match try!(self.send_message(ClientMessage::CreateQueue(StrBox::new(queue_name)))) {
@@ -64,6 +111,43 @@ impl<S: Read + Write> Client<S> {
         }
     }
 
+    /// Create a new queue with specific options, such as a dead-letter queue
+    /// or limits on its length.
+    ///
+    /// If the queue already exists, its options are left unchanged. Use
+    /// `alter_queue` to change them.
+    pub fn create_with_options<'a>(&mut self, queue_name: &'a str, options: QueueOptions)
+            -> Result<QueueId<'a>> {
+        let message = ClientMessage::CreateQueueWithOptions(StrBox::new(queue_name), options);
+        match try!(self.send_message(message)) {
+            ServerMessage::QueueCreated => Ok(QueueId::from(queue_name)),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Fetch the options of an existing queue.
+    pub fn queue_options(&mut self, queue: QueueId) -> Result<QueueOptions> {
+        match try!(self.send_message(ClientMessage::GetQueueConfig(queue.0.clone()))) {
+            ServerMessage::QueueConfig(options) => Ok(options),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Replace the options of an existing queue.
+    ///
+    /// The new options apply to messages sent or read from then on. Messages
+    /// already in the queue are kept, even if it is now over its limits.
+    pub fn alter_queue(&mut self, queue: QueueId, options: QueueOptions) -> Result<()> {
+        match try!(self.send_message(ClientMessage::AlterQueue(queue.0.clone(), options))) {
+            ServerMessage::QueueAltered => Ok(()),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
     /// Delete an existing queue.
     pub fn delete(&mut self, queue: QueueId) -> Result<()> {
         match try!(self.send_message(ClientMessage::DeleteQueue(queue.0.clone()))) {
@@ -74,14 +158,277 @@ impl<S: Read + Write> Client<S> {
         }
     }
 
+    /// List the names of every queue on the server, in order.
+    pub fn list_queues(&mut self) -> Result<Vec<QueueId<'static>>> {
+        let mut names: Vec<String> = Vec::new();
+
+        loop {
+            let response = {
+                // Continue after the last name listed so far.
+                let after = names.last().map(|name| StrBox::new(&**name));
+                try!(self.send_message(ClientMessage::ListQueues(after)))
+            };
+
+            match response {
+                ServerMessage::Queues(listed, more) => {
+                    names.extend(listed.into_iter());
+                    if !more { break }
+                },
+                _ => panic!("Received incorrect message from the server.")
+            }
+        }
+
+        Ok(names.into_iter().map(QueueId::from).collect())
+    }
+
+    /// Fetch the statistics of an existing queue, such as the number of
+    /// objects ready to be read and in flight.
+    pub fn stats(&mut self, queue: QueueId) -> Result<QueueStats> {
+        match try!(self.send_message(ClientMessage::QueueStats(queue.0.clone()))) {
+            ServerMessage::QueueStats(stats) => Ok(stats),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Fetch how much memory the server is using for messages, and its
+    /// budget.
+    ///
+    /// Once the budget is used up, sending fails with `Error::OverBudget`
+    /// until messages are read and confirmed.
+    pub fn memory_stats(&mut self) -> Result<MemoryStats> {
+        match try!(self.send_message(ClientMessage::MemoryStats)) {
+            ServerMessage::MemoryStats(stats) => Ok(stats),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Remove every message waiting to be read from an existing queue,
+    /// without deleting the queue, returning how many were removed.
+    ///
+    /// Messages which are leased are not removed.
+    pub fn purge(&mut self, queue: QueueId) -> Result<u64> {
+        match try!(self.send_message(ClientMessage::Purge(queue.0.clone()))) {
+            ServerMessage::Purged(count) => Ok(count),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Remove a single message waiting to be read, from whichever queue
+    /// holds it.
+    ///
+    /// Returns `Error::Leased` if the message is leased, in which case it can
+    /// be removed once it is requeued.
+    pub fn delete_message(&mut self, entity_id: Uuid) -> Result<()> {
+        match try!(self.send_message(ClientMessage::DeleteMessage(entity_id))) {
+            ServerMessage::MessageDeleted => Ok(()),
+            ServerMessage::Leased => Err(Error::Leased(entity_id)),
+            ServerMessage::NoSuchEntity => Err(Error::NoObject(entity_id)),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Move up to `count` messages from the front of one existing queue to
+    /// the back of another, returning how many were moved.
+    ///
+    /// Moved messages start afresh, with no deliveries, so messages can be
+    /// retried by moving them out of a dead-letter queue. Messages which are
+    /// leased, or do not fit in the other queue, are not moved. If either
+    /// queue does not exist, `Error::NoQueue` names the first which does not,
+    /// and moving messages to the queue they are in is refused with
+    /// `Error::BadRequest`.
+    pub fn move_messages(&mut self, from: QueueId, to: QueueId, count: u32) -> Result<u64> {
+        match try!(self.send_message(ClientMessage::Move(from.0.clone(), to.0.clone(), count))) {
+            ServerMessage::Moved(count) => Ok(count),
+            ServerMessage::NoSuchQueue(name) => Err(Error::NoQueue(QueueId::from(name))),
+            ServerMessage::BadRequest => Err(Error::BadRequest),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
     /// Send an object to an existing queue on the server.
+    ///
+    /// Objects larger than `CHUNK_LEN` are uploaded in chunks.
     pub fn send(&mut self, queue: QueueId, data: &[u8]) -> Result<Uuid> {
+        if data.len() > CHUNK_LEN { return self.upload(queue, data) }
+
         let message = ClientMessage::Enqueue(queue.0.clone(), SliceBox::new(data));
-        let response = try!(self.send_message(message));
+        self.enqueue(queue, message)
+    }
 
-        match response {
-            ServerMessage::ObjectQueued(id) => Ok(id),
-            ServerMessage::Full(id, data) => Err(Error::Full(id, data.take())),
+    /// Send an object to an existing queue on the server, which will not be
+    /// available to read until `delay` milliseconds have elapsed.
+    ///
+    /// If the queue is deleted before the delay elapses, the object is
+    /// discarded. If the queue is still full a minute after the delay
+    /// elapses, the object is moved to its dead-letter queue, or discarded.
+    pub fn send_delayed(&mut self, queue: QueueId, data: &[u8], delay: u64) -> Result<Uuid> {
+        let message = ClientMessage::EnqueueDelayed(queue.0.clone(), SliceBox::new(data), delay);
+        self.enqueue(queue, message)
+    }
+
+    /// Send an object to an existing queue on the server, which is discarded
+    /// or moved to the dead-letter queue of the queue if it has not been read
+    /// within `ttl` milliseconds.
+    ///
+    /// A `ttl` of 0 uses the default time-to-live of the queue.
+    pub fn send_with_ttl(&mut self, queue: QueueId, data: &[u8], ttl: u64) -> Result<Uuid> {
+        let message = ClientMessage::EnqueueWithTtl(queue.0.clone(), SliceBox::new(data), ttl);
+        self.enqueue(queue, message)
+    }
+
+    /// Send an object with a priority to an existing queue on the server.
+    ///
+    /// If the queue supports priorities, objects with a higher priority are
+    /// read before any with a lower priority.
+    pub fn send_with_priority(&mut self, queue: QueueId, data: &[u8],
+                              priority: u8) -> Result<Uuid> {
+        let message = ClientMessage::EnqueueWithPriority(queue.0.clone(), SliceBox::new(data),
+                                                         priority);
+        self.enqueue(queue, message)
+    }
+
+    /// Send several objects to an existing queue on the server, in order,
+    /// returning their ids.
+    ///
+    /// The objects are sent in as few messages as possible, and objects
+    /// larger than `CHUNK_LEN` are uploaded in chunks on their own.
+    ///
+    /// If the queue fills up, `Error::BatchFull` contains the ids of the
+    /// objects which were queued before it did, and the rest were not queued.
+    /// Queues which block producers instead wait until the whole batch fits.
+    /// If the server runs out of memory budget, `Error::OverBudget` is
+    /// returned, and objects sent in earlier messages may have been queued.
+    pub fn send_batch(&mut self, queue: QueueId, objects: &[&[u8]]) -> Result<Vec<Uuid>> {
+        let budget = (self.pipeline.max_message_len() as usize)
+            .saturating_sub(ENQUEUE_BATCH_OVERHEAD + queue.0.as_ref().len());
+
+        let mut ids = Vec::with_capacity(objects.len());
+        let mut batch = Vec::new();
+        let mut batch_len = 0;
+
+        for &object in objects {
+            let large = object.len() > CHUNK_LEN;
+            let len = object.len() + BATCHED_OBJECT_OVERHEAD;
+
+            if large || batch.len() == MAX_BATCH || batch_len + len > budget {
+                try!(self.enqueue_batch(&queue, &mut batch, &mut ids));
+                batch_len = 0;
+            }
+
+            if large {
+                match self.upload(QueueId(queue.0.clone()), object) {
+                    Ok(id) => ids.push(id),
+                    Err(Error::Full(..)) => return Err(Error::BatchFull(ids)),
+                    Err(e) => return Err(e)
+                }
+            } else {
+                batch.push(SliceBox::new(object));
+                batch_len += len;
+            }
+        }
+
+        try!(self.enqueue_batch(&queue, &mut batch, &mut ids));
+        Ok(ids)
+    }
+
+    /// Bind an existing queue to a topic, so it gets a copy of every object
+    /// published to the topic, creating the topic if it does not exist.
+    pub fn bind(&mut self, topic: &str, queue: QueueId) -> Result<()> {
+        match try!(self.send_message(ClientMessage::Bind(StrBox::new(topic), queue.0.clone()))) {
+            ServerMessage::Bound => Ok(()),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Bind an existing queue to a topic like `bind`, but only for objects
+    /// published with a routing key matching `pattern`.
+    ///
+    /// Routing keys and patterns are made up of words separated by dots, such
+    /// as `orders.new.eu`. In a pattern, the word `*` matches any single word,
+    /// and `#` matches any number of words, so `orders.*.eu` and `logs.#` are
+    /// both valid patterns. A queue can be bound to a topic with several
+    /// patterns, and still only gets one copy of each object.
+    pub fn bind_with_pattern(&mut self, topic: &str, queue: QueueId,
+                             pattern: &str) -> Result<()> {
+        let message = ClientMessage::BindWithPattern(StrBox::new(topic), queue.0.clone(),
+                                                     StrBox::new(pattern));
+        match try!(self.send_message(message)) {
+            ServerMessage::Bound => Ok(()),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Unbind a queue from a topic, whatever patterns it was bound with. The
+    /// topic is removed once no queues are bound to it.
+    pub fn unbind(&mut self, topic: &str, queue: QueueId) -> Result<()> {
+        match try!(self.send_message(ClientMessage::Unbind(StrBox::new(topic), queue.0.clone()))) {
+            ServerMessage::Unbound => Ok(()),
+            ServerMessage::NoSuchEntity => Err(Error::NotBound),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Publish an object to a topic, which sends a separate copy of it, with
+    /// its own id, to every queue bound to the topic, returning their ids.
+    ///
+    /// Objects larger than `CHUNK_LEN` cannot be published. If any of the
+    /// bound queues were full, `Error::PublishFull` contains the ids of the
+    /// copies which were sent and the queues which were full, apart from
+    /// queues which block producers, which are waited on until the copy fits.
+    /// If any of the copies would go over a memory budget of the server, none
+    /// are sent.
+    pub fn publish(&mut self, topic: &str, data: &[u8]) -> Result<Vec<Uuid>> {
+        if data.len() > CHUNK_LEN { return Err(Error::TooLarge) }
+
+        let message = ClientMessage::Publish(StrBox::new(topic), SliceBox::new(data));
+        self.publish_message(topic, message)
+    }
+
+    /// Publish an object to a topic like `publish`, with a routing key, so it
+    /// is only sent to the queues bound with a pattern matching the key.
+    pub fn publish_with_key(&mut self, topic: &str, key: &str, data: &[u8]) -> Result<Vec<Uuid>> {
+        if data.len() > CHUNK_LEN { return Err(Error::TooLarge) }
+
+        let message = ClientMessage::PublishWithKey(StrBox::new(topic), StrBox::new(key),
+                                                    SliceBox::new(data));
+        self.publish_message(topic, message)
+    }
+
+    /// Request up to `max` objects from an existing queue at once, each read
+    /// as if by `read_ms` with the same timeout.
+    ///
+    /// At most `MAX_BATCH` objects are read at a time, and if the queue is
+    /// empty no objects are returned.
+    pub fn read_batch(&mut self, queue: QueueId, max: u32, timeout: u64) -> Result<Vec<Message>> {
+        match try!(self.send_message(ClientMessage::ReadBatch(queue.0.clone(), max, timeout))) {
+            ServerMessage::ReadBatch(objects) => {
+                let mut messages = Vec::with_capacity(objects.len());
+
+                for object in objects {
+                    // Objects which did not fit in the response are fetched
+                    // in chunks.
+                    let data = match object.data {
+                        Some(data) => data,
+                        None => try!(self.download(object.id, object.len))
+                    };
+
+                    messages.push(Message {
+                        id: object.id,
+                        data: data,
+                        deliveries: object.deliveries
+                    });
+                }
+
+                Ok(messages)
+            },
             ServerMessage::NoSuchEntity =>
                 Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
             _ => panic!("Received incorrect message from the server.")
@@ -94,22 +441,31 @@ impl<S: Read + Write> Client<S> {
     /// this message. If the timeout elapses before we have sent confirmation, then
     /// the message will be requeued.
     ///
-    /// Timeouts are given in milliseconds. A timeout of 0 indicates no timeout.
+    /// Timeouts are given in milliseconds. A timeout of 0 uses the lease timeout
+    /// of the queue, which is no timeout unless the queue was configured with
+    /// one.
+    ///
+    /// Regardless of the timeout, the message is requeued immediately if this
+    /// Client is dropped before the message is confirmed.
     pub fn read_ms(&mut self, queue: QueueId, timeout: u64) -> Result<Message> {
-        match try!(self.send_message(ClientMessage::Read(queue.0.clone(), timeout))) {
-            ServerMessage::Read(id, data) =>
-                Ok(Message { id: id, data: data.take() }),
-            ServerMessage::Empty => Err(Error::Empty),
-            ServerMessage::NoSuchEntity =>
-                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
-            _ => panic!("Received incorrect message from the server.")
-        }
+        let message = ClientMessage::Read(queue.0.clone(), timeout);
+        self.read(queue, message)
+    }
+
+    /// Request an object from an existing queue like `read_ms`, but if the
+    /// queue is empty wait up to `wait` milliseconds for an object to be
+    /// sent to it, instead of returning `Error::Empty` straight away.
+    pub fn read_wait_ms(&mut self, queue: QueueId, timeout: u64, wait: u64) -> Result<Message> {
+        let message = ClientMessage::ReadWait(queue.0.clone(), timeout, wait);
+        self.read(queue, message)
     }
 
     /// Confirm that we have processed a message to the point that it should not
     /// be requeued.
     ///
-    /// This should be called before the timeout on the associated read elapses.
+    /// This should be called before the timeout on the associated read elapses,
+    /// but can be called from any Client connected to the same server, or to a
+    /// server sharing the same queues.
     pub fn confirm(&mut self, entity_id: Uuid) -> Result<()> {
         match try!(self.send_message(ClientMessage::Confirm(entity_id))) {
             ServerMessage::Confirmed => Ok(()),
@@ -120,45 +476,205 @@ impl<S: Read + Write> Client<S> {
         }
     }
 
+    /// Confirm several messages at once, as if by `confirm`.
+    ///
+    /// If any of them could not be confirmed, `Error::NotConfirmed` contains
+    /// their ids, and the rest were confirmed.
+    pub fn confirm_batch(&mut self, entity_ids: &[Uuid]) -> Result<()> {
+        let mut unconfirmed = Vec::new();
+
+        for batch in entity_ids.chunks(MAX_BATCH) {
+            match try!(self.send_message(ClientMessage::ConfirmBatch(batch.to_vec()))) {
+                ServerMessage::BatchConfirmed(ids) => unconfirmed.extend(ids.into_iter()),
+                _ => panic!("Received incorrect message from the server.")
+            }
+        }
+
+        if unconfirmed.is_empty() { Ok(()) } else { Err(Error::NotConfirmed(unconfirmed)) }
+    }
+
+    /// Give back a message without processing it, so that it is requeued
+    /// without waiting for the timeout on the associated read to elapse.
+    ///
+    /// The message is requeued after `delay` milliseconds, or immediately if
+    /// `delay` is 0.
+    pub fn nack(&mut self, entity_id: Uuid, delay: u64) -> Result<()> {
+        match try!(self.send_message(ClientMessage::Nack(entity_id, delay))) {
+            ServerMessage::Nacked => Ok(()),
+            ServerMessage::Full(id, data) => Err(Error::Full(id, data.take())),
+            ServerMessage::NoSuchEntity => Err(Error::NoObject(entity_id)),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Extend the timeout on a message which is taking longer to process
+    /// than expected.
+    ///
+    /// The message will be requeued if it is not confirmed within `timeout`
+    /// milliseconds from now, instead of when the timeout it was read with
+    /// elapses. A timeout of 0 is refused with `Error::BadRequest`.
+    pub fn extend_lease(&mut self, entity_id: Uuid, timeout: u64) -> Result<()> {
+        match try!(self.send_message(ClientMessage::ExtendLease(entity_id, timeout))) {
+            ServerMessage::LeaseExtended => Ok(()),
+            ServerMessage::NoSuchEntity => Err(Error::NoObject(entity_id)),
+            ServerMessage::BadRequest => Err(Error::BadRequest),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Upload a large object in chunks, then enqueue it.
+    fn upload(&mut self, queue: QueueId, data: &[u8]) -> Result<Uuid> {
+        for chunk in data.chunks(CHUNK_LEN) {
+            match try!(self.send_message(ClientMessage::Chunk(SliceBox::new(chunk)))) {
+                ServerMessage::ChunkReceived => {},
+                ServerMessage::TooLarge => return Err(Error::TooLarge),
+                ServerMessage::OverBudget => return Err(Error::OverBudget),
+                _ => panic!("Received incorrect message from the server.")
+            }
+        }
+
+        // The server does not send large objects back when the queue is full.
+        let message = ClientMessage::EnqueueUpload(queue.0.clone());
+        match self.enqueue(queue, message) {
+            Err(Error::Full(id, _)) => Err(Error::Full(id, data.to_vec())),
+            result => result
+        }
+    }
+
+    /// Fetch a large read object in chunks.
+    fn download(&mut self, id: Uuid, len: u64) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len as usize);
+
+        while (data.len() as u64) < len {
+            match try!(self.send_message(ClientMessage::ReadChunk(id, data.len() as u64))) {
+                ServerMessage::Chunk(chunk) => {
+                    let chunk = chunk.take();
+                    if chunk.len() == 0 { return Err(Error::NoObject(id)) }
+                    data.extend(chunk.into_iter());
+                },
+                ServerMessage::NoSuchEntity => return Err(Error::NoObject(id)),
+                _ => panic!("Received incorrect message from the server.")
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Send one of the Read messages, and interpret the response.
+    fn read(&mut self, queue: QueueId, message: ClientMessage) -> Result<Message> {
+        match try!(self.send_message(message)) {
+            ServerMessage::Read(id, data, deliveries) =>
+                Ok(Message { id: id, data: data.take(), deliveries: deliveries }),
+            ServerMessage::ReadChunked(id, len, deliveries) =>
+                Ok(Message { id: id, data: try!(self.download(id, len)), deliveries: deliveries }),
+            ServerMessage::Empty => Err(Error::Empty),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Send one of the Publish messages, and interpret the response.
+    fn publish_message(&mut self, topic: &str, message: ClientMessage) -> Result<Vec<Uuid>> {
+        match try!(self.send_message(message)) {
+            ServerMessage::Published(ids, full) => {
+                if full.is_empty() { return Ok(ids) }
+                Err(Error::PublishFull(ids, full.into_iter().map(QueueId::from).collect()))
+            },
+            ServerMessage::OverBudget => Err(Error::OverBudget),
+            ServerMessage::NoSuchEntity => Err(Error::NoTopic(topic.to_string())),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Send one of the Enqueue messages, and interpret the response.
+    fn enqueue(&mut self, queue: QueueId, message: ClientMessage) -> Result<Uuid> {
+        match try!(self.send_message(message)) {
+            ServerMessage::ObjectQueued(id) => Ok(id),
+            ServerMessage::Full(id, data) => Err(Error::Full(id, data.take())),
+            ServerMessage::OverBudget => Err(Error::OverBudget),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
+    /// Send the objects collected for a batch, if there are any, adding the
+    /// ids of the queued objects to `ids`.
+    fn enqueue_batch<'a>(&mut self, queue: &QueueId<'a>, batch: &mut Vec<SliceBox<'a, u8>>,
+                         ids: &mut Vec<Uuid>) -> Result<()> {
+        if batch.is_empty() { return Ok(()) }
+
+        let batch = mem::replace(batch, Vec::new());
+        let len = batch.len();
+
+        match try!(self.send_message(ClientMessage::EnqueueBatch(queue.0.clone(), batch))) {
+            ServerMessage::ObjectsQueued(queued) => {
+                let full = queued.len() < len;
+                ids.extend(queued.into_iter());
+
+                if full { Err(Error::BatchFull(mem::replace(ids, Vec::new()))) } else { Ok(()) }
+            },
+            ServerMessage::OverBudget => Err(Error::OverBudget),
+            ServerMessage::NoSuchEntity =>
+                Err(Error::NoQueue(QueueId(queue.0.to_owned()))),
+            _ => panic!("Received incorrect message from the server.")
+        }
+    }
+
     fn send_message(&mut self, message: ClientMessage) -> Result<ServerMessage<'static>> {
-        try!(self.pipeline.send(&message));
-        self.pipeline.receive()
+        let request = try!(self.pipeline.send(&message));
+        self.pipeline.receive_for(request)
     }
 }
 
 /// An alternative Client which allows pipelining requests.
 ///
-/// Requests can be sent using `send`, then responses waited on by
-/// using `iter` and looping over incoming responses.
-// FIXME: Providing a nicer Future-based API is blocked on eventual changes.
+/// Requests can be sent using `send`, which returns the id of the request,
+/// then responses waited on by using `receive` with that id, or by using
+/// `iter` and looping over incoming responses. See AsyncClient for a
+/// Future-based API.
 pub struct PipelinedClient<S: Read + Write> {
     pipeline: Pipeline<S>
 }
 
 impl PipelinedClient<TcpStream> {
     /// Connect to an existing server, so we can start sending messages.
-    pub fn connect<T: ToSocketAddrs>(addr: T) -> io::Result<PipelinedClient<TcpStream>> {
-        Ok(PipelinedClient::new(try!(TcpStream::connect(addr))))
+    pub fn connect<T: ToSocketAddrs>(addr: T) -> Result<PipelinedClient<TcpStream>> {
+        PipelinedClient::new(try!(TcpStream::connect(addr)))
     }
 }
 
 impl<S: Read + Write> PipelinedClient<S> {
-    pub fn new(stream: S) -> PipelinedClient<S> {
-        PipelinedClient { pipeline: Pipeline::new(stream) }
+    /// Create a new PipelinedClient which reads and writes from the passed
+    /// stream, and perform the handshake with the server.
+    pub fn new(stream: S) -> Result<PipelinedClient<S>> {
+        let mut pipeline = Pipeline::new(stream);
+        try!(pipeline.handshake());
+        Ok(PipelinedClient { pipeline: pipeline })
     }
 
     /// Send a ClientMessage, but do not wait for a response.
     // NOTE: This API already requires knowledge of the internals
     // for decoding ServerMessages, so not much harm done by not
     // providing as many convenience methods.
-    pub fn send(&mut self, message: &ClientMessage) -> Result<()> {
+    pub fn send(&mut self, message: &ClientMessage) -> Result<RequestId> {
         self.pipeline.send(message)
     }
 
-    /// Get an iterator over all incoming responses.
+    /// Wait for the response to the request with this id.
+    ///
+    /// Responses to other requests which arrive first are kept, and can
+    /// still be received later.
+    pub fn receive(&mut self, request: RequestId) -> Result<ServerMessage<'static>> {
+        self.pipeline.receive_for(request)
+    }
+
+    /// Get an iterator over all incoming responses, along with the ids of
+    /// the requests they respond to.
     ///
-    /// The Responses will be in the same order as the outgoing requests,
-    /// in FIFO (or really FOFI, since we are receiving) order.
+    /// The Responses will be in the order they arrive, which the server
+    /// does not guarantee to be the order of the requests.
     pub fn iter(&mut self) -> ResponseIter<S> {
         self.pipeline.iter()
     }
@@ -1,63 +1,216 @@
-use common::{ServerMessage, ClientMessage};
+use common::{ServerMessage, ClientMessage, Hello, Welcome, ProtocolError, RequestId,
+             PROTOCOL_VERSION, FEATURES, PUSH, MAX_CLIENT_MESSAGE_LEN};
 
 use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
 use {Error, Result};
 
 pub struct Pipeline<S: Read + Write> {
     stream: S,
-    expecting: u32
+
+    /// The id of the next request.
+    next: RequestId,
+
+    /// The requests we have not returned a response for yet.
+    outstanding: HashSet<RequestId>,
+
+    /// The ids of the Subscribe requests of the subscriptions started on
+    /// this connection, keyed by the name of their queue.
+    ///
+    /// Objects are pushed with the id of the Subscribe request until the
+    /// subscription is stopped, so the id is not used for other requests.
+    subscriptions: HashMap<String, RequestId>,
+
+    /// Responses which arrived while waiting for the response to a
+    /// different request, in the order they arrived.
+    buffered: VecDeque<(RequestId, ServerMessage<'static>)>,
+
+    /// Objects pushed to subscriptions which have not been received yet,
+    /// along with the id of the request which started the subscription.
+    pushed: VecDeque<(RequestId, ServerMessage<'static>)>,
+
+    /// The features agreed on with the server in the handshake.
+    features: Vec<String>,
+
+    /// The longest message the server accepts, as it told us in the
+    /// handshake.
+    max_message_len: u64
 }
 
 impl<S: Read + Write> Pipeline<S> {
     pub fn new(stream: S) -> Pipeline<S> {
         Pipeline {
             stream: stream,
-            expecting: 0
+            next: 0,
+            outstanding: HashSet::new(),
+            subscriptions: HashMap::new(),
+            buffered: VecDeque::new(),
+            pushed: VecDeque::new(),
+            features: Vec::new(),
+            max_message_len: MAX_CLIENT_MESSAGE_LEN
+        }
+    }
+
+    /// Perform the Hello/Welcome handshake which has to start every
+    /// connection, offering every feature this crate supports.
+    pub fn handshake(&mut self) -> Result<()> {
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect()
+        };
+        try!(hello.encode_to(&mut self.stream));
+
+        match try!(Welcome::decode_from(&mut self.stream)) {
+            Welcome::Accepted(_, features, max_message_len) => {
+                self.features = features;
+                self.max_message_len = max_message_len;
+                Ok(())
+            },
+            Welcome::Unsupported(version) =>
+                Err(Error::from(ProtocolError::Unsupported(version)))
+        }
+    }
+
+    /// The features agreed on with the server in the handshake.
+    pub fn features(&self) -> &[String] { &self.features }
+
+    /// The longest message the server accepts.
+    pub fn max_message_len(&self) -> u64 { self.max_message_len }
+
+    /// Send a request, returning the id its response will carry.
+    ///
+    /// Requests which belong to a feature the server did not agree to are
+    /// refused without sending them.
+    pub fn send(&mut self, data: &ClientMessage) -> Result<RequestId> {
+        try!(agreed(&self.features, data));
+
+        let request = self.next_request();
+        try!(data.encode_to(&mut self.stream, request, self.max_message_len));
+
+        self.next = request.wrapping_add(1);
+        self.outstanding.insert(request);
+
+        match *data {
+            ClientMessage::Subscribe(ref queue, _, _) => {
+                self.subscriptions.insert(queue.as_ref().to_string(), request);
+            },
+            ClientMessage::Unsubscribe(ref queue) => { self.subscriptions.remove(queue.as_ref()); },
+            _ => {}
         }
+
+        Ok(request)
+    }
+
+    pub fn incoming(&self) -> u32 { self.outstanding.len() as u32 }
+
+    pub fn stream(&self) -> &S { &self.stream }
+
+    pub fn into_stream(self) -> S { self.stream }
+
+    /// Receive the next response, whichever request it is for.
+    pub fn receive(&mut self) -> Result<(RequestId, ServerMessage<'static>)> {
+        if self.outstanding.is_empty() { return Err(Error::NoResponseExpected) }
+
+        let (request, message) = match self.buffered.pop_front() {
+            Some(response) => response,
+            None => try!(self.next_response())
+        };
+
+        self.outstanding.remove(&request);
+        Ok((request, message))
     }
 
-    pub fn send(&mut self, data: &ClientMessage) -> Result<()> {
-        try!(data.encode_to(&mut self.stream));
-        self.expecting += 1;
-        Ok(())
+    /// Receive the response to a specific request, keeping any responses
+    /// to other requests which arrive first.
+    pub fn receive_for(&mut self, request: RequestId) -> Result<ServerMessage<'static>> {
+        if !self.outstanding.contains(&request) { return Err(Error::NoResponseExpected) }
+
+        if let Some(position) = self.buffered.iter().position(|&(id, _)| id == request) {
+            self.outstanding.remove(&request);
+            return Ok(self.buffered.remove(position).unwrap().1)
+        }
+
+        loop {
+            let (id, message) = try!(self.next_response());
+            if id == request {
+                self.outstanding.remove(&request);
+                return Ok(message)
+            }
+
+            self.buffered.push_back((id, message));
+        }
     }
 
-    pub fn incoming(&self) -> u32 { self.expecting }
+    /// Receive the next object pushed to a subscription, along with the id
+    /// of the request which started the subscription, keeping any responses
+    /// which arrive first.
+    pub fn receive_push(&mut self) -> Result<(RequestId, ServerMessage<'static>)> {
+        loop {
+            if let Some(pushed) = self.pushed.pop_front() { return Ok(pushed) }
 
-    pub fn receive(&mut self) -> Result<ServerMessage<'static>> {
-        if self.expecting == 0 {
-            Err(Error::NoResponseExpected)
-        } else {
-            let message = try!(ServerMessage::decode_from(&mut self.stream));
-            self.expecting -= 1;
-            Ok(message.0)
+            let (message, header) = try!(ServerMessage::decode_any_from(&mut self.stream));
+            if header.kind == PUSH {
+                return Ok((header.request, message))
+            }
+
+            self.buffered.push_back((header.request, message));
         }
     }
 
+    /// Take every pushed object which has arrived but not been received.
+    pub fn take_pushed(&mut self) -> VecDeque<(RequestId, ServerMessage<'static>)> {
+        mem::replace(&mut self.pushed, VecDeque::new())
+    }
+
     pub fn iter(&mut self) -> ResponseIter<S> {
         ResponseIter { parent: self }
     }
+
+    /// The id of the next request, skipping the ids which are still in use
+    /// once the ids wrap around.
+    fn next_request(&self) -> RequestId {
+        let mut request = self.next;
+        while self.outstanding.contains(&request) ||
+              self.subscriptions.values().any(|&id| id == request) {
+            request = request.wrapping_add(1);
+        }
+
+        request
+    }
+
+    /// Read frames until the next response arrives, keeping any pushed
+    /// objects which arrive first.
+    fn next_response(&mut self) -> Result<(RequestId, ServerMessage<'static>)> {
+        loop {
+            let (message, header) = try!(ServerMessage::decode_any_from(&mut self.stream));
+            if header.kind != PUSH { return Ok((header.request, message)) }
+
+            self.pushed.push_back((header.request, message));
+        }
+    }
 }
 
+/// Check that a request does not belong to a feature which is not among the
+/// features agreed on with the server.
+pub fn agreed(features: &[String], message: &ClientMessage) -> Result<()> {
+    match message.feature() {
+        Some(feature) if !features.iter().any(|agreed| agreed == feature) =>
+            Err(Error::from(ProtocolError::Feature(feature.to_string()))),
+        _ => Ok(())
+    }
+}
+
+/// An iterator over the responses to all outstanding requests, in the order
+/// they arrive.
 pub struct ResponseIter<'a, S: Read + Write + 'a> {
     parent: &'a mut Pipeline<S>
 }
 
 impl<'a, S: Read + Write> Iterator for ResponseIter<'a, S> {
-    type Item = ServerMessage<'static>;
-
-    fn next(&mut self) -> Option<ServerMessage<'static>> {
-        if self.parent.expecting == 0 {
-            None
-        } else {
-            match ServerMessage::decode_from(&mut self.parent.stream) {
-                Ok(message) => {
-                    self.parent.expecting -= 1;
-                    Some(message.0)
-                },
-                _ => None
-            }
-        }
+    type Item = (RequestId, ServerMessage<'static>);
+
+    fn next(&mut self) -> Option<(RequestId, ServerMessage<'static>)> {
+        self.parent.receive().ok()
     }
 }
-
@@ -0,0 +1,257 @@
+use bincode::{self, SizeLimit};
+use rustc_serialize::{Encodable, Decodable};
+
+use std::io::{self, Read, Write};
+
+use {EncodingResult, EncodingError, DecodingError, DecodingResult, MAX_SERVER_MESSAGE_LEN};
+
+/// The version of the protocol spoken by this crate.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// The optional parts of the protocol supported by this crate, which are
+/// offered in Hello and agreed on in Welcome.
+pub static FEATURES: &'static [&'static str] = &[
+    "dead-letter", "delayed", "ttl", "priority", "chunked", "batch",
+    "long-poll", "subscribe", "topics", "routing", "introspection",
+    "peek", "admin", "config", "memory", "browse"
+];
+
+/// The length of the header at the start of every frame.
+pub const HEADER_LEN: usize = 13;
+
+/// Chosen by the client for every message it sends, and echoed by the
+/// server in the response, so responses can be matched to requests.
+pub type RequestId = u32;
+
+/// The kind of a frame containing a Hello or a Welcome.
+pub const HANDSHAKE: u8 = 0;
+
+/// The kind of a frame containing a ClientMessage or a ServerMessage.
+pub const MESSAGE: u8 = 1;
+
+/// The kind of a frame containing a ServerMessage which the server sent
+/// without a request, such as an object pushed to a subscription.
+///
+/// Its request id is the id of the request which started the subscription.
+pub const PUSH: u8 = 2;
+
+/// The header at the start of every frame sent over the wire.
+///
+/// It is made up of the length of the body of the frame as a big-endian
+/// u32, the protocol version as a big-endian u16, the kind of frame, the
+/// type of the message as a big-endian u16, and the id of the request as a
+/// big-endian u32.
+///
+/// The kind tells handshakes, responses and pushed objects apart, and the
+/// type tells which message the body holds, so messages whose type belongs
+/// to an optional feature which was not agreed on in the handshake can be
+/// refused before their body is decoded. The type of a message is the
+/// index of its variant, which is also the first field of the body, and
+/// the two have to match. Handshakes have the type 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub len: u32,
+    pub version: u16,
+    pub kind: u8,
+    pub tag: u16,
+    pub request: RequestId
+}
+
+impl Header {
+    /// Parse the header at the start of `buf`, if all of it is there.
+    pub fn parse(buf: &[u8]) -> Option<Header> {
+        if buf.len() < HEADER_LEN { return None }
+
+        Some(Header {
+            len: (buf[0] as u32) << 24 | (buf[1] as u32) << 16 |
+                 (buf[2] as u32) << 8 | buf[3] as u32,
+            version: (buf[4] as u16) << 8 | buf[5] as u16,
+            kind: buf[6],
+            tag: (buf[7] as u16) << 8 | buf[8] as u16,
+            request: (buf[9] as u32) << 24 | (buf[10] as u32) << 16 |
+                     (buf[11] as u32) << 8 | buf[12] as u32
+        })
+    }
+
+    /// The header as it is sent over the wire.
+    pub fn bytes(&self) -> [u8; HEADER_LEN] {
+        [(self.len >> 24) as u8, (self.len >> 16) as u8,
+         (self.len >> 8) as u8, self.len as u8,
+         (self.version >> 8) as u8, self.version as u8,
+         self.kind,
+         (self.tag >> 8) as u8, self.tag as u8,
+         (self.request >> 24) as u8, (self.request >> 16) as u8,
+         (self.request >> 8) as u8, self.request as u8]
+    }
+}
+
+/// Sent by the client as the first frame on a new connection.
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable, PartialEq)]
+pub struct Hello {
+    /// The protocol version the client wants to use.
+    pub version: u16,
+
+    /// The features the client would like to use.
+    pub features: Vec<String>
+}
+
+/// The response of the server to a Hello.
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable, PartialEq)]
+pub enum Welcome {
+    /// The server speaks the requested version, and supports these of the
+    /// requested features.
+    ///
+    /// The last field is the length of the longest message the server
+    /// accepts from the client.
+    Accepted(u16, Vec<String>, u64),
+
+    /// The server does not speak the requested version, and only speaks
+    /// this one. The server will refuse any further messages.
+    Unsupported(u16)
+}
+
+impl Hello {
+    /// Called on the client, to serialize over the wire as a single frame.
+    pub fn encode_to<W: Write>(&self, write: &mut W) -> EncodingResult<()> {
+        encode_into(self, write, HANDSHAKE, 0, 0, SizeLimit::Infinite)
+    }
+
+    /// Called on the server, to deserialize from the body of a received frame.
+    pub fn decode(buf: &[u8]) -> DecodingResult<(Hello, u64)> {
+        bincode::decode(buf)
+    }
+}
+
+impl Welcome {
+    /// Called on the server, to serialize over the wire as a single frame.
+    pub fn encode(&self) -> EncodingResult<Vec<u8>> {
+        encode(self, HANDSHAKE, 0, 0, SizeLimit::Infinite)
+    }
+
+    /// Called on the client, to deserialize a single frame from the wire.
+    pub fn decode_from<R: Read>(read: &mut R) -> Result<Welcome, ProtocolError> {
+        decode_from(read, HANDSHAKE, MAX_SERVER_MESSAGE_LEN).map(|(welcome, _)| welcome)
+    }
+}
+
+/// A violation of the protocol by the other end of a connection.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A frame had a protocol version other than the one agreed on.
+    Version(u16),
+
+    /// A frame of an unexpected kind was received, such as a message
+    /// before the handshake.
+    UnexpectedKind(u8),
+
+    /// A frame was longer than the limit for its receiver.
+    OverLong(u32),
+
+    /// The body of a frame did not hold a message of the type in its
+    /// header, or no message has that type.
+    Type(u16),
+
+    /// The server does not speak our protocol version, only this one.
+    Unsupported(u16),
+
+    /// A message belongs to an optional feature which was not agreed on in
+    /// the handshake.
+    Feature(String),
+
+    Decoding(DecodingError),
+    Io(io::Error)
+}
+
+impl From<DecodingError> for ProtocolError {
+    fn from(err: DecodingError) -> ProtocolError { ProtocolError::Decoding(err) }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> ProtocolError { ProtocolError::Io(err) }
+}
+
+/// Encode a value of the type `tag` as a complete frame.
+pub fn encode<T: Encodable>(value: &T, kind: u8, tag: u16, request: RequestId,
+                            limit: SizeLimit) -> EncodingResult<Vec<u8>> {
+    let body = try!(bincode::encode(value, limit));
+
+    let header = Header {
+        len: body.len() as u32,
+        version: PROTOCOL_VERSION,
+        kind: kind,
+        tag: tag,
+        request: request
+    };
+    let mut frame = header.bytes().to_vec();
+    frame.extend(body.into_iter());
+    Ok(frame)
+}
+
+/// Encode a value of the type `tag` as a complete frame, and write it out.
+pub fn encode_into<T, W>(value: &T, write: &mut W, kind: u8, tag: u16, request: RequestId,
+                         limit: SizeLimit) -> EncodingResult<()>
+where T: Encodable, W: Write {
+    let frame = try!(encode(value, kind, tag, request, limit));
+    try!(write.write_all(&frame).map_err(EncodingError::IoError));
+    Ok(())
+}
+
+/// Read a single frame of the given kind, and decode its body, returning it
+/// along with the id of the request in the frame.
+///
+/// The version of handshake frames is not checked, since the version is
+/// only agreed on by the handshake.
+pub fn decode_from<T, R>(read: &mut R, kind: u8,
+                         limit: u64) -> Result<(T, RequestId), ProtocolError>
+where T: Decodable, R: Read {
+    decode_any_from(read, &[kind], limit).map(|(value, header)| (value, header.request))
+}
+
+/// Read a single frame of any of the given kinds, and decode its body,
+/// returning it along with the header of the frame.
+///
+/// The body of frames other than handshakes is only decoded if it holds a
+/// message of the type in the header.
+pub fn decode_any_from<T, R>(read: &mut R, kinds: &[u8],
+                             limit: u64) -> Result<(T, Header), ProtocolError>
+where T: Decodable, R: Read {
+    let mut header = [0; HEADER_LEN];
+    try!(read_exactly(read, &mut header));
+    let header = Header::parse(&header).unwrap();
+
+    if !kinds.contains(&header.kind) {
+        return Err(ProtocolError::UnexpectedKind(header.kind))
+    } else if header.kind != HANDSHAKE && header.version != PROTOCOL_VERSION {
+        return Err(ProtocolError::Version(header.version))
+    } else if header.len as u64 > limit {
+        return Err(ProtocolError::OverLong(header.len))
+    }
+
+    let mut body = vec![0; header.len as usize];
+    try!(read_exactly(read, &mut body));
+
+    if header.kind != HANDSHAKE && !tagged(&body, header.tag) {
+        return Err(ProtocolError::Type(header.tag))
+    }
+
+    let (value, _): (T, u64) = try!(bincode::decode(&body));
+    Ok((value, header))
+}
+
+/// Whether `body` holds a message of the type `tag`, whose variant index
+/// bincode writes as a big-endian u32 at the start of the body.
+pub fn tagged(body: &[u8], tag: u16) -> bool {
+    body.len() >= 4 && &body[..4] == &[0, 0, (tag >> 8) as u8, tag as u8][..]
+}
+
+fn read_exactly<R: Read>(read: &mut R, mut buf: &mut [u8]) -> io::Result<()> {
+    while buf.len() != 0 {
+        match try!(read.read(buf)) {
+            0 => return Err(io::Error::new(io::ErrorKind::Other,
+                                           "connection closed in the middle of a frame")),
+            n => { let rest = buf; buf = &mut rest[n..]; }
+        }
+    }
+
+    Ok(())
+}
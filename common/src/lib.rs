@@ -8,13 +8,213 @@ use std::io::{Read, Write};
 
 pub use bincode::{EncodingResult, DecodingResult, EncodingError,
                   DecodingError, StrBox, SliceBox};
+pub use frame::{Header, Hello, Welcome, ProtocolError, RequestId, PROTOCOL_VERSION,
+                FEATURES, HEADER_LEN, HANDSHAKE, MESSAGE, PUSH, tagged};
 
+/// Framing of messages on the wire, and the handshake which starts every
+/// connection.
+pub mod frame;
+
+/// The largest message a client sends by default. Servers can be configured
+/// to accept larger messages, and tell clients their limit in the Welcome.
 pub const MAX_CLIENT_MESSAGE_LEN: u64 = 2048;
 pub const MAX_SERVER_MESSAGE_LEN: u64 = 2048;
 
-const CLIENT_SIZE_LIMIT: SizeLimit = SizeLimit::Bounded(MAX_CLIENT_MESSAGE_LEN);
+/// The largest object sent in a single message. Larger objects are sent
+/// in chunks of at most this size.
+pub const CHUNK_LEN: usize = 1024;
+
+/// An upper bound on the encoded size of a BatchedObject without its data,
+/// and of the rest of a ReadBatch response.
+pub const BATCHED_OBJECT_OVERHEAD: usize = 80;
+
+/// An upper bound on the encoded size of a PeekedObject without its data,
+/// and of the rest of a Peeked response.
+pub const PEEKED_OBJECT_OVERHEAD: usize = 96;
+
+/// The most objects sent in a single batch message: as many as fit in a
+/// ReadBatch or Peeked response of at most `MAX_SERVER_MESSAGE_LEN` bytes
+/// without their data. PeekedObjects are the larger of the two.
+///
+/// The data of a batch must still fit in the message limit, so a batch of
+/// `MAX_BATCH` objects only carries small objects whole. Data which does not
+/// fit in a response is fetched in chunks, and the client sends objects
+/// which do not fit in an EnqueueBatch in further messages.
+pub const MAX_BATCH: usize = MAX_SERVER_MESSAGE_LEN as usize / PEEKED_OBJECT_OVERHEAD - 1;
+
 const SERVER_SIZE_LIMIT: SizeLimit = SizeLimit::Bounded(MAX_SERVER_MESSAGE_LEN);
 
+/// Options for a queue, given when it is created.
+#[derive(Debug, Clone, Default, RustcDecodable, RustcEncodable, PartialEq)]
+pub struct QueueOptions {
+    /// The number of times a message can be read without being confirmed
+    /// before it is moved to the dead-letter queue instead of being requeued.
+    ///
+    /// A value of 0 allows any number of deliveries.
+    pub max_deliveries: u32,
+
+    /// The name of the queue messages are moved to once they reach
+    /// `max_deliveries`, or their time-to-live elapses.
+    ///
+    /// If there is no dead-letter queue, such messages are discarded.
+    pub dead_letter: Option<String>,
+
+    /// The default time-to-live of messages in this queue, in milliseconds,
+    /// used for messages enqueued without one of their own.
+    ///
+    /// A value of 0 means messages never expire.
+    pub ttl: u64,
+
+    /// The most messages the queue holds waiting to be read.
+    ///
+    /// A value of 0 uses the capacity the server gives every queue, if any.
+    pub max_len: u64,
+
+    /// The most bytes of message data the queue holds waiting to be read.
+    ///
+    /// A value of 0 allows any number of bytes.
+    pub max_bytes: u64,
+
+    /// The timeout, in milliseconds, of Reads from this queue which are made
+    /// without one of their own.
+    ///
+    /// A value of 0 means such Reads have no timeout.
+    pub lease_timeout: u64,
+
+    /// What happens to messages sent to the queue when it is full.
+    pub overflow: Overflow
+}
+
+/// What happens to a message sent to a queue which is full.
+///
+/// Messages moved or dead-lettered into a full queue are always rejected.
+#[derive(Debug, Clone, Copy, RustcDecodable, RustcEncodable, PartialEq)]
+pub enum Overflow {
+    /// The message is sent back to the client with Full.
+    Reject,
+
+    /// Messages at the front of the queue, which would be read next, are
+    /// discarded until the new message fits.
+    DropOldest,
+
+    /// The response to the client is held back until the message fits.
+    ///
+    /// Applies to every message sent by clients. The response to a batch,
+    /// or to a publish, is held back until every message in it fits, or one
+    /// is rejected because the queue stopped blocking.
+    Block
+}
+
+impl Default for Overflow {
+    fn default() -> Overflow { Overflow::Reject }
+}
+
+/// A snapshot of the contents of a queue, and running totals of the
+/// operations on it since the server started.
+#[derive(Debug, Clone, Default, RustcDecodable, RustcEncodable, PartialEq)]
+pub struct QueueStats {
+    /// The number of objects ready to be read.
+    pub depth: u64,
+
+    /// The number of objects read which have not been confirmed, nacked, or
+    /// had their timeout elapse.
+    pub in_flight: u64,
+
+    /// The number of objects enqueued with a delay which has not elapsed,
+    /// or nacked with a delay, which will become ready to read.
+    pub delayed: u64,
+
+    /// The number of objects enqueued, including copies published to the
+    /// queue and objects moved to it as a dead-letter queue.
+    pub enqueued: u64,
+
+    /// The number of objects read.
+    pub dequeued: u64,
+
+    /// The number of objects finished with for good, because they were
+    /// confirmed, expired, or were delivered too many times.
+    pub confirmed: u64,
+
+    /// The number of objects put back to be read again.
+    pub requeued: u64,
+
+    /// The number of objects discarded from the front of the queue to make
+    /// room for new ones, because it was full and drops the oldest objects.
+    pub dropped: u64,
+
+    /// How long the object which has been ready to read the longest has
+    /// been waiting, in milliseconds, or None if the queue is empty.
+    pub oldest: Option<u64>,
+
+    /// The total length of the objects ready to be read, in bytes.
+    pub bytes: u64
+}
+
+/// The memory used for objects by a server, and the queues it shares with
+/// other servers.
+#[derive(Debug, Clone, Default, RustcDecodable, RustcEncodable, PartialEq)]
+pub struct MemoryStats {
+    /// The bytes of objects ready to be read, in every queue.
+    pub queued: u64,
+
+    /// The bytes of objects read which have not been confirmed yet.
+    pub leased: u64,
+
+    /// The bytes of responses waiting to be sent to clients.
+    pub outgoing: u64,
+
+    /// The bytes of objects held outside any queue: delayed objects, objects
+    /// being uploaded in chunks, objects held back by full queues, and read
+    /// objects which could not be requeued because their queue was full.
+    pub held: u64,
+
+    /// The most bytes the server holds before it refuses new objects, or 0
+    /// if it has no budget.
+    pub budget: u64
+}
+
+/// An object read as part of a batch.
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable, PartialEq)]
+pub struct BatchedObject {
+    pub id: Uuid,
+
+    /// The data of the object, or None if it did not fit in the batch, in
+    /// which case it is fetched using ReadChunk.
+    pub data: Option<Vec<u8>>,
+
+    /// The length of the data.
+    pub len: u64,
+
+    /// The number of times the object has been read, including this time.
+    pub deliveries: u32
+}
+
+/// An object seen by Peek, which is left in its queue.
+#[derive(Debug, Clone, RustcDecodable, RustcEncodable, PartialEq)]
+pub struct PeekedObject {
+    pub id: Uuid,
+
+    /// The data of the object, or None if it did not fit in the response,
+    /// in which case it is fetched using PeekChunk.
+    pub data: Option<Vec<u8>>,
+
+    /// The length of the data.
+    pub len: u64,
+
+    /// The number of times the object has been read so far.
+    pub deliveries: u32,
+
+    /// The priority the object was enqueued with.
+    pub priority: u8,
+
+    /// When the object expires, in milliseconds since the Unix epoch.
+    pub expires: Option<u64>,
+
+    /// When the object became ready to read, in milliseconds since the Unix
+    /// epoch.
+    pub enqueued: u64
+}
+
 #[derive(Debug, RustcDecodable, RustcEncodable, PartialEq)]
 pub enum ClientMessage<'a> {
     // These Strings and Vec<u8>s should be RefBox's of str and [u8]
@@ -34,15 +234,211 @@ pub enum ClientMessage<'a> {
     /// this message. If the timeout elapses before we have sent confirmation, then
     /// the message will be requeued.
     ///
-    /// Timeouts are given in milliseconds. A timeout of 0 indicates no timeout.
+    /// Timeouts are given in milliseconds. A timeout of 0 uses the lease timeout
+    /// of the queue, which is no timeout unless the queue is configured with
+    /// one.
+    ///
+    /// Regardless of the timeout, the message is requeued immediately if the
+    /// connection is closed before the message is confirmed.
     Read(StrBox<'a>, u64),
 
     /// Confirm that we have processed a message to the point that it should not
     /// be requeued.
     ///
     /// This should be called before the timeout on the associated Read message
-    /// elapses.
-    Confirm(Uuid)
+    /// elapses. It does not need to be sent on the same connection as the Read.
+    Confirm(Uuid),
+
+    /// Give back a message without processing it, so it is requeued without
+    /// waiting for the timeout on the associated Read message to elapse.
+    ///
+    /// The message is requeued after the given delay in milliseconds. A delay
+    /// of 0 requeues it immediately. Once nacked, the message can no longer
+    /// be confirmed.
+    Nack(Uuid, u64),
+
+    /// Extend the timeout on a Read message which is taking longer to process
+    /// than expected.
+    ///
+    /// The message will be requeued if it is not confirmed within the given
+    /// number of milliseconds from now, replacing the previous timeout. A
+    /// timeout of 0 would hold the message forever, so it is refused with
+    /// BadRequest.
+    ///
+    /// The lease may be extended through any connection, but the message
+    /// stays leased to the connection which read it.
+    ExtendLease(Uuid, u64),
+
+    /// Create a new queue with specific options.
+    ///
+    /// If the queue already exists, its options are left unchanged, and can
+    /// be changed with AlterQueue instead.
+    CreateQueueWithOptions(StrBox<'a>, QueueOptions),
+
+    /// Enqueue a new object on an existing queue, which only becomes
+    /// available to Read once the given delay in milliseconds has elapsed.
+    ///
+    /// Until then the object is held by the server, and is lost if the
+    /// server stops unless its queues are durable. A delay of 0 enqueues it
+    /// immediately. Objects which still do not fit in the queue a minute
+    /// after their delay are moved to its dead-letter queue, or discarded.
+    EnqueueDelayed(StrBox<'a>, SliceBox<'a, u8>, u64),
+
+    /// Enqueue a new object on an existing queue, which expires if it has
+    /// not been read within the given number of milliseconds.
+    ///
+    /// Expired objects are never returned by Read, and are moved to the
+    /// dead-letter queue of the queue if it has one. A time-to-live of 0
+    /// uses the default of the queue.
+    EnqueueWithTtl(StrBox<'a>, SliceBox<'a, u8>, u64),
+
+    /// Enqueue a new object on an existing queue with a priority, where
+    /// objects with higher priorities are read first.
+    ///
+    /// Queues which do not support priorities ignore it, and Enqueue uses
+    /// a priority of 0.
+    EnqueueWithPriority(StrBox<'a>, SliceBox<'a, u8>, u8),
+
+    /// Append a chunk of at most `CHUNK_LEN` bytes to the object being
+    /// uploaded on this connection.
+    ///
+    /// The object counts against the memory budget of the server as it is
+    /// uploaded, and the upload is dropped if a chunk would take the server
+    /// over it.
+    Chunk(SliceBox<'a, u8>),
+
+    /// Enqueue the object uploaded on this connection by Chunk messages on
+    /// an existing queue, and start a new upload.
+    EnqueueUpload(StrBox<'a>),
+
+    /// Fetch the chunk of a read object starting at the given offset, after
+    /// the object was too large to be sent in response to the Read.
+    ReadChunk(Uuid, u64),
+
+    /// Enqueue up to `MAX_BATCH` objects of at most `CHUNK_LEN` bytes on an
+    /// existing queue, in order. The whole message must fit in the server's
+    /// message limit.
+    EnqueueBatch(StrBox<'a>, Vec<SliceBox<'a, u8>>),
+
+    /// Read up to the given number of objects, at most `MAX_BATCH`, from an
+    /// existing queue, each with the given timeout as for Read.
+    ReadBatch(StrBox<'a>, u32, u64),
+
+    /// Confirm up to `MAX_BATCH` objects at once.
+    ConfirmBatch(Vec<Uuid>),
+
+    /// Read an object from an existing queue, as for Read, waiting up to the
+    /// second number of milliseconds for one to be enqueued if the queue is
+    /// empty.
+    ///
+    /// The server answers Empty only once the wait elapses. A wait of 0
+    /// answers straight away, like Read.
+    ReadWait(StrBox<'a>, u64, u64),
+
+    /// Subscribe to an existing queue, so the server pushes objects from it
+    /// as they arrive, each read as for Read with the given timeout.
+    ///
+    /// At most `prefetch`, the first number, objects are pushed which have
+    /// not yet been confirmed, nacked, or had their timeout elapse, on this
+    /// connection. Pushed objects are sent in PUSH frames carrying the id of
+    /// this request.
+    ///
+    /// Subscribing to a queue again replaces the previous subscription.
+    Subscribe(StrBox<'a>, u32, u64),
+
+    /// Stop pushing objects from a queue. Objects already pushed stay read.
+    Unsubscribe(StrBox<'a>),
+
+    /// Bind an existing queue, the second name, to a topic, the first,
+    /// creating the topic if it does not exist.
+    ///
+    /// The queue gets a copy of every object published to the topic, as if
+    /// bound with the pattern `#`.
+    Bind(StrBox<'a>, StrBox<'a>),
+
+    /// Unbind a queue, the second name, from a topic, the first, whatever
+    /// patterns it was bound with. The topic is removed once no queues are
+    /// bound to it.
+    Unbind(StrBox<'a>, StrBox<'a>),
+
+    /// Enqueue a separate copy of an object of at most `CHUNK_LEN` bytes,
+    /// with its own id, on every queue bound to an existing topic, as if
+    /// published with an empty routing key.
+    Publish(StrBox<'a>, SliceBox<'a, u8>),
+
+    /// Bind an existing queue to a topic as for Bind, but only for objects
+    /// published with a routing key matching the pattern, the third name.
+    ///
+    /// Routing keys and patterns are words separated by dots. In a pattern,
+    /// the word `*` matches any single word, and `#` any number of words.
+    BindWithPattern(StrBox<'a>, StrBox<'a>, StrBox<'a>),
+
+    /// Publish an object to a topic as for Publish, with a routing key, the
+    /// second name, so it is only copied to queues bound with a matching
+    /// pattern.
+    PublishWithKey(StrBox<'a>, StrBox<'a>, SliceBox<'a, u8>),
+
+    /// List the names of the queues, in order, starting after the given
+    /// name, or from the first queue if there is none.
+    ListQueues(Option<StrBox<'a>>),
+
+    /// Fetch the statistics of an existing queue.
+    QueueStats(StrBox<'a>),
+
+    /// Look at up to the given number of objects, at most `MAX_BATCH`, in an
+    /// existing queue, in the order they would be read, after skipping the
+    /// first number of objects.
+    ///
+    /// The objects stay queued, are not leased, and their deliveries are not
+    /// counted.
+    Peek(StrBox<'a>, u32, u32),
+
+    /// Fetch the chunk of a peeked object starting at the given offset, after
+    /// the object was too large to be sent in response to Peek or Browse.
+    ///
+    /// The server copies such objects when they are peeked at, so they can
+    /// be fetched even if they are read meanwhile, but only until their last
+    /// chunk is fetched or the next Peek or Browse on the connection.
+    PeekChunk(StrBox<'a>, Uuid, u64),
+
+    /// Remove every object waiting to be read from an existing queue, leaving
+    /// the queue in place. Objects which are leased are not removed.
+    Purge(StrBox<'a>),
+
+    /// Remove a single object waiting to be read, from whichever queue holds
+    /// it. Objects which are leased cannot be removed.
+    DeleteMessage(Uuid),
+
+    /// Move up to the given number of objects from the front of an existing
+    /// queue, the first name, to the back of another, the second, with no
+    /// deliveries. Objects which are leased are not moved, and expired objects
+    /// are moved to the dead-letter queue of the first queue instead.
+    ///
+    /// Moving stops at the first object which does not fit in the second
+    /// queue, and it stays where it was. Moving objects to the queue they are
+    /// in is answered with BadRequest.
+    Move(StrBox<'a>, StrBox<'a>, u32),
+
+    /// Fetch the options of an existing queue.
+    GetQueueConfig(StrBox<'a>),
+
+    /// Replace the options of an existing queue.
+    ///
+    /// The new options apply to objects enqueued or read from then on.
+    /// Objects already queued are kept, even if the queue is now over its
+    /// limits.
+    AlterQueue(StrBox<'a>, QueueOptions),
+
+    /// Fetch the memory used by the server.
+    MemoryStats,
+
+    /// Look at objects in an existing queue as for Peek, starting just after
+    /// the object with the given id, or from the front of the queue if there
+    /// is none, so a queue can be browsed a page at a time without objects
+    /// read meanwhile shifting the rest.
+    ///
+    /// If that object is no longer queued, the response is CursorLost.
+    Browse(StrBox<'a>, Option<Uuid>, u32)
 }
 
 #[derive(Debug, RustcDecodable, RustcEncodable, PartialEq)]
@@ -57,8 +453,9 @@ pub enum ServerMessage<'a> {
     ObjectQueued(Uuid),
 
     /// The response to Read ClientMessage's, which contains the data and
-    /// the id of that data.
-    Read(Uuid, SliceBox<'a, u8>),
+    /// the id of that data, and the number of times the data has been read,
+    /// including this time.
+    Read(Uuid, SliceBox<'a, u8>, u32),
 
     /// The Confirm message was received in time, and the data has not been
     /// requeued.
@@ -68,6 +465,9 @@ pub enum ServerMessage<'a> {
     Requeued,
 
     /// Attempted to queue or re-queue, but the queue was full.
+    ///
+    /// Objects larger than `CHUNK_LEN` are not sent back, and the data is
+    /// left empty.
     Full(Uuid, SliceBox<'a, u8>),
 
     /// A Read was requested on a queue with no data.
@@ -75,17 +475,205 @@ pub enum ServerMessage<'a> {
 
     /// A message was sent with a non-existent uuid, or a queue was accessed that
     /// does not exist.
-    NoSuchEntity
+    NoSuchEntity,
+
+    /// The Nack message was received, and the data will be requeued.
+    Nacked,
+
+    /// The timeout of the Read was replaced by the one in the ExtendLease
+    /// message.
+    LeaseExtended,
+
+    /// The response to Read ClientMessage's for objects larger than
+    /// `CHUNK_LEN`, which contains the id and the length of the object, and
+    /// the number of times it has been read, including this time.
+    ///
+    /// The data is then fetched using ReadChunk.
+    ReadChunked(Uuid, u64, u32),
+
+    /// A chunk of a read object, in response to ReadChunk.
+    Chunk(SliceBox<'a, u8>),
+
+    /// The chunk was appended to the object being uploaded.
+    ChunkReceived,
+
+    /// The object being uploaded grew larger than the server allows, and
+    /// was discarded.
+    ///
+    /// Also sent in response to batches of more than `MAX_BATCH` objects.
+    TooLarge,
+
+    /// The response to EnqueueBatch, containing the ids of the queued
+    /// objects in order.
+    ///
+    /// If the queue filled up part way through the batch, or stopped
+    /// blocking while part of it was held back, there are fewer ids than
+    /// objects, and the remaining objects were not queued.
+    ObjectsQueued(Vec<Uuid>),
+
+    /// The response to ReadBatch, which is empty if the queue was empty.
+    ReadBatch(Vec<BatchedObject>),
+
+    /// The response to ConfirmBatch, containing the ids of the objects
+    /// which could not be confirmed because they were requeued, their queue
+    /// was full, or they did not exist.
+    BatchConfirmed(Vec<Uuid>),
+
+    /// The subscription was started, and objects will be pushed as Read or
+    /// ReadChunked messages, or NoSuchEntity if the queue is deleted.
+    Subscribed,
+
+    /// The subscription was stopped.
+    Unsubscribed,
+
+    /// The queue was bound to the topic, or already was.
+    Bound,
+
+    /// The queue was unbound from the topic.
+    Unbound,
+
+    /// The response to Publish, containing the ids of the copies which were
+    /// queued, followed by the names of the bound queues which were full.
+    Published(Vec<Uuid>, Vec<String>),
+
+    /// The response to ListQueues, containing as many names as fit in a
+    /// single message, and whether there are more names after them.
+    Queues(Vec<String>, bool),
+
+    /// The response to QueueStats.
+    QueueStats(QueueStats),
+
+    /// The response to Peek and Browse, which is empty if there were no
+    /// objects to look at.
+    Peeked(Vec<PeekedObject>),
+
+    /// The response to Purge, containing the number of objects removed.
+    Purged(u64),
+
+    /// The object was removed by DeleteMessage.
+    MessageDeleted,
+
+    /// The object could not be removed because it is leased. It can be
+    /// removed once it is requeued, or confirmed instead.
+    Leased,
+
+    /// The response to Move, containing the number of objects moved.
+    Moved(u64),
+
+    /// The response to GetQueueConfig.
+    QueueConfig(QueueOptions),
+
+    /// The options of the queue were replaced.
+    QueueAltered,
+
+    /// The response to MemoryStats.
+    MemoryStats(MemoryStats),
+
+    /// Attempted to queue objects, or upload a chunk of one, but they would
+    /// take the server or the queue over its memory budget, so none of them
+    /// were queued.
+    OverBudget,
+
+    /// The request could not be told apart from another request on the same
+    /// connection, such as a ReadWait with the id of one still waiting, or
+    /// asked for something the server does not allow, such as extending a
+    /// lease by 0.
+    BadRequest,
+
+    /// The response to Move when either queue does not exist, naming the
+    /// first which does not.
+    NoSuchQueue(String),
+
+    /// The response to Browse when the object to continue after is no longer
+    /// queued, because it was read or removed.
+    CursorLost
 }
 
 impl<'a> ClientMessage<'a> {
-    /// Called on the client, to serialize over the wire.
+    /// The type of this message, sent in the header of its frame.
+    pub fn tag(&self) -> u16 {
+        match *self {
+            ClientMessage::CreateQueue(..) => 0,
+            ClientMessage::DeleteQueue(..) => 1,
+            ClientMessage::Enqueue(..) => 2,
+            ClientMessage::Read(..) => 3,
+            ClientMessage::Confirm(..) => 4,
+            ClientMessage::Nack(..) => 5,
+            ClientMessage::ExtendLease(..) => 6,
+            ClientMessage::CreateQueueWithOptions(..) => 7,
+            ClientMessage::EnqueueDelayed(..) => 8,
+            ClientMessage::EnqueueWithTtl(..) => 9,
+            ClientMessage::EnqueueWithPriority(..) => 10,
+            ClientMessage::Chunk(..) => 11,
+            ClientMessage::EnqueueUpload(..) => 12,
+            ClientMessage::ReadChunk(..) => 13,
+            ClientMessage::EnqueueBatch(..) => 14,
+            ClientMessage::ReadBatch(..) => 15,
+            ClientMessage::ConfirmBatch(..) => 16,
+            ClientMessage::ReadWait(..) => 17,
+            ClientMessage::Subscribe(..) => 18,
+            ClientMessage::Unsubscribe(..) => 19,
+            ClientMessage::Bind(..) => 20,
+            ClientMessage::Unbind(..) => 21,
+            ClientMessage::Publish(..) => 22,
+            ClientMessage::BindWithPattern(..) => 23,
+            ClientMessage::PublishWithKey(..) => 24,
+            ClientMessage::ListQueues(..) => 25,
+            ClientMessage::QueueStats(..) => 26,
+            ClientMessage::Peek(..) => 27,
+            ClientMessage::PeekChunk(..) => 28,
+            ClientMessage::Purge(..) => 29,
+            ClientMessage::DeleteMessage(..) => 30,
+            ClientMessage::Move(..) => 31,
+            ClientMessage::GetQueueConfig(..) => 32,
+            ClientMessage::AlterQueue(..) => 33,
+            ClientMessage::MemoryStats => 34,
+            ClientMessage::Browse(..) => 35
+        }
+    }
+
+    /// The optional feature of the protocol this message belongs to, which
+    /// has to be agreed on in the handshake before the message is sent.
+    ///
+    /// Messages which are part of the core protocol belong to no feature.
+    pub fn feature(&self) -> Option<&'static str> {
+        ClientMessage::feature_of(self.tag())
+    }
+
+    /// The optional feature of the protocol the messages of the type `tag`
+    /// belong to, as for `feature`, so the server can refuse them before it
+    /// decodes them.
+    pub fn feature_of(tag: u16) -> Option<&'static str> {
+        Some(match tag {
+            8 => "delayed",
+            9 => "ttl",
+            10 => "priority",
+            11...13 => "chunked",
+            14...16 => "batch",
+            17 => "long-poll",
+            18...19 => "subscribe",
+            20...22 => "topics",
+            23...24 => "routing",
+            25...26 => "introspection",
+            27...28 => "peek",
+            29...31 => "admin",
+            7 | 32...33 => "config",
+            34 => "memory",
+            35 => "browse",
+            _ => return None
+        })
+    }
+
+    /// Called on the client, to serialize over the wire as a single frame
+    /// with the given request id, no longer than the limit the server gave
+    /// in its Welcome.
     #[inline]
-    pub fn encode_to<W: Write>(&self, write: &mut W) -> EncodingResult<()> {
-        bincode::encode_into(self, write, CLIENT_SIZE_LIMIT)
+    pub fn encode_to<W: Write>(&self, write: &mut W, request: RequestId,
+                               limit: u64) -> EncodingResult<()> {
+        frame::encode_into(self, write, MESSAGE, self.tag(), request, SizeLimit::Bounded(limit))
     }
 
-    /// Called on the server, to deserialize from a received message.
+    /// Called on the server, to deserialize from the body of a received frame.
     #[inline]
     pub fn decode(buf: &[u8]) -> DecodingResult<(ClientMessage<'static>, u64)> {
         bincode::decode(buf)
@@ -93,19 +681,80 @@ impl<'a> ClientMessage<'a> {
 }
 
 impl<'a> ServerMessage<'a> {
-    /// Called on the server, to serialize over the wire.
+    /// The type of this message, sent in the header of its frame.
+    pub fn tag(&self) -> u16 {
+        match *self {
+            ServerMessage::QueueCreated => 0,
+            ServerMessage::QueueDeleted => 1,
+            ServerMessage::ObjectQueued(..) => 2,
+            ServerMessage::Read(..) => 3,
+            ServerMessage::Confirmed => 4,
+            ServerMessage::Requeued => 5,
+            ServerMessage::Full(..) => 6,
+            ServerMessage::Empty => 7,
+            ServerMessage::NoSuchEntity => 8,
+            ServerMessage::Nacked => 9,
+            ServerMessage::LeaseExtended => 10,
+            ServerMessage::ReadChunked(..) => 11,
+            ServerMessage::Chunk(..) => 12,
+            ServerMessage::ChunkReceived => 13,
+            ServerMessage::TooLarge => 14,
+            ServerMessage::ObjectsQueued(..) => 15,
+            ServerMessage::ReadBatch(..) => 16,
+            ServerMessage::BatchConfirmed(..) => 17,
+            ServerMessage::Subscribed => 18,
+            ServerMessage::Unsubscribed => 19,
+            ServerMessage::Bound => 20,
+            ServerMessage::Unbound => 21,
+            ServerMessage::Published(..) => 22,
+            ServerMessage::Queues(..) => 23,
+            ServerMessage::QueueStats(..) => 24,
+            ServerMessage::Peeked(..) => 25,
+            ServerMessage::Purged(..) => 26,
+            ServerMessage::MessageDeleted => 27,
+            ServerMessage::Leased => 28,
+            ServerMessage::Moved(..) => 29,
+            ServerMessage::QueueConfig(..) => 30,
+            ServerMessage::QueueAltered => 31,
+            ServerMessage::MemoryStats(..) => 32,
+            ServerMessage::OverBudget => 33,
+            ServerMessage::BadRequest => 34,
+            ServerMessage::NoSuchQueue(..) => 35,
+            ServerMessage::CursorLost => 36
+        }
+    }
+
+    /// Called on the server, to serialize over the wire as a single frame,
+    /// in response to the request with the given id.
     #[inline]
-    pub fn encode(&self) -> EncodingResult<Vec<u8>> {
-        bincode::encode(self, SERVER_SIZE_LIMIT)
+    pub fn encode(&self, request: RequestId) -> EncodingResult<Vec<u8>> {
+        frame::encode(self, MESSAGE, self.tag(), request, SERVER_SIZE_LIMIT)
     }
 
-    /// Called on the client, to deserialize over the wire.
+    /// Called on the server, to serialize over the wire as a single frame
+    /// pushed to the subscription started by the request with the given id.
+    #[inline]
+    pub fn encode_push(&self, subscription: RequestId) -> EncodingResult<Vec<u8>> {
+        frame::encode(self, PUSH, self.tag(), subscription, SERVER_SIZE_LIMIT)
+    }
+
+    /// Called on the client, to deserialize a single frame from the wire,
+    /// along with the id of the request it responds to.
     #[inline]
     pub fn decode_from<R: Read>(read: &mut R)
-            -> DecodingResult<(ServerMessage<'static>, u64)> {
-        bincode::decode_from(read, SERVER_SIZE_LIMIT)
+            -> Result<(ServerMessage<'static>, RequestId), ProtocolError> {
+        frame::decode_from(read, MESSAGE, MAX_SERVER_MESSAGE_LEN)
+    }
+
+    /// Called on the client, to deserialize a single response or pushed
+    /// frame from the wire, along with its header.
+    #[inline]
+    pub fn decode_any_from<R: Read>(read: &mut R)
+            -> Result<(ServerMessage<'static>, Header), ProtocolError> {
+        frame::decode_any_from(read, &[MESSAGE, PUSH], MAX_SERVER_MESSAGE_LEN)
     }
 
+    /// Deserialize from the body of a received frame.
     #[inline]
     pub fn decode(data: &[u8]) -> DecodingResult<(ServerMessage, u64)> {
         bincode::decode(data)
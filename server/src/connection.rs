@@ -1,16 +1,34 @@
-use mio::{EventLoop, NonBlock};
-use eventual::{self, Future, Async, Complete, AsyncError};
+use mio::{self, EventLoop, NonBlock, Token};
 use uuid::Uuid;
 
-use common::{ClientMessage, ServerMessage, SliceBox, MAX_CLIENT_MESSAGE_LEN};
-use rt::Handler;
-use queue::{Queue, Queues};
+use common::{ClientMessage, ServerMessage, SliceBox, QueueOptions, BatchedObject, PeekedObject,
+             Overflow, BATCHED_OBJECT_OVERHEAD, PEEKED_OBJECT_OVERHEAD, CHUNK_LEN, MAX_BATCH,
+             MAX_SERVER_MESSAGE_LEN};
+use common::{Header, Hello, Welcome, ProtocolError, RequestId, HEADER_LEN, HANDSHAKE, MESSAGE,
+             PROTOCOL_VERSION, FEATURES, tagged};
+use rt::{Handler, Timeout};
+use wait::{self, Waiting, Retry};
+use queue::{self, Queue, Queues, Entry, Memory, Signal};
+use lease::{Leases, Holder, Confirmation, Nack, Renewal, Expiry};
 
 use std::net::TcpStream;
-use std::io::{self, Cursor, ErrorKind};
-use std::collections::{HashMap, VecDeque};
+use std::io::{self, Cursor, ErrorKind, Read};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::{cmp, mem};
 
-use {Error};
+use {Error, Limits};
+
+/// An upper bound on the encoded size of a Queues response without its names.
+const LIST_QUEUES_OVERHEAD: usize = 32;
+
+/// The most frames of the largest allowed size read from a connection
+/// before they are handled, so a client sending many chunks at once is not
+/// buffered in full.
+const READ_WINDOW: u64 = 4;
+
+/// The most objects a single connection can have held back by queues which
+/// block producers. Any more are rejected as if the queue did not block.
+const MAX_BLOCKED: usize = 1024;
 
 /// An existing Connection with a single Client.
 pub struct Connection<Q: Queue> {
@@ -22,38 +40,180 @@ pub struct Connection<Q: Queue> {
     /// We read out ClientMessages from here.
     incoming: Vec<u8>,
 
-    /// Pending outgoing messages.
+    /// Pending outgoing messages, which are counted in `memory` until they
+    /// are written.
     outgoing: VecDeque<Cursor<Vec<u8>>>,
 
-    /// Pending Reads which have yet to be Confirmed.
-    ///
-    /// The keys are the Uuid's of the data which has been read out but
-    /// not confirmed.
+    /// The memory used by the queues, shared with every connection to them,
+    /// in which this connection holds its upload and the objects held back
+    /// by full queues.
+    memory: Memory,
+
+    /// The id of the event loop this connection is registered with.
+    event_loop: usize,
+
+    /// The objects read, but not yet confirmed, by all connections.
+    leases: Leases<Q>,
+
+    /// Finds a queue by name, to move objects whose lease ended to their
+    /// dead-letter queue.
+    resolve: Box<Fn(&str) -> Option<Q> + Send>,
+
+    /// The objects read on this connection which have not been confirmed
+    /// on this connection yet.
     ///
-    /// Each value contains a Complete which will be completed when we do
-    /// receive a confirm message, and a second cancellation Future which
-    /// will be completed if the timeout on the Read elapsed and the data
-    /// was requeued.
+    /// The values are the id of the lease and the timeout of the Read, if
+    /// it had a timeout.
     ///
-    /// In the event that the queue in question was full when the timeout
-    /// elapsed, the cancellation future will be failed with the queue, the
-    /// id of the data, and the object itself.
+    /// Objects can also be confirmed or given back through other
+    /// connections, in which case `Leases` tells this connection to forget
+    /// them.
+    leased: HashMap<Uuid, (u64, Option<mio::Timeout>)>,
+
+    /// The limits on the size of messages and objects from the client.
+    limits: Limits,
+
+    /// The object being uploaded by Chunk messages, which is held in
+    /// `memory`.
+    upload: Vec<u8>,
+
+    /// Copies of the objects in the last Peek or Browse response which were
+    /// too large to send in it, keyed by id, along with the name of their
+    /// queue, until their last chunk is fetched by PeekChunk.
+    peeking: HashMap<Uuid, (String, Vec<u8>)>,
+
+    /// How far the Hello/Welcome handshake has got.
+    handshake: Handshake,
+
+    /// The optional features agreed on in the handshake.
+    features: Vec<String>,
+
+    /// The ReadWait requests on this connection which are waiting for an
+    /// object, keyed by the id of the request.
+    parked: HashMap<RequestId, Parked>,
+
+    /// The subscriptions on this connection, keyed by the name of the queue.
+    subscriptions: HashMap<String, Subscription>,
+
+    /// The objects sent on this connection which are held back until their
+    /// queue has room for them, in the order they were sent.
     ///
-    /// If the cancellation future is *aborted* rather than failed, due to
-    /// the cancellation future never being completed or failed, the data
-    /// was requeued succesfully after the timeout elapsed.
-    unconfirmed: HashMap<Uuid, (Complete<(), Error>, Future<(), (Q, Uuid, Vec<u8>)>)>
+    /// The connection waits on the room signal of each queue holding back
+    /// objects, and tries them again whenever the queue makes room.
+    blocked: VecDeque<Blocked>,
+
+    /// The responses to EnqueueBatch and Publish requests with objects held
+    /// back in `blocked`, keyed by the id of the request, which are sent
+    /// once every one of those objects is settled.
+    pending: HashMap<RequestId, Pending>
+}
+
+/// An object sent to a full queue which blocks producers, waiting for room.
+struct Blocked {
+    /// The id of the request, which is answered once the object is enqueued.
+    request: RequestId,
+
+    /// The name of the queue.
+    queue: String,
+
+    entry: Entry,
+
+    /// Whether the object is one of several sent by the request, which is
+    /// answered from `pending` instead of on its own.
+    part: bool
+}
+
+/// The response to a request which sent several objects, some of which are
+/// held back by queues which block producers.
+enum Pending {
+    /// An EnqueueBatch, with the ids of every object in the batch, and how
+    /// many of them, at the end of the batch, are still held back.
+    Batch(Vec<Uuid>, usize),
+
+    /// A Publish, with the ids of the copies queued so far, the names of the
+    /// bound queues which did not take a copy, and how many copies are still
+    /// held back.
+    Publish(Vec<Uuid>, Vec<String>, usize)
+}
+
+/// A ReadWait request waiting for an object to be enqueued.
+struct Parked {
+    /// The name of the queue.
+    queue: String,
+
+    /// The timeout to read the object with.
+    timeout: u64,
+
+    /// The timer which answers the request with Empty if it is still
+    /// waiting.
+    wait: mio::Timeout
+}
+
+/// A subscription to a queue, which pushes objects to the client as they
+/// arrive.
+struct Subscription {
+    /// The id of the Subscribe request, which every pushed object carries.
+    request: RequestId,
+
+    /// The most objects which can be outstanding at once.
+    prefetch: u32,
+
+    /// The timeout to read pushed objects with.
+    timeout: u64,
+
+    /// The objects pushed which have not been confirmed, nacked, or had
+    /// their timeout elapse on this connection.
+    outstanding: HashSet<Uuid>,
+
+    /// Whether the subscription is waiting for objects to be enqueued.
+    waiting: bool
+}
+
+impl Subscription {
+    /// Whether more objects can be pushed.
+    fn open(&self) -> bool {
+        (self.outstanding.len() as u64) < self.prefetch as u64
+    }
+}
+
+/// The state of the handshake at the start of a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Handshake {
+    /// Waiting for the Hello.
+    Pending,
+
+    /// The client's protocol version was accepted.
+    Accepted,
+
+    /// The client's protocol version was refused, so we do not understand
+    /// any of its messages.
+    Rejected
 }
 
 impl<Q: Queue> Connection<Q> {
     /// Create a new connection from a stream.
     #[inline]
-    pub fn new(connection: NonBlock<TcpStream>) -> Connection<Q> {
+    pub fn new(connection: NonBlock<TcpStream>, event_loop: usize, leases: Leases<Q>,
+               resolve: Box<Fn(&str) -> Option<Q> + Send>, memory: Memory,
+               limits: Limits) -> Connection<Q> {
         Connection {
             connection: connection,
             incoming: Vec::new(),
             outgoing: VecDeque::new(),
-            unconfirmed: HashMap::new()
+            memory: memory,
+            event_loop: event_loop,
+            leases: leases,
+            resolve: resolve,
+            leased: HashMap::new(),
+            limits: limits,
+            upload: Vec::new(),
+            peeking: HashMap::new(),
+            handshake: Handshake::Pending,
+            features: Vec::new(),
+            parked: HashMap::new(),
+            subscriptions: HashMap::new(),
+            blocked: VecDeque::new(),
+            pending: HashMap::new()
         }
     }
 
@@ -65,63 +225,451 @@ impl<Q: Queue> Connection<Q> {
 
     /// Handle a readable event on this connection, using the passed queues and
     /// event loop.
+    ///
+    /// `token` is the token this connection is registered with, and is used
+    /// to route the timeouts of Read requests back to this connection.
     #[inline]
-    pub fn readable<Qu>(&mut self, queues: &Qu, evloop: &mut EventLoop<Handler<Qu>>)
-        -> Result<(), Error>
+    pub fn readable<Qu>(&mut self, token: Token, queues: &Qu, waiting: &Waiting,
+                        evloop: &mut EventLoop<Handler<Qu>>) -> Result<(), Error>
     where Qu: Queues<Queue=Q> + Send {
-        match io::copy(&mut self.connection, &mut self.incoming) {
-            Ok(_) => {},
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
-            Err(e) => return Err(Error::from(e)),
-        };
+        // Whatever is left in the buffer is less than a whole frame, so there
+        // is always room to read more.
+        let window = self.limits.max_message_len.saturating_add(HEADER_LEN as u64)
+            .saturating_mul(READ_WINDOW);
 
-        // Process 1 or more messages read into the incoming buffer.
-        //
-        // Under request pipelining, we may be able to handle many messages
-        // at once.
-        while let Ok((message, message_len)) =
-                ClientMessage::<'static>::decode(&self.incoming) {
-            // Chop off the message we just processed.
-            self.incoming = self.incoming[message_len as usize..].to_vec();
-
-            let outgoing = Cursor::new(try!(match message {
-                ClientMessage::CreateQueue(id) => {
-                    queues.insert(id.take());
-                    ServerMessage::QueueCreated
-                },
+        loop {
+            // io::copy only returns succesfully once it reaches the end of the
+            // window or the stream, meaning the client closed the connection.
+            let room = window - self.incoming.len() as u64;
+            let (closed, full) = match io::copy(&mut self.connection.by_ref().take(room),
+                                                &mut self.incoming) {
+                Ok(read) => (read < room, read == room),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (false, false),
+                Err(e) => return Err(Error::from(e)),
+            };
 
-                ClientMessage::DeleteQueue(id) => {
-                    queues.remove(id.as_ref())
-                        .map(|_| ServerMessage::QueueDeleted)
-                        .unwrap_or(ServerMessage::NoSuchEntity)
-                },
+            try!(self.frames(token, queues, waiting, evloop));
+
+            if closed { return Err(Error::Disconnected) }
+            if !full { break }
+        }
+
+        // Confirms may have made room for more objects to be pushed.
+        self.pump(token, queues, waiting, evloop)
+    }
+
+    /// Handle every whole frame in the incoming buffer.
+    ///
+    /// Under request pipelining, we may be able to handle many messages
+    /// at once.
+    fn frames<Qu>(&mut self, token: Token, queues: &Qu, waiting: &Waiting,
+                  evloop: &mut EventLoop<Handler<Qu>>) -> Result<(), Error>
+    where Qu: Queues<Queue=Q> + Send {
+        while let Some(header) = Header::parse(&self.incoming) {
+            // Check the length before the whole frame arrives, so we never
+            // buffer an overlong message.
+            if header.len as u64 > self.limits.max_message_len {
+                return Err(Error::OverLongMessage)
+            }
+
+            let end = HEADER_LEN + header.len as usize;
+            if self.incoming.len() < end { break }
+
+            // Chop off the frame we are about to process.
+            let body = self.incoming[HEADER_LEN..end].to_vec();
+            self.incoming = self.incoming[end..].to_vec();
+
+            let outgoing = match (self.handshake, header.kind) {
+                (Handshake::Pending, HANDSHAKE) =>
+                    try!(self.hello(&body, queues.topics().is_some())),
 
-                ClientMessage::Enqueue(id, object) => {
-                    let uuid = Uuid::new_v4();
-                    queues.queue(id.as_ref()).map(|queue| {
-                        match queue.enqueue(uuid.clone(), object.take()) {
-                            Ok(()) => ServerMessage::ObjectQueued(uuid),
-                            Err((uuid, data)) =>
-                                ServerMessage::Full(uuid, SliceBox::boxed(data))
+                (Handshake::Accepted, MESSAGE) => {
+                    if header.version != PROTOCOL_VERSION {
+                        return Err(Error::Protocol(ProtocolError::Version(header.version)))
+                    }
+
+                    // Refuse messages of features which were not agreed on
+                    // before decoding them.
+                    if let Some(feature) = ClientMessage::feature_of(header.tag) {
+                        if !self.features.iter().any(|agreed| agreed == feature) {
+                            let feature = feature.to_string();
+                            return Err(Error::Protocol(ProtocolError::Feature(feature)))
                         }
-                    }).unwrap_or(ServerMessage::NoSuchEntity)
+                    }
+
+                    if !tagged(&body, header.tag) {
+                        return Err(Error::Protocol(ProtocolError::Type(header.tag)))
+                    }
+
+                    let (message, _) = try!(ClientMessage::<'static>::decode(&body));
+
+                    match try!(self.respond(token, header.request, queues, waiting, evloop,
+                                            message)) {
+                        Some(response) => try!(response.encode(header.request)),
+
+                        // The response is sent once an object arrives.
+                        None => continue
+                    }
                 },
 
-                ClientMessage::Read(id, timeout) =>
-                    try!(self.read_ms(evloop, queues, id.as_ref(), timeout)),
+                (Handshake::Rejected, _) =>
+                    return Err(Error::Protocol(ProtocolError::Unsupported(PROTOCOL_VERSION))),
 
-                ClientMessage::Confirm(uuid) => self.confirm(&uuid)
-            }.encode()));
+                (_, kind) => return Err(Error::Protocol(ProtocolError::UnexpectedKind(kind)))
+            };
 
-            self.outgoing.push_back(outgoing);
+            self.push(outgoing);
         }
 
-        if self.incoming.len() as u64 > MAX_CLIENT_MESSAGE_LEN {
-            // The client has sent an overlong message.
-            Err(Error::OverLongMessage)
+        Ok(())
+    }
+
+    /// Respond to the Hello which starts every connection, agreeing on the
+    /// protocol version and the features to use.
+    ///
+    /// Publishing is only agreed to if the queues have `topics`.
+    fn hello(&mut self, body: &[u8], topics: bool) -> Result<Vec<u8>, Error> {
+        let (hello, _) = try!(Hello::decode(body));
+
+        let welcome = if hello.version == PROTOCOL_VERSION {
+            self.handshake = Handshake::Accepted;
+
+            self.features = hello.features.into_iter()
+                .filter(|feature| FEATURES.contains(&&**feature))
+                .filter(|feature| topics || (feature != "topics" && feature != "routing"))
+                .collect();
+            Welcome::Accepted(PROTOCOL_VERSION, self.features.clone(),
+                              self.limits.max_message_len)
         } else {
-            Ok(())
-        }
+            warn!("Client requested unsupported protocol version {}.", hello.version);
+            self.handshake = Handshake::Rejected;
+            Welcome::Unsupported(PROTOCOL_VERSION)
+        };
+
+        Ok(try!(welcome.encode()))
+    }
+
+    /// Handle a single message from the client, producing the response.
+    ///
+    /// Returns None if the response will be sent later, after a Read has
+    /// waited for an object, or an object has waited for room in its queue.
+    fn respond<Qu>(&mut self, token: Token, request: RequestId, queues: &Qu,
+                   waiting: &Waiting, evloop: &mut EventLoop<Handler<Qu>>,
+                   message: ClientMessage<'static>)
+                   -> Result<Option<ServerMessage<'static>>, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        Ok(Some(match message {
+            ClientMessage::CreateQueue(id) => {
+                queues.insert(id.take(), QueueOptions::default());
+                ServerMessage::QueueCreated
+            },
+
+            ClientMessage::CreateQueueWithOptions(id, options) => {
+                queues.insert(id.take(), options);
+                ServerMessage::QueueCreated
+            },
+
+            ClientMessage::DeleteQueue(id) => {
+                queues.remove(id.as_ref()).map(|queue| {
+                    // Reads waiting on the queue, and producers waiting for
+                    // room in it, find out it is gone.
+                    if let Some(signal) = queue.signal() { signal.notify_all() }
+                    if let Some(room) = queue.room() { room.notify_all() }
+                    if let Some(topics) = queues.topics() { topics.unbind_all(id.as_ref()) }
+                    ServerMessage::QueueDeleted
+                }).unwrap_or(ServerMessage::NoSuchEntity)
+            },
+
+            ClientMessage::Enqueue(id, object) | ClientMessage::EnqueueDelayed(id, object, 0) => {
+                let queue = queues.queue(id.as_ref());
+                match queue {
+                    Some(queue) => {
+                        let entry = new_entry(&queue, object.take(), 0, 0);
+                        return self.enqueue(token, waiting, evloop, request, id.take(), &queue,
+                                            entry)
+                    },
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::EnqueueWithTtl(id, object, ttl) => {
+                let queue = queues.queue(id.as_ref());
+                match queue {
+                    Some(queue) => {
+                        let entry = new_entry(&queue, object.take(), ttl, 0);
+                        return self.enqueue(token, waiting, evloop, request, id.take(), &queue,
+                                            entry)
+                    },
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::EnqueueWithPriority(id, object, priority) => {
+                let queue = queues.queue(id.as_ref());
+                match queue {
+                    Some(queue) => {
+                        let mut entry = new_entry(&queue, object.take(), 0, 0);
+                        entry.priority = priority;
+                        return self.enqueue(token, waiting, evloop, request, id.take(), &queue,
+                                            entry)
+                    },
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::EnqueueDelayed(id, object, delay) =>
+                try!(self.enqueue_delayed(evloop, queues, id.take(), object.take(), delay)),
+
+            ClientMessage::Chunk(chunk) => {
+                let chunk = chunk.take();
+                if (self.upload.len() + chunk.len()) as u64 > self.limits.max_object_len {
+                    self.take_upload();
+                    ServerMessage::TooLarge
+                } else if !self.memory.reserve(chunk.len(), self.limits.max_memory) {
+                    self.take_upload();
+                    ServerMessage::OverBudget
+                } else {
+                    self.upload.extend(chunk.into_iter());
+                    ServerMessage::ChunkReceived
+                }
+            },
+
+            ClientMessage::EnqueueUpload(id) => {
+                let queue = queues.queue(id.as_ref());
+                match queue {
+                    Some(queue) => {
+                        let upload = self.take_upload();
+                        let entry = new_entry(&queue, upload, 0, 0);
+                        return self.enqueue(token, waiting, evloop, request, id.take(), &queue,
+                                            entry)
+                    },
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::ReadChunk(uuid, offset) => {
+                self.leases.chunk(&uuid, offset, CHUNK_LEN, self.holder(token))
+                    .map(|chunk| ServerMessage::Chunk(SliceBox::boxed(chunk)))
+                    .unwrap_or(ServerMessage::NoSuchEntity)
+            },
+
+            ClientMessage::Read(id, timeout) =>
+                try!(self.read_ms(token, evloop, queues, id.as_ref(), timeout)),
+
+            ClientMessage::Confirm(uuid) => self.confirm(token, evloop, &uuid),
+
+            ClientMessage::Nack(uuid, delay) => self.nack(token, evloop, &uuid, delay),
+
+            ClientMessage::ExtendLease(_, 0) => ServerMessage::BadRequest,
+
+            ClientMessage::ExtendLease(uuid, timeout) =>
+                try!(self.extend_lease(token, evloop, &uuid, timeout)),
+
+            ClientMessage::EnqueueBatch(_, ref objects) if objects.len() > MAX_BATCH =>
+                ServerMessage::TooLarge,
+
+            ClientMessage::EnqueueBatch(id, objects) => {
+                let queue = queues.queue(id.as_ref());
+                match queue {
+                    Some(queue) => {
+                        let entries = objects.into_iter()
+                            .map(|object| new_entry(&queue, object.take(), 0, 0))
+                            .collect();
+                        return self.enqueue_batch(token, waiting, evloop, request, id.take(),
+                                                  &queue, entries)
+                    },
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::ReadBatch(id, max, timeout) =>
+                try!(self.read_batch(token, evloop, queues, id.as_ref(), max, timeout)),
+
+            ClientMessage::ConfirmBatch(ref uuids) if uuids.len() > MAX_BATCH =>
+                ServerMessage::TooLarge,
+
+            ClientMessage::ConfirmBatch(uuids) => {
+                ServerMessage::BatchConfirmed(uuids.into_iter().filter(|uuid| {
+                    self.confirm(token, evloop, uuid) != ServerMessage::Confirmed
+                }).collect())
+            },
+
+            // Waiting Reads are told apart by the ids of their requests.
+            ClientMessage::ReadWait(..) if self.waits_with(request) => ServerMessage::BadRequest,
+
+            ClientMessage::ReadWait(id, timeout, wait) => {
+                let signal = wait::note(queues, id.as_ref());
+
+                // Wait behind any Reads already waiting on the queue, rather
+                // than taking an object ahead of them.
+                if wait == 0 || !wait::is_waiting(&signal) {
+                    let response = try!(self.read_ms(token, evloop, queues, id.as_ref(), timeout));
+                    if response != ServerMessage::Empty || wait == 0 { return Ok(Some(response)) }
+                }
+
+                let name = id.take();
+                try!(self.park(token, evloop, name.clone(), request, timeout, wait));
+                waiting.park(name, token, request, signal, evloop.channel());
+
+                return Ok(None)
+            },
+
+            ClientMessage::Subscribe(_, 0, _) => ServerMessage::BadRequest,
+
+            ClientMessage::Subscribe(..) if self.waits_with(request) => ServerMessage::BadRequest,
+
+            ClientMessage::Subscribe(id, prefetch, timeout) => {
+                if queues.queue(id.as_ref()).is_none() {
+                    return Ok(Some(ServerMessage::NoSuchEntity))
+                }
+
+                let name = id.take();
+                let subscription = self.subscriptions.entry(name.clone()).or_insert(Subscription {
+                    request: request,
+                    prefetch: prefetch,
+                    timeout: timeout,
+                    outstanding: HashSet::new(),
+                    waiting: false
+                });
+
+                // A new subscription replaces the old one, but objects pushed
+                // to the old one still take up room.
+                if subscription.waiting {
+                    waiting.unpark(queues, &name, token, subscription.request);
+                    subscription.waiting = false;
+                }
+
+                subscription.request = request;
+                subscription.prefetch = prefetch;
+                subscription.timeout = timeout;
+
+                // Objects are pushed once the response is sent.
+                ServerMessage::Subscribed
+            },
+
+            ClientMessage::Unsubscribe(id) => {
+                match self.subscriptions.remove(id.as_ref()) {
+                    Some(subscription) => {
+                        if subscription.waiting {
+                            waiting.unpark(queues, id.as_ref(), token, subscription.request);
+                        }
+
+                        ServerMessage::Unsubscribed
+                    },
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::Bind(topic, queue) =>
+                bind(queues, topic.take(), queue.take(), "#".to_string()),
+
+            ClientMessage::BindWithPattern(topic, queue, pattern) =>
+                bind(queues, topic.take(), queue.take(), pattern.take()),
+
+            ClientMessage::Unbind(topic, queue) => {
+                if queues.unbind(topic.as_ref(), queue.as_ref()) {
+                    ServerMessage::Unbound
+                } else {
+                    ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::Publish(topic, object) => {
+                let bound = queues.topics().and_then(|topics| topics.route(topic.as_ref(), ""));
+                match bound {
+                    Some(bound) => return self.publish(token, waiting, evloop, request, queues,
+                                                       bound, object.take()),
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::PublishWithKey(topic, key, object) => {
+                let bound = queues.topics()
+                    .and_then(|topics| topics.route(topic.as_ref(), key.as_ref()));
+                match bound {
+                    Some(bound) => return self.publish(token, waiting, evloop, request, queues,
+                                                       bound, object.take()),
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::ListQueues(after) =>
+                list_queues(queues, after.as_ref().map(|after| after.as_ref())),
+
+            ClientMessage::QueueStats(id) => {
+                queues.queue(id.as_ref()).map(|queue| ServerMessage::QueueStats(queue.stats()))
+                    .unwrap_or(ServerMessage::NoSuchEntity)
+            },
+
+            ClientMessage::Peek(id, offset, count) => {
+                match queues.queue(id.as_ref()) {
+                    Some(queue) => {
+                        let entries = queue.peek(offset as usize,
+                                                 cmp::min(count as usize, MAX_BATCH));
+                        self.peek_response(id.as_ref(), entries)
+                    },
+                    None => ServerMessage::NoSuchEntity
+                }
+            },
+
+            ClientMessage::Browse(id, after, count) => {
+                let queue = match queues.queue(id.as_ref()) {
+                    Some(queue) => queue,
+                    None => return Ok(Some(ServerMessage::NoSuchEntity))
+                };
+
+                let count = cmp::min(count as usize, MAX_BATCH);
+                let entries = match after {
+                    Some(after) => queue.browse(&after, count),
+                    None => Some(queue.peek(0, count))
+                };
+
+                match entries {
+                    Some(entries) => self.peek_response(id.as_ref(), entries),
+                    None => ServerMessage::CursorLost
+                }
+            },
+
+            ClientMessage::PeekChunk(id, uuid, offset) =>
+                self.peek_chunk(id.as_ref(), &uuid, offset),
+
+            ClientMessage::Purge(id) => {
+                queues.queue(id.as_ref()).map(|queue| ServerMessage::Purged(queue.purge() as u64))
+                    .unwrap_or(ServerMessage::NoSuchEntity)
+            },
+
+            ClientMessage::DeleteMessage(uuid) => delete_message(queues, &self.leases, &uuid),
+
+            ClientMessage::Move(from, to, count) => {
+                if from.as_ref() == to.as_ref() { return Ok(Some(ServerMessage::BadRequest)) }
+
+                let response = queues.move_entries(from.as_ref(), to.as_ref(), count as usize)
+                    .map(|moved| ServerMessage::Moved(moved as u64))
+                    .unwrap_or_else(ServerMessage::NoSuchQueue);
+
+                // Expired objects skipped by the move go to the dead-letter queue.
+                if let Some(queue) = queues.queue(from.as_ref()) { dead_letter(queues, &queue) }
+                response
+            },
+
+            ClientMessage::GetQueueConfig(id) => {
+                queues.queue(id.as_ref()).map(|queue| ServerMessage::QueueConfig(queue.options()))
+                    .unwrap_or(ServerMessage::NoSuchEntity)
+            },
+
+            ClientMessage::AlterQueue(id, options) => {
+                queues.queue(id.as_ref()).map(|queue| {
+                    queue.alter(options);
+                    ServerMessage::QueueAltered
+                }).unwrap_or(ServerMessage::NoSuchEntity)
+            },
+
+            ClientMessage::MemoryStats => {
+                let mut stats = self.memory.stats();
+                stats.budget = self.limits.max_memory;
+                ServerMessage::MemoryStats(stats)
+            }
+        }))
     }
 
     /// Handle a writable event on this connection.
@@ -134,46 +682,372 @@ impl<Q: Queue> Connection<Q> {
                     self.outgoing.push_front(top);
                     break
                 },
-                Ok(_) => continue,
+                Ok(_) => self.memory.sent(top.into_inner().len())
+            }
+        }
+    }
+
+    /// Queue an encoded response to be written to the client.
+    fn push(&mut self, response: Vec<u8>) {
+        self.memory.send(response.len());
+        self.outgoing.push_back(Cursor::new(response));
+    }
+
+    /// Identifies this connection, as the holder of the objects it reads.
+    fn holder(&self, token: Token) -> Holder {
+        Holder { event_loop: self.event_loop, token: token }
+    }
+
+    /// Handle the timeout of a Read on this connection elapsing.
+    ///
+    /// If the object has not been confirmed yet, it is requeued.
+    pub fn expire(&mut self, uuid: &Uuid, lease: u64) {
+        self.leased.remove(uuid);
+        self.leases.release(uuid, lease, &*self.resolve);
+        self.settle(uuid);
+    }
+
+    /// Forget an object read on this connection whose lease was ended by
+    /// another connection.
+    pub fn forget<Qu>(&mut self, evloop: &mut EventLoop<Handler<Qu>>, uuid: &Uuid, lease: u64)
+    where Qu: Queues<Queue=Q> + Send {
+        // The object may have been read again on this connection since.
+        if self.leased.get(uuid).map(|&(current, _)| current == lease) != Some(true) { return }
+
+        if let Some((_, Some(timeout))) = self.leased.remove(uuid) {
+            evloop.clear_timeout(timeout);
+        }
+
+        self.settle(uuid);
+    }
+
+    /// Clear the timeouts of all pending Reads.
+    ///
+    /// Called when the connection is being disconnected, so the timeouts
+    /// do not fire after the connection is gone.
+    pub fn clear_timeouts<Qu>(&self, evloop: &mut EventLoop<Handler<Qu>>)
+    where Qu: Queues<Queue=Q> + Send {
+        for &(_, timeout) in self.leased.values() {
+            if let Some(timeout) = timeout {
+                evloop.clear_timeout(timeout);
+            }
+        }
+
+        for parked in self.parked.values() {
+            evloop.clear_timeout(parked.wait);
+        }
+    }
+
+    /// The name of the queue and the id of the request of every ReadWait
+    /// and subscription on this connection which is waiting for an object.
+    pub fn parked(&self) -> Vec<(String, RequestId)> {
+        let reads = self.parked.iter().map(|(&request, parked)| (parked.queue.clone(), request));
+        let subscriptions = self.subscriptions.iter()
+            .filter(|&(_, subscription)| subscription.waiting)
+            .map(|(name, subscription)| (name.clone(), subscription.request));
+
+        reads.chain(subscriptions).collect()
+    }
+
+    /// Whether a ReadWait or subscription on this connection is waiting with
+    /// the id of this request, so no other one can wait with it.
+    fn waits_with(&self, request: RequestId) -> bool {
+        self.parked.contains_key(&request) ||
+            self.subscriptions.values().any(|subscription| subscription.request == request)
+    }
+
+    /// Try a waiting ReadWait or subscription again, after it was woken by
+    /// an object being enqueued on the named queue, sending the response if
+    /// there is one now.
+    pub fn retry<Qu>(&mut self, token: Token, name: &str, request: RequestId, queues: &Qu,
+                     evloop: &mut EventLoop<Handler<Qu>>) -> Result<Retry, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        // A subscription may be waiting with the same id on another queue.
+        let timeout = self.parked.get(&request)
+            .and_then(|parked| if parked.queue == name { Some(parked.timeout) } else { None });
+
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.retry_subscription(token, name, request, queues, evloop)
+        };
+
+        let response = try!(self.read_ms(token, evloop, queues, name, timeout));
+        if response == ServerMessage::Empty { return Ok(Retry::Empty) }
+
+        let parked = self.parked.remove(&request).unwrap();
+        evloop.clear_timeout(parked.wait);
+
+        self.push(try!(response.encode(request)));
+        Ok(Retry::Done)
+    }
+
+    /// Try pushing an object to a waiting subscription again, after an
+    /// object was enqueued on its queue.
+    fn retry_subscription<Qu>(&mut self, token: Token, name: &str, request: RequestId,
+                              queues: &Qu,
+                              evloop: &mut EventLoop<Handler<Qu>>) -> Result<Retry, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        // The subscription may have been replaced by one which is not waiting.
+        let state = self.subscriptions.get(name)
+            .map(|subscription| (subscription.request == request, subscription.open()));
+
+        match state {
+            Some((true, true)) => {},
+            Some((true, false)) => {
+                self.subscriptions.get_mut(name).unwrap().waiting = false;
+                return Ok(Retry::Unused)
+            },
+            _ => return Ok(Retry::Unused)
+        }
+
+        // Only one object at a time, so other Reads get their turn.
+        if !try!(self.fill(token, evloop, queues, name, 1)) { return Ok(Retry::Empty) }
+
+        match self.subscriptions.get_mut(name) {
+            Some(ref subscription) if subscription.open() => Ok(Retry::More),
+            Some(subscription) => {
+                subscription.waiting = false;
+                Ok(Retry::Done)
+            },
+            // The queue was deleted.
+            None => Ok(Retry::Done)
+        }
+    }
+
+    /// Push objects to every subscription with room for more, and wait for
+    /// objects to be enqueued on the queues of those which run out.
+    ///
+    /// Subscriptions wait behind any Reads already waiting on their queue
+    /// instead of taking objects ahead of them.
+    pub fn pump<Qu>(&mut self, token: Token, queues: &Qu, waiting: &Waiting,
+                    evloop: &mut EventLoop<Handler<Qu>>) -> Result<(), Error>
+    where Qu: Queues<Queue=Q> + Send {
+        let open = self.subscriptions.iter()
+            .filter(|&(_, subscription)| !subscription.waiting && subscription.open())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+
+        for name in open {
+            let signal = wait::note(queues, &name);
+            if !wait::is_waiting(&signal) {
+                let prefetch = self.subscriptions[&name].prefetch;
+                if try!(self.fill(token, evloop, queues, &name, prefetch)) { continue }
+            }
+
+            let request = {
+                let subscription = self.subscriptions.get_mut(&name).unwrap();
+                subscription.waiting = true;
+                subscription.request
+            };
+
+            waiting.park(name, token, request, signal, evloop.channel());
+        }
+
+        Ok(())
+    }
+
+    /// Push up to `max` objects from the named queue to its subscription,
+    /// stopping early once it has `prefetch` objects outstanding.
+    ///
+    /// Returns false if the queue ran out of objects first.
+    fn fill<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                queues: &Qu, name: &str, max: u32) -> Result<bool, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        for _ in 0..max {
+            let (request, timeout) = match self.subscriptions.get(name) {
+                Some(subscription) if subscription.open() =>
+                    (subscription.request, subscription.timeout),
+
+                // Full, or unsubscribed.
+                _ => return Ok(true)
+            };
+
+            let response = try!(self.read_ms(token, evloop, queues, name, timeout));
+            let uuid = match response {
+                ServerMessage::Read(ref uuid, _, _) | ServerMessage::ReadChunked(ref uuid, _, _) =>
+                    uuid.clone(),
+                ServerMessage::Empty => return Ok(false),
+
+                // The queue was deleted, which ends the subscription.
+                _ => {
+                    self.subscriptions.remove(name);
+                    self.push(try!(response.encode_push(request)));
+                    return Ok(true)
+                }
+            };
+
+            self.subscriptions.get_mut(name).unwrap().outstanding.insert(uuid);
+            self.push(try!(response.encode_push(request)));
+        }
+
+        Ok(true)
+    }
+
+    /// The names of the queues holding back objects sent on this connection,
+    /// which the connection is waiting for room in.
+    pub fn held(&self) -> Vec<String> {
+        let mut names = self.blocked.iter().map(|blocked| blocked.queue.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Try again to enqueue the objects held back by the named queue, after
+    /// it was woken by the room signal of the queue, sending the responses
+    /// for those which fit now, and for the requests whose last held back
+    /// object fits now.
+    pub fn unblock<Qu>(&mut self, name: &str, queues: &Qu) -> Result<Retry, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        if !self.blocked.iter().any(|blocked| blocked.queue == name) { return Ok(Retry::Unused) }
+
+        let queue = queues.queue(name);
+        let mut queued = 0;
+        let mut full_again = false;
+
+        let blocked = mem::replace(&mut self.blocked, VecDeque::new());
+        for Blocked { request, queue: held, entry, part } in blocked {
+            // Objects stay behind any sent to the same queue before them.
+            if held != name || full_again {
+                self.blocked.push_back(Blocked { request: request, queue: held, entry: entry,
+                                                 part: part });
+                continue
+            }
+
+            let uuid = entry.id.clone();
+            let bytes = entry.data.len();
+
+            // The rest of a batch is dropped once part of it was rejected.
+            if part && !self.pending.contains_key(&request) {
+                self.memory.unhold(bytes);
+                continue
             }
+
+            let response = match queue {
+                Some(ref queue) => match queue.offer(entry) {
+                    Ok(_) => {
+                        queued += 1;
+                        if part { self.settle_part(request, held, Some(uuid)) } else {
+                            Some(ServerMessage::ObjectQueued(uuid))
+                        }
+                    },
+
+                    // The queue may have been altered to stop blocking.
+                    Err(entry) => if queue.options().overflow == Overflow::Block {
+                        full_again = true;
+                        self.blocked.push_back(Blocked { request: request, queue: held,
+                                                         entry: entry, part: part });
+                        continue
+                    } else if part {
+                        self.settle_part(request, held, None)
+                    } else {
+                        Some(full(entry))
+                    }
+                },
+                None => if part { self.settle_part(request, held, None) } else {
+                    Some(ServerMessage::NoSuchEntity)
+                }
+            };
+
+            self.memory.unhold(bytes);
+            if let Some(response) = response { self.push(try!(response.encode(request))) }
         }
+
+        Ok(match (full_again, queued) {
+            (false, _) => Retry::Done,
+            (true, 0) => Retry::Empty,
+            (true, _) => Retry::More
+        })
     }
 
-    /// Handle a read request from a client, including setting up our timeout
-    /// confirm and cancellation futures for handling Confirm requests.
-    fn read_ms<Qu>(&mut self, evloop: &mut EventLoop<Handler<Qu>>,
-                  queues: &Qu, id: &str, timeout: u64) -> Result<ServerMessage, Error>
+    /// Settle one of the objects held back for a request which sent several,
+    /// with its id if it was enqueued, or None if the named queue rejected
+    /// it, producing the response once every one of them is settled.
+    ///
+    /// A batch is answered as soon as one of its objects is rejected, since
+    /// every object after it is then dropped.
+    fn settle_part(&mut self, request: RequestId, name: String,
+                   uuid: Option<Uuid>) -> Option<ServerMessage<'static>> {
+        let done = match self.pending.get_mut(&request) {
+            Some(&mut Pending::Batch(_, ref mut left)) => {
+                if uuid.is_some() { *left -= 1 }
+                uuid.is_none() || *left == 0
+            },
+            Some(&mut Pending::Publish(ref mut queued, ref mut full, ref mut left)) => {
+                match uuid {
+                    Some(uuid) => queued.push(uuid),
+                    None => full.push(name)
+                }
+                *left -= 1;
+                *left == 0
+            },
+            None => false
+        };
+        if !done { return None }
+
+        match self.pending.remove(&request) {
+            Some(Pending::Batch(uuids, left)) => {
+                let queued = uuids.len() - left;
+                Some(ServerMessage::ObjectsQueued(uuids.into_iter().take(queued).collect()))
+            },
+            Some(Pending::Publish(queued, full, _)) => Some(ServerMessage::Published(queued, full)),
+            None => None
+        }
+    }
+
+    /// Make room for another object to be pushed to the subscription an
+    /// object was pushed to, if it was.
+    fn settle(&mut self, uuid: &Uuid) {
+        for subscription in self.subscriptions.values_mut() {
+            subscription.outstanding.remove(uuid);
+        }
+    }
+
+    /// Answer a ReadWait whose wait elapsed with Empty, returning the name
+    /// of its queue if it was still waiting.
+    pub fn give_up(&mut self, request: RequestId) -> Option<String> {
+        let parked = match self.parked.remove(&request) {
+            Some(parked) => parked,
+            None => return None
+        };
+
+        match ServerMessage::Empty.encode(request) {
+            Ok(response) => self.push(response),
+            Err(e) => error!("Could not encode the response to a ReadWait: {:?}", e)
+        }
+
+        Some(parked.queue)
+    }
+
+    /// Start waiting for an object for a ReadWait which found its queue
+    /// empty, for at most `wait` milliseconds.
+    fn park<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>, name: String,
+                request: RequestId, timeout: u64, wait: u64) -> Result<(), Error>
+    where Qu: Queues<Queue=Q> + Send {
+        let wait = try!(evloop.timeout_ms(Timeout::Wait(token, request), wait));
+        self.parked.insert(request, Parked { queue: name, timeout: timeout, wait: wait });
+        Ok(())
+    }
+
+    /// Handle a read request from a client, including setting up the timeout
+    /// after which the object is requeued if it was not confirmed.
+    fn read_ms<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                  queues: &Qu, id: &str,
+                  timeout: u64) -> Result<ServerMessage<'static>, Error>
     where Qu: Queues<Queue=Q> + Send {
         if let Some(queue) = queues.queue(&id) {
             let top = queue.dequeue();
-            if let Some((uuid, object)) = top {
-                let (timeout_tx, timeout_rx) = Future::pair();
-                let (confirm_tx, confirm_rx) = Future::pair();
-                let (cancellation_tx, cancellation_rx) = Future::pair();
-
-                try!(evloop.timeout_ms(timeout_tx, timeout));
-
-                let (cuuid, cobject) = (uuid.clone(), object.clone());
-                eventual::select((timeout_rx, confirm_rx))
-                    .map(move |(choice, _)| {
-                        match choice {
-                            // Timeout expired first.
-                            0 => match queue.requeue(cuuid, cobject) {
-                                Ok(()) => {},
-                                Err((id, data)) => {
-                                    cancellation_tx.fail((queue, id, data))
-                                }
-                            },
-                            // Confirm received first.
-                            1 => cancellation_tx.complete(()),
-                            x => panic!("Received impossible hint {:?} from select", x)
-                        }
-                    }).fire();
+            dead_letter(queues, &queue);
 
-                self.unconfirmed.insert(uuid.clone(),
-                                        (confirm_tx, cancellation_rx));
+            if let Some(entry) = top {
+                // Large objects are fetched in chunks using ReadChunk.
+                let (uuid, len, deliveries, object) =
+                    try!(self.lease(token, evloop, queue, entry, timeout, CHUNK_LEN));
 
-                Ok(ServerMessage::Read(uuid, SliceBox::boxed(object)))
+                Ok(match object {
+                    Some(object) => ServerMessage::Read(uuid, SliceBox::boxed(object), deliveries),
+                    None => ServerMessage::ReadChunked(uuid, len, deliveries)
+                })
             } else {
                 Ok(ServerMessage::Empty)
             }
@@ -182,33 +1056,663 @@ impl<Q: Queue> Connection<Q> {
         }
     }
 
-    /// Handle a Confirm request, using the unconfirmed map.
-    fn confirm(&mut self, uuid: &Uuid) -> ServerMessage {
-        self.unconfirmed.remove(uuid)
-            .map(|(confirm_tx, cancellation_rx)| {
-                match cancellation_rx.poll() {
-                    // The timeout has elapsed and data succesfully
-                    // requeued.
-                    Ok(Ok(())) => ServerMessage::Requeued,
-                    Ok(Err(AsyncError::Aborted)) => ServerMessage::Requeued,
-
-                    // The timeout has elapsed, but the data was not
-                    // succesfully requeued.
-                    Ok(Err(AsyncError::Failed((queue, id, data)))) => {
-                        // Try to queue again now.
-                        match queue.requeue(id, data) {
-                            Ok(()) => ServerMessage::Requeued,
-                            Err((id, data)) => {
-                                ServerMessage::Full(id, SliceBox::boxed(data))
-                            }
+    /// Handle a ReadBatch request, leasing every object read as for Read.
+    fn read_batch<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                      queues: &Qu, id: &str, max: u32,
+                      timeout: u64) -> Result<ServerMessage<'static>, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        let queue = match queues.queue(&id) {
+            Some(queue) => queue,
+            None => return Ok(ServerMessage::NoSuchEntity)
+        };
+
+        let mut entries = queue.dequeue_batch(cmp::min(max as usize, MAX_BATCH)).into_iter();
+        dead_letter(queues, &queue);
+
+        // Objects are sent in the response while they fit, and the rest are
+        // fetched using ReadChunk.
+        let mut budget = (MAX_SERVER_MESSAGE_LEN as usize)
+            .saturating_sub(BATCHED_OBJECT_OVERHEAD * (entries.len() + 1));
+        let mut objects = Vec::with_capacity(entries.len());
+
+        while let Some(entry) = entries.next() {
+            let inline = cmp::min(budget, CHUNK_LEN);
+            match self.lease(token, evloop, queue.clone(), entry, timeout, inline) {
+                Ok((uuid, len, deliveries, data)) => {
+                    if data.is_some() { budget -= len as usize; }
+                    objects.push(BatchedObject {
+                        id: uuid,
+                        data: data,
+                        len: len,
+                        deliveries: deliveries
+                    });
+                },
+                Err(e) => {
+                    // Put back the objects we have not leased yet, in order.
+                    for entry in entries.rev() {
+                        if let Err(entry) = queue.requeue(entry) {
+                            error!("Queue full, lost object {} from a failed batch read.",
+                                   entry.id);
                         }
-                    },
-                    Err(_) => {
-                        confirm_tx.complete(());
-                        ServerMessage::Confirmed
                     }
+
+                    return Err(e)
+                }
+            }
+        }
+
+        Ok(ServerMessage::ReadBatch(objects))
+    }
+
+    /// Lease a dequeued object to this connection, setting up the timeout
+    /// after which it is requeued if it was not confirmed.
+    ///
+    /// Returns the id, length and number of deliveries of the object, along
+    /// with a copy of its data if it is no longer than `inline` bytes.
+    fn lease<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                 queue: Q, mut entry: Entry, timeout: u64,
+                 inline: usize) -> Result<(Uuid, u64, u32, Option<Vec<u8>>), Error>
+    where Qu: Queues<Queue=Q> + Send {
+        entry.deliveries += 1;
+
+        let uuid = entry.id.clone();
+        let deliveries = entry.deliveries;
+        let len = entry.data.len();
+        let object = if len > inline { None } else { Some(entry.data.clone()) };
+
+        let (timeout, expiry) = {
+            let options = queue.options();
+
+            // A timeout of 0 uses the lease timeout of the queue.
+            let timeout = if timeout == 0 { options.lease_timeout } else { timeout };
+            (timeout, expiry(options, deliveries))
+        };
+
+        let lease = self.leases.lease(queue, entry, expiry, self.holder(token), evloop.channel());
+
+        // If that is also 0 there is no timeout, and the object is only
+        // requeued if this connection is closed.
+        let timeout = if timeout == 0 {
+            None
+        } else {
+            match evloop.timeout_ms(Timeout::Lease(token, uuid.clone(), lease), timeout) {
+                Ok(timeout) => Some(timeout),
+                Err(e) => {
+                    // Don't lose the object just because we couldn't
+                    // set a timer for it.
+                    self.leases.release(&uuid, lease, &*self.resolve);
+                    return Err(Error::from(e))
                 }
-            }).unwrap_or(ServerMessage::NoSuchEntity)
+            }
+        };
+
+        self.leased.insert(uuid.clone(), (lease, timeout));
+        Ok((uuid, len as u64, deliveries, object))
+    }
+
+    /// Enqueue a new object sent by the client on the named queue, producing
+    /// the response.
+    ///
+    /// If the queue is full and blocks producers, the object is held back
+    /// until it fits, and None is returned. Objects sent to the queue after
+    /// it wait behind it, so they are still enqueued in the order they were
+    /// sent. The first object held back by a queue starts waiting on its
+    /// room signal.
+    ///
+    /// Objects which would take the server or the queue over its memory
+    /// budget are refused, rather than held back. Objects held back are held
+    /// in the memory of the server until they are enqueued or dropped.
+    fn enqueue<Qu>(&mut self, token: Token, waiting: &Waiting,
+                   evloop: &mut EventLoop<Handler<Qu>>, request: RequestId, name: String,
+                   queue: &Q, entry: Entry) -> Result<Option<ServerMessage<'static>>, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        let bytes = entry.data.len() as u64;
+        if !reserve(&self.limits, &self.memory, queue, bytes, bytes) {
+            return Ok(Some(ServerMessage::OverBudget))
+        }
+
+        let options = queue.options();
+        let blocks = options.overflow == Overflow::Block && queue.room().is_some();
+        let behind = blocks && self.blocked.iter().any(|blocked| blocked.queue == name);
+
+        // Noted before trying the queue, so no room made after it was found
+        // full can be missed.
+        let room = queue.room().map(|room| (room.clone(), room.generation()));
+
+        let entry = if behind {
+            entry
+        } else {
+            let uuid = entry.id.clone();
+            match queue.offer(entry) {
+                Ok(_) => {
+                    self.memory.unhold(bytes as usize);
+                    return Ok(Some(ServerMessage::ObjectQueued(uuid)))
+                },
+                Err(entry) => entry
+            }
+        };
+
+        // Objects which could never fit are rejected straight away.
+        let fits = options.max_bytes == 0 || bytes <= options.max_bytes;
+        if !blocks || !fits || self.blocked.len() >= MAX_BLOCKED {
+            self.memory.unhold(bytes as usize);
+            return Ok(Some(full(entry)))
+        }
+
+        self.hold_back(token, waiting, evloop, request, name, room, behind, vec![entry], false);
+        Ok(None)
+    }
+
+    /// Handle an EnqueueBatch request, producing the response, or None if
+    /// part of the batch is held back.
+    ///
+    /// If the queue is full and blocks producers, the objects which did not
+    /// fit are held back as for `enqueue`, and the ids of the whole batch
+    /// are sent once the last of them is enqueued. If any of them is
+    /// rejected instead, the ids of the objects before it are sent, and the
+    /// rest of the batch is dropped.
+    fn enqueue_batch<Qu>(&mut self, token: Token, waiting: &Waiting,
+                         evloop: &mut EventLoop<Handler<Qu>>, request: RequestId, name: String,
+                         queue: &Q,
+                         entries: Vec<Entry>) -> Result<Option<ServerMessage<'static>>, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        let bytes = entries.iter().fold(0, |bytes, entry| bytes + entry.data.len() as u64);
+        if !reserve(&self.limits, &self.memory, queue, bytes, bytes) {
+            return Ok(Some(ServerMessage::OverBudget))
+        }
+
+        let uuids = entries.iter().map(|entry| entry.id.clone()).collect::<Vec<_>>();
+        let options = queue.options();
+        let blocks = options.overflow == Overflow::Block && queue.room().is_some();
+        let behind = blocks && self.blocked.iter().any(|blocked| blocked.queue == name);
+        let room = queue.room().map(|room| (room.clone(), room.generation()));
+
+        let rest = if behind {
+            entries
+        } else {
+            match queue::offer_batch(queue, entries) {
+                Ok(()) => Vec::new(),
+                Err(rest) => rest
+            }
+        };
+
+        // Batches holding an object which could never fit are not held back.
+        let held = rest.iter().fold(0, |held, entry| held + entry.data.len() as u64);
+        let fits = rest.iter().all(|entry| {
+            options.max_bytes == 0 || entry.data.len() as u64 <= options.max_bytes
+        });
+        if rest.is_empty() || !blocks || !fits || self.blocked.len() + rest.len() > MAX_BLOCKED {
+            self.memory.unhold(bytes as usize);
+            let queued = uuids.len() - rest.len();
+            return Ok(Some(ServerMessage::ObjectsQueued(uuids.into_iter().take(queued).collect())))
+        }
+
+        self.memory.unhold((bytes - held) as usize);
+        self.pending.insert(request, Pending::Batch(uuids, rest.len()));
+        self.hold_back(token, waiting, evloop, request, name, room, behind, rest, true);
+        Ok(None)
+    }
+
+    /// Handle a Publish request, by enqueueing a copy of the object on each
+    /// of the queues its routing key matched, producing the response, or
+    /// None if any of the copies are held back.
+    ///
+    /// Copies for full queues which block producers are held back as for
+    /// `enqueue`, and the response is sent once every one of them is
+    /// settled. If any of the copies would go over a memory budget, none are
+    /// enqueued.
+    fn publish<Qu>(&mut self, token: Token, waiting: &Waiting,
+                   evloop: &mut EventLoop<Handler<Qu>>, request: RequestId, queues: &Qu,
+                   bound: Vec<String>,
+                   data: Vec<u8>) -> Result<Option<ServerMessage<'static>>, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        // Queues are unbound when they are deleted, so this only misses queues
+        // deleted since the bindings were looked up.
+        let bound = bound.into_iter()
+            .filter_map(|name| queues.queue(&name).map(|queue| (name, queue)))
+            .collect::<Vec<_>>();
+
+        // The budget of every queue is checked before the copies are held.
+        let bytes = data.len() as u64;
+        let total = bytes * bound.len() as u64;
+        if !bound.iter().all(|&(_, ref queue)| fits_queue(&self.limits, queue, bytes)) ||
+            !self.memory.reserve(total as usize, self.limits.max_memory) {
+            return Ok(Some(ServerMessage::OverBudget))
+        }
+
+        let mut queued = Vec::with_capacity(bound.len());
+        let mut full = Vec::new();
+        let mut held = 0;
+
+        for (name, queue) in bound {
+            let entry = new_entry(&queue, data.clone(), 0, 0);
+            let uuid = entry.id.clone();
+            let options = queue.options();
+            let blocks = options.overflow == Overflow::Block && queue.room().is_some();
+            let behind = blocks && self.blocked.iter().any(|blocked| blocked.queue == name);
+            let room = queue.room().map(|room| (room.clone(), room.generation()));
+
+            let entry = if behind {
+                entry
+            } else {
+                match queue.offer(entry) {
+                    Ok(_) => {
+                        queued.push(uuid);
+                        continue
+                    },
+                    Err(entry) => entry
+                }
+            };
+
+            let fits = options.max_bytes == 0 || bytes <= options.max_bytes;
+            if !blocks || !fits || self.blocked.len() >= MAX_BLOCKED {
+                full.push(name);
+                continue
+            }
+
+            self.hold_back(token, waiting, evloop, request, name, room, behind, vec![entry], true);
+            held += 1;
+        }
+
+        self.memory.unhold((total - bytes * held) as usize);
+        if held == 0 { return Ok(Some(ServerMessage::Published(queued, full))) }
+
+        self.pending.insert(request, Pending::Publish(queued, full, held as usize));
+        Ok(None)
+    }
+
+    /// Hold back objects sent by a request until the named queue has room
+    /// for them, behind any it already holds back, starting to wait on the
+    /// room signal of the queue unless the connection already is.
+    ///
+    /// `room` should have been noted before the queue was found full.
+    fn hold_back<Qu>(&mut self, token: Token, waiting: &Waiting,
+                     evloop: &mut EventLoop<Handler<Qu>>, request: RequestId, name: String,
+                     room: Option<(Signal, u64)>, behind: bool, entries: Vec<Entry>, part: bool)
+    where Qu: Queues<Queue=Q> + Send {
+        if !behind { waiting.hold(name.clone(), token, room, evloop.channel()) }
+
+        for entry in entries {
+            self.blocked.push_back(Blocked { request: request, queue: name.clone(), entry: entry,
+                                             part: part });
+        }
+    }
+
+    /// Take the object uploaded by Chunk messages so far, which is no longer
+    /// held by the connection.
+    fn take_upload(&mut self) -> Vec<u8> {
+        let upload = mem::replace(&mut self.upload, Vec::new());
+        self.memory.unhold(upload.len());
+        upload
+    }
+
+    /// Respond to a Peek or Browse of the named queue, keeping copies of the
+    /// objects left out of the response until they are fetched by PeekChunk.
+    fn peek_response(&mut self, name: &str, entries: Vec<Entry>) -> ServerMessage<'static> {
+        let (response, large) = peeked(entries);
+        self.peeking = large.into_iter()
+            .map(|(uuid, data)| (uuid, (name.to_owned(), data)))
+            .collect();
+        response
+    }
+
+    /// Send a chunk of a large object from the last Peek or Browse response,
+    /// and forget the object once its last chunk is sent.
+    fn peek_chunk(&mut self, name: &str, uuid: &Uuid, offset: u64) -> ServerMessage<'static> {
+        let (chunk, last) = match self.peeking.get(uuid) {
+            Some(&(ref queue, ref data)) if queue == name => {
+                let start = cmp::min(offset, data.len() as u64) as usize;
+                let end = cmp::min(start + CHUNK_LEN, data.len());
+                (data[start..end].to_vec(), end == data.len())
+            },
+            _ => return ServerMessage::NoSuchEntity
+        };
+
+        if last { self.peeking.remove(uuid); }
+        ServerMessage::Chunk(SliceBox::boxed(chunk))
+    }
+
+    /// Handle an EnqueueDelayed request, by holding the object in a timer
+    /// until its delay elapses.
+    fn enqueue_delayed<Qu>(&mut self, evloop: &mut EventLoop<Handler<Qu>>, queues: &Qu,
+                           name: String, object: Vec<u8>,
+                           delay: u64) -> Result<ServerMessage<'static>, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        let queue = match queues.queue(&name) {
+            Some(queue) => queue,
+            None => return Ok(ServerMessage::NoSuchEntity)
+        };
+
+        // Delayed objects are held by the server until they are delivered.
+        let bytes = object.len() as u64;
+        if !reserve(&self.limits, &self.memory, &queue, bytes, bytes) {
+            return Ok(ServerMessage::OverBudget)
+        }
+
+        // The time-to-live starts once the object becomes available.
+        let entry = new_entry(&queue, object, 0, delay);
+        let uuid = entry.id.clone();
+        queue.delay(&entry);
+        if let Err(e) = evloop.timeout_ms(Timeout::Deliver(name, entry, 0), delay) {
+            queue.discard_delayed(&uuid);
+            self.memory.unhold(bytes as usize);
+            return Err(Error::from(e))
+        }
+        Ok(ServerMessage::ObjectQueued(uuid))
+    }
+
+    /// Handle a Confirm request, which may be for an object read on any
+    /// connection.
+    fn confirm<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                   uuid: &Uuid) -> ServerMessage<'static>
+    where Qu: Queues<Queue=Q> + Send {
+        self.settle(uuid);
+
+        match self.leases.confirm(uuid, self.holder(token)) {
+            Confirmation::Confirmed => {
+                if let Some((_, Some(timeout))) = self.leased.remove(uuid) {
+                    evloop.clear_timeout(timeout);
+                }
+
+                ServerMessage::Confirmed
+            },
+            Confirmation::Requeued => ServerMessage::Requeued,
+            Confirmation::Full(entry) => full(entry),
+            Confirmation::NoSuchEntity => ServerMessage::NoSuchEntity
+        }
+    }
+
+    /// Handle an ExtendLease request, which may be for an object read on any
+    /// connection, by replacing the timeout of its Read with a new one.
+    ///
+    /// The object stays leased to the connection which read it, so closing
+    /// this connection does not requeue it.
+    fn extend_lease<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                        uuid: &Uuid,
+                        timeout: u64) -> Result<ServerMessage<'static>, Error>
+    where Qu: Queues<Queue=Q> + Send {
+        match self.leases.renew(uuid, timeout, self.holder(token), &*self.resolve) {
+            Renewal::Renewed(lease) => {
+                try!(self.restart(token, evloop, uuid, lease, timeout));
+                Ok(ServerMessage::LeaseExtended)
+            },
+            Renewal::Forwarded => Ok(ServerMessage::LeaseExtended),
+            Renewal::NoSuchEntity => Ok(ServerMessage::NoSuchEntity)
+        }
+    }
+
+    /// Restart the timeout of a Read on this connection after its lease was
+    /// extended through another connection, returning false if the Read had
+    /// already ended.
+    pub fn renewed<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                       uuid: &Uuid, old: u64, lease: u64, timeout: u64) -> bool
+    where Qu: Queues<Queue=Q> + Send {
+        if self.leased.get(uuid).map(|&(current, _)| current == old) != Some(true) {
+            return false
+        }
+
+        if let Err(e) = self.restart(token, evloop, uuid, lease, timeout) {
+            error!("Could not extend the lease on object {}: {:?}", uuid, e);
+        }
+
+        true
+    }
+
+    /// Replace the timeout of the Read of a leased object with a timeout of
+    /// `timeout` milliseconds for its renewed lease.
+    fn restart<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                   uuid: &Uuid, lease: u64, timeout: u64) -> Result<(), Error>
+    where Qu: Queues<Queue=Q> + Send {
+        if let Some((_, Some(timeout))) = self.leased.remove(uuid) {
+            evloop.clear_timeout(timeout);
+        }
+
+        let timeout = if timeout == 0 {
+            None
+        } else {
+            match evloop.timeout_ms(Timeout::Lease(token, uuid.clone(), lease), timeout) {
+                Ok(timeout) => Some(timeout),
+                Err(e) => {
+                    self.leases.release(uuid, lease, &*self.resolve);
+                    return Err(Error::from(e))
+                }
+            }
+        };
+
+        self.leased.insert(uuid.clone(), (lease, timeout));
+        Ok(())
+    }
+
+    /// Handle a Nack request, which may be for an object read on any
+    /// connection, by requeueing the object now or after `delay` milliseconds.
+    fn nack<Qu>(&mut self, token: Token, evloop: &mut EventLoop<Handler<Qu>>,
+                uuid: &Uuid, delay: u64) -> ServerMessage<'static>
+    where Qu: Queues<Queue=Q> + Send {
+        let nack = self.leases.nack(uuid, delay != 0, self.holder(token), &*self.resolve);
+        self.settle(uuid);
+
+        // The timeout of the Read no longer applies.
+        if nack != Nack::NoSuchEntity {
+            if let Some((_, Some(timeout))) = self.leased.remove(uuid) {
+                evloop.clear_timeout(timeout);
+            }
+        }
+
+        match nack {
+            Nack::Requeued => ServerMessage::Nacked,
+
+            // The delay belongs to the event loop rather than this connection,
+            // so closing the connection does not cut it short.
+            Nack::Delayed(lease) => {
+                if let Err(e) = evloop.timeout_ms(Timeout::Requeue(uuid.clone(), lease), delay) {
+                    error!("Could not delay requeueing nacked object: {:?}", e);
+                    self.leases.release(uuid, lease, &*self.resolve);
+                }
+
+                ServerMessage::Nacked
+            },
+            Nack::Full(entry) => full(entry),
+            Nack::NoSuchEntity => ServerMessage::NoSuchEntity
+        }
     }
 }
 
+/// Create a new object for `queue`, which expires `ttl` milliseconds after
+/// it becomes available in `delay` milliseconds.
+///
+/// A `ttl` of 0 uses the default time-to-live of the queue.
+fn new_entry<Q: Queue>(queue: &Q, data: Vec<u8>, ttl: u64, delay: u64) -> Entry {
+    let ttl = if ttl == 0 { queue.options().ttl } else { ttl };
+
+    let mut entry = Entry::new(Uuid::new_v4(), data);
+    entry.enqueued += delay;
+    if ttl != 0 {
+        entry.expires = Some(queue::now_ms() + delay + ttl);
+    }
+
+    entry
+}
+
+/// Bind an existing queue to a topic, producing the response to the client.
+fn bind<Qu: Queues>(queues: &Qu, topic: String, queue: String,
+                    pattern: String) -> ServerMessage<'static> {
+    if queues.queue(&queue).is_none() { return ServerMessage::NoSuchEntity }
+
+    queues.bind(topic, queue, pattern);
+    ServerMessage::Bound
+}
+
+/// Hold `total` bytes of objects on their way into queues in `memory`, if
+/// they fit within the memory budget of the server and `bytes` of them fit
+/// within the memory budget of `queue`.
+///
+/// Returns false, holding nothing, if either budget would be exceeded.
+/// Otherwise the bytes are held until they are released with `unhold`, once
+/// the objects are queued or dropped.
+fn reserve<Q: Queue>(limits: &Limits, memory: &Memory, queue: &Q, bytes: u64,
+                     total: u64) -> bool {
+    fits_queue(limits, queue, bytes) && memory.reserve(total as usize, limits.max_memory)
+}
+
+/// Whether `bytes` more of objects fit within the memory budget of `queue`.
+fn fits_queue<Q: Queue>(limits: &Limits, queue: &Q, bytes: u64) -> bool {
+    limits.max_queue_memory == 0 || queue.bytes() + bytes <= limits.max_queue_memory
+}
+
+/// List the names of the queues after `after`, in order, as many as fit in
+/// a single message, producing the response to the client.
+fn list_queues<Qu: Queues>(queues: &Qu, after: Option<&str>) -> ServerMessage<'static> {
+    let mut names = queues.names();
+    names.sort();
+
+    let mut budget = (MAX_SERVER_MESSAGE_LEN as usize).saturating_sub(LIST_QUEUES_OVERHEAD);
+    let mut listed = Vec::new();
+    let mut more = false;
+
+    for name in names {
+        if after.map(|after| &*name <= after).unwrap_or(false) { continue }
+
+        // Names are encoded with their length.
+        let len = name.len() + 8;
+        if len > budget && !listed.is_empty() {
+            more = true;
+            break
+        }
+
+        budget = budget.saturating_sub(len);
+        listed.push(name);
+    }
+
+    ServerMessage::Queues(listed, more)
+}
+
+/// Describe objects which were peeked at, producing the response to the
+/// client along with the data of the objects left out of it.
+///
+/// Objects are sent in the response while they fit, and the rest are
+/// fetched using PeekChunk.
+fn peeked(entries: Vec<Entry>) -> (ServerMessage<'static>, Vec<(Uuid, Vec<u8>)>) {
+    let mut budget = (MAX_SERVER_MESSAGE_LEN as usize)
+        .saturating_sub(PEEKED_OBJECT_OVERHEAD * (entries.len() + 1));
+    let mut large = Vec::new();
+
+    let objects = entries.into_iter().map(|entry| {
+        let len = entry.data.len();
+        let data = if len > cmp::min(budget, CHUNK_LEN) {
+            large.push((entry.id.clone(), entry.data));
+            None
+        } else {
+            budget -= len;
+            Some(entry.data)
+        };
+
+        PeekedObject {
+            id: entry.id,
+            data: data,
+            len: len as u64,
+            deliveries: entry.deliveries,
+            priority: entry.priority,
+            expires: entry.expires,
+            enqueued: entry.enqueued
+        }
+    }).collect();
+
+    (ServerMessage::Peeked(objects), large)
+}
+
+/// Remove an object waiting to be read from whichever queue holds it,
+/// producing the response to the client.
+fn delete_message<Qu: Queues>(queues: &Qu, leases: &Leases<Qu::Queue>,
+                              uuid: &Uuid) -> ServerMessage<'static> {
+    if leases.is_leased(uuid) { return ServerMessage::Leased }
+
+    // Leased objects are not in any queue, so this can never remove one,
+    // even if it is read in the meantime.
+    for name in queues.names() {
+        let removed = queues.queue(&name).and_then(|queue| queue.remove(uuid));
+        if removed.is_some() { return ServerMessage::MessageDeleted }
+    }
+
+    ServerMessage::NoSuchEntity
+}
+
+/// Tell the client a queue was full, sending back the object if it fits in
+/// a single message.
+fn full(entry: Entry) -> ServerMessage<'static> {
+    let data = if entry.data.len() > CHUNK_LEN { Vec::new() } else { entry.data };
+    ServerMessage::Full(entry.id, SliceBox::boxed(data))
+}
+
+/// Move the objects a queue skipped because their time-to-live elapsed to
+/// its dead-letter queue, or drop them if it has none.
+fn dead_letter<Qu: Queues>(queues: &Qu, queue: &Qu::Queue) {
+    let expired = queue.expired();
+    if expired.is_empty() { return }
+
+    let name = match queue.options().dead_letter {
+        Some(name) => name,
+        None => return
+    };
+
+    let dead_letter = match queues.queue(&name) {
+        Some(dead_letter) => dead_letter,
+        None => {
+            warn!("Dead-letter queue {} does not exist, discarding {} expired objects.",
+                  name, expired.len());
+            return
+        }
+    };
+
+    for mut entry in expired {
+        // It would otherwise expire again straight away.
+        entry.expires = None;
+        if let Err(entry) = dead_letter.enqueue(entry) {
+            warn!("Dead-letter queue {} full, discarding expired object {}.", name, entry.id);
+        }
+    }
+}
+
+/// Decide what happens to an object read from a queue with these options
+/// for the `deliveries`th time if it is not confirmed.
+///
+/// The dead-letter queue is only looked up once the lease ends, since it may
+/// be deleted or created in the meantime.
+fn expiry(options: QueueOptions, deliveries: u32) -> Expiry {
+    if options.max_deliveries == 0 || deliveries < options.max_deliveries {
+        return Expiry::Requeue
+    }
+
+    match options.dead_letter {
+        Some(name) => Expiry::DeadLetter(name),
+        None => Expiry::Discard
+    }
+}
+
+impl<Q: Queue> Drop for Connection<Q> {
+    /// Requeue every object read on this connection which was not confirmed,
+    /// since no Confirm for it can arrive once the connection is gone.
+    fn drop(&mut self) {
+        let mut leased = mem::replace(&mut self.leased, HashMap::new())
+            .into_iter().collect::<Vec<_>>();
+
+        // Requeue the most recently read objects first, so the objects end up
+        // at the front of their queues in the order they were read.
+        leased.sort_by(|&(_, (a, _)), &(_, (b, _))| b.cmp(&a));
+
+        for (uuid, (lease, _)) in leased {
+            self.leases.release(&uuid, lease, &*self.resolve);
+        }
+
+        // Responses which were never written no longer take up memory, and
+        // neither do the objects still held by the connection.
+        let unsent = self.outgoing.iter().fold(0, |unsent, response| {
+            unsent + response.get_ref().len()
+        });
+        self.memory.sent(unsent);
+
+        let held = self.blocked.iter().fold(self.upload.len(), |held, blocked| {
+            held + blocked.entry.data.len()
+        });
+        self.memory.unhold(held);
+    }
+}
@@ -1,14 +1,24 @@
-use common::EncodingError;
+use common::{EncodingError, DecodingError, ProtocolError};
 use mio::TimerError;
 use std::io;
+use std::path::PathBuf;
 
 /// Errors which can occur on the server.
 #[derive(Debug)]
 pub enum Error {
     Notify,
     OverLongMessage,
+    Disconnected,
     Timer(TimerError),
     Encoding(EncodingError),
+    Decoding(DecodingError),
+    Full(String),
+    Corrupt(PathBuf),
+
+    /// A log segment or snapshot does not start with the header of the format
+    /// this server writes, or was written in the given other version of it.
+    UnknownFormat(PathBuf, Option<u32>),
+    Protocol(ProtocolError),
     Io(io::Error)
 }
 
@@ -23,7 +33,14 @@ impl From<TimerError> for Error {
     fn from(err: TimerError) -> Error { Error::Timer(err) }
 }
 
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Error { Error::Protocol(err) }
+}
+
 impl From<EncodingError> for Error {
     fn from(err: EncodingError) -> Error { Error::Encoding(err) }
 }
 
+impl From<DecodingError> for Error {
+    fn from(err: DecodingError) -> Error { Error::Decoding(err) }
+}
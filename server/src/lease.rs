@@ -0,0 +1,471 @@
+use mio::{self, Token};
+use uuid::Uuid;
+
+use queue::{Queue, Entry};
+use rt::Message;
+
+use std::collections::{HashMap, VecDeque};
+use std::cmp;
+use std::sync::{Arc, Mutex};
+
+/// A registry of objects which have been read but not yet confirmed,
+/// keyed by the id of the object.
+///
+/// Leases are shared by every connection to a Server, and by every Server
+/// using the same set of queues if the queues share them, so an object can
+/// be confirmed from any of those connections, not only the one that read
+/// it.
+pub struct Leases<Q: Queue>(Arc<Mutex<Table<Q>>>);
+
+/// The most objects whose lease ended to remember, so that confirming them
+/// late is answered with Requeued rather than NoSuchEntity.
+const MAX_REQUEUED: usize = 64 * 1024;
+
+/// Identifies the connection holding a lease, by the id of its event loop
+/// and its token.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Holder {
+    pub event_loop: usize,
+    pub token: Token
+}
+
+struct Table<Q: Queue> {
+    /// Objects which are currently leased.
+    active: HashMap<Uuid, Lease<Q>>,
+
+    /// Objects whose lease ended before they were confirmed.
+    ///
+    /// If the object could not be requeued because the queue was full, the
+    /// value contains the queue and the object, so we can try again when the
+    /// Confirm message arrives. The object stays held in the memory of the
+    /// queue until then.
+    ///
+    /// Entries are removed when the object is confirmed or read again, and
+    /// objects which were requeued are forgotten once more than
+    /// MAX_REQUEUED are remembered.
+    requeued: HashMap<Uuid, Option<(Q, Entry)>>,
+
+    /// The objects which were requeued, in the order they were remembered.
+    markers: VecDeque<Uuid>,
+
+    /// The id of the next lease.
+    next: u64
+}
+
+/// A read object, held until it is Confirmed or requeued.
+struct Lease<Q: Queue> {
+    /// Identifies this lease, as opposed to other leases of the same object
+    /// after it was requeued and read again.
+    id: u64,
+
+    /// The queue the object was read from.
+    queue: Q,
+
+    /// A copy of the object, to requeue.
+    entry: Entry,
+
+    /// What to do with the object if the lease ends without a Confirm.
+    expiry: Expiry,
+
+    /// Whether the object was given back with a delay, and is waiting
+    /// for this lease to be released to be requeued.
+    nacked: bool,
+
+    /// The connection which read the object, or which gave it back with a
+    /// delay if it was nacked.
+    holder: Holder,
+
+    /// The event loop of that connection, which is told when the lease is
+    /// ended by another connection.
+    notify: mio::Sender<Message>
+}
+
+/// What happens to an object when its lease ends without a Confirm.
+pub enum Expiry {
+    /// Requeue it on the queue it was read from.
+    Requeue,
+
+    /// It has been delivered as many times as its queue allows, so move it
+    /// to the dead-letter queue with this name, or requeue it if that queue
+    /// no longer exists.
+    DeadLetter(String),
+
+    /// It has been delivered as many times as its queue allows, and the
+    /// queue has no dead-letter queue, so discard it.
+    Discard
+}
+
+/// The outcome of giving back an object without confirming it.
+#[derive(Debug, PartialEq)]
+pub enum Nack {
+    /// The object was requeued, or moved to its dead-letter queue.
+    Requeued,
+
+    /// The object was given a new lease with this id, and will be requeued
+    /// when that lease is released.
+    Delayed(u64),
+
+    /// The object could not be requeued because the queue was full.
+    Full(Entry),
+
+    /// No object with this id is leased.
+    NoSuchEntity
+}
+
+/// The outcome of extending the lease on an object.
+#[derive(Debug, PartialEq)]
+pub enum Renewal {
+    /// The lease is held by the connection which extended it, and was
+    /// replaced by the lease with this id.
+    Renewed(u64),
+
+    /// The lease is held by another connection, which was told to restart
+    /// the timeout of its Read.
+    Forwarded,
+
+    /// No object with this id is leased, or it was nacked.
+    NoSuchEntity
+}
+
+/// The outcome of confirming an object.
+#[derive(Debug, PartialEq)]
+pub enum Confirmation {
+    /// The lease was still valid, and the object will never be requeued.
+    Confirmed,
+
+    /// The lease had already ended, and the object was requeued.
+    Requeued,
+
+    /// The lease had already ended, but the object could not be requeued
+    /// because the queue was full.
+    Full(Entry),
+
+    /// No object with this id was read.
+    NoSuchEntity
+}
+
+impl<Q: Queue> Leases<Q> {
+    /// Create a new, empty, registry.
+    pub fn new() -> Leases<Q> {
+        Leases(Arc::new(Mutex::new(Table {
+            active: HashMap::new(),
+            requeued: HashMap::new(),
+            markers: VecDeque::new(),
+            next: 0
+        })))
+    }
+
+    /// Start a lease on an object just read from `queue` by the connection
+    /// `holder`, returning the id of the new lease.
+    pub fn lease(&self, queue: Q, entry: Entry, expiry: Expiry, holder: Holder,
+                 notify: mio::Sender<Message>) -> u64 {
+        let mut guard = self.0.lock().unwrap();
+        let table = &mut *guard;
+
+        let id = table.next;
+        table.next += 1;
+
+        if let Some(counters) = queue.counters() { counters.lease() }
+        queue.memory().lease(entry.data.len());
+        table.requeued.remove(&entry.id);
+        table.active.insert(entry.id.clone(), Lease {
+            id: id,
+            queue: queue,
+            entry: entry,
+            expiry: expiry,
+            nacked: false,
+            holder: holder,
+            notify: notify
+        });
+        id
+    }
+
+    /// Confirm an object through the connection `by`, ending its lease for
+    /// good.
+    pub fn confirm(&self, uuid: &Uuid, by: Holder) -> Confirmation {
+        let (lease, requeued) = {
+            let mut guard = self.0.lock().unwrap();
+            let table = &mut *guard;
+
+            // The object was given back, and will be requeued soon.
+            if table.active.get(uuid).map(|active| active.nacked) == Some(true) {
+                return Confirmation::Requeued
+            }
+
+            match table.active.remove(uuid) {
+                Some(lease) => (Some(lease), None),
+                None => (None, table.requeued.remove(uuid))
+            }
+        };
+
+        if let Some(lease) = lease {
+            lease.ended(by);
+            if let Some(counters) = lease.queue.counters() { counters.release() }
+            lease.queue.memory().release(lease.entry.data.len());
+            lease.queue.confirm(uuid);
+            return Confirmation::Confirmed
+        }
+
+        match requeued {
+            Some(None) => Confirmation::Requeued,
+
+            // Try to queue again now, or hand the object back.
+            Some(Some((queue, entry))) => {
+                queue.memory().unhold(entry.data.len());
+                match queue.requeue(entry) {
+                    Ok(()) => Confirmation::Requeued,
+                    Err(entry) => Confirmation::Full(entry)
+                }
+            },
+
+            None => Confirmation::NoSuchEntity
+        }
+    }
+
+    /// Replace the lease on an object with a new one through the connection
+    /// `by`, so that releasing the old lease has no effect.
+    ///
+    /// The lease stays with the connection holding it, which is told to
+    /// restart the timeout of its Read with `timeout` if it is not `by`.
+    pub fn renew(&self, uuid: &Uuid, timeout: u64, by: Holder,
+                 resolve: &Fn(&str) -> Option<Q>) -> Renewal {
+        let id = {
+            let mut guard = self.0.lock().unwrap();
+            let table = &mut *guard;
+
+            let renewable = table.active.get(uuid).map(|active| !active.nacked);
+            if renewable != Some(true) { return Renewal::NoSuchEntity }
+
+            let id = table.next;
+            table.next += 1;
+
+            let lease = table.active.get_mut(uuid).unwrap();
+            let old = lease.id;
+            lease.id = id;
+
+            if lease.holder == by { return Renewal::Renewed(id) }
+
+            let message = Message::Renewed(lease.holder.token, uuid.clone(), old, id, timeout);
+            if lease.notify.send(message).is_ok() { return Renewal::Forwarded }
+            id
+        };
+
+        // The event loop of the holder has shut down, so nothing would ever
+        // end the new lease.
+        self.release(uuid, id, resolve);
+        Renewal::NoSuchEntity
+    }
+
+    /// Whether an object is leased, including objects which were nacked and
+    /// are waiting to be requeued.
+    pub fn is_leased(&self, uuid: &Uuid) -> bool {
+        self.0.lock().unwrap().active.contains_key(uuid)
+    }
+
+    /// Copy up to `len` bytes of a leased object, starting at `offset`, for
+    /// the connection `by`.
+    ///
+    /// Returns None if the object is not leased to that connection.
+    pub fn chunk(&self, uuid: &Uuid, offset: u64, len: usize, by: Holder) -> Option<Vec<u8>> {
+        let table = self.0.lock().unwrap();
+
+        let lease = table.active.get(uuid).and_then(|lease| {
+            if lease.holder == by && !lease.nacked { Some(lease) } else { None }
+        });
+
+        let chunk = lease.map(|lease| {
+            let data = &lease.entry.data;
+            let start = cmp::min(offset, data.len() as u64) as usize;
+            let end = cmp::min(start + len, data.len());
+            data[start..end].to_vec()
+        });
+        chunk
+    }
+
+    /// Give back an object without confirming it, through the connection
+    /// `by`.
+    ///
+    /// If `delay` is false the object is requeued immediately. Otherwise its
+    /// lease is replaced by a new one which can not be confirmed, and the
+    /// object is requeued when that lease is released.
+    ///
+    /// `resolve` finds queues by name, for objects which are moved to their
+    /// dead-letter queue.
+    pub fn nack(&self, uuid: &Uuid, delay: bool, by: Holder,
+                resolve: &Fn(&str) -> Option<Q>) -> Nack {
+        let lease = {
+            let mut guard = self.0.lock().unwrap();
+            let table = &mut *guard;
+
+            let nacked = table.active.get(uuid).map(|active| active.nacked);
+            if nacked != Some(false) { return Nack::NoSuchEntity }
+
+            if delay {
+                let id = table.next;
+                table.next += 1;
+
+                let lease = table.active.get_mut(uuid).unwrap();
+                lease.ended(by);
+                lease.holder = by;
+                lease.id = id;
+                lease.nacked = true;
+                if let Some(counters) = lease.queue.counters() {
+                    counters.release();
+                    counters.delay();
+                }
+                return Nack::Delayed(id)
+            }
+
+            let lease = table.active.remove(uuid).unwrap();
+            lease.ended(by);
+            if let Some(counters) = lease.queue.counters() { counters.release() }
+            lease.queue.memory().release(lease.entry.data.len());
+            if let Expiry::Requeue = lease.expiry {
+                table.remember(uuid.clone());
+            }
+
+            lease
+        };
+
+        match end(lease, resolve) {
+            Ok(()) => Nack::Requeued,
+            Err((_, entry)) => {
+                self.0.lock().unwrap().requeued.remove(&entry.id);
+                Nack::Full(entry)
+            }
+        }
+    }
+
+    /// Requeue every object given back with a delay through a connection on
+    /// the event loop with this id, which is shutting down, so its timers
+    /// will never requeue them.
+    pub fn release_nacked(&self, event_loop: usize, resolve: &Fn(&str) -> Option<Q>) {
+        let nacked = {
+            let table = self.0.lock().unwrap();
+            table.active.iter()
+                .filter(|&(_, lease)| lease.nacked && lease.holder.event_loop == event_loop)
+                .map(|(uuid, lease)| (uuid.clone(), lease.id))
+                .collect::<Vec<_>>()
+        };
+
+        for (uuid, lease) in nacked { self.release(&uuid, lease, resolve) }
+    }
+
+    /// End a lease because its timeout elapsed, its delay after being nacked
+    /// elapsed, or its connection was closed, and requeue the object or move
+    /// it to its dead-letter queue.
+    ///
+    /// Does nothing if the object was already confirmed, or was requeued and
+    /// read again under a new lease.
+    ///
+    /// `resolve` finds queues by name, as for `nack`.
+    pub fn release(&self, uuid: &Uuid, lease: u64, resolve: &Fn(&str) -> Option<Q>) {
+        let lease = {
+            let mut guard = self.0.lock().unwrap();
+            let table = &mut *guard;
+
+            let current = table.active.get(uuid).map(|active| active.id == lease);
+            if current != Some(true) { return }
+
+            let lease = table.active.remove(uuid).unwrap();
+            if let Some(counters) = lease.queue.counters() {
+                if lease.nacked { counters.deliver() } else { counters.release() }
+            }
+
+            lease.queue.memory().release(lease.entry.data.len());
+
+            if let Expiry::Requeue = lease.expiry {
+                table.remember(uuid.clone());
+            }
+
+            lease
+        };
+
+        if let Err((queue, entry)) = end(lease, resolve) {
+            queue.memory().hold(entry.data.len());
+            self.0.lock().unwrap().requeued.insert(uuid.clone(), Some((queue, entry)));
+        }
+    }
+}
+
+impl<Q: Queue> Table<Q> {
+    /// Remember that an object was requeued, forgetting the object which was
+    /// requeued longest ago if too many are remembered.
+    fn remember(&mut self, uuid: Uuid) {
+        self.requeued.insert(uuid.clone(), None);
+        self.markers.push_back(uuid);
+
+        while self.markers.len() > MAX_REQUEUED {
+            let oldest = self.markers.pop_front().unwrap();
+
+            // Objects which could not be requeued are kept until they are.
+            let requeued = self.requeued.get(&oldest).map(|full| full.is_none());
+            if requeued == Some(true) { self.requeued.remove(&oldest); }
+        }
+    }
+}
+
+impl<Q: Queue> Lease<Q> {
+    /// Tell the connection holding this lease that it ended, unless it was
+    /// ended by that connection itself.
+    fn ended(&self, by: Holder) {
+        if self.holder == by { return }
+
+        let message = Message::Released(self.holder.token, self.entry.id.clone(), self.id);
+        if self.notify.send(message).is_err() {
+            warn!("Could not tell a connection that its lease on {} ended.", self.entry.id);
+        }
+    }
+}
+
+/// Carry out the expiry of a lease which ended without a Confirm, finding
+/// its dead-letter queue, if it has one, using `resolve`.
+///
+/// If the object had to be requeued but its queue was full, returns the
+/// queue and the object.
+fn end<Q: Queue>(lease: Lease<Q>, resolve: &Fn(&str) -> Option<Q>) -> Result<(), (Q, Entry)> {
+    let Lease { queue, entry, expiry, .. } = lease;
+    let id = entry.id.clone();
+
+    // Rather than lose the object, put it back where it was if it cannot be
+    // moved to its dead-letter queue.
+    let entry = match expiry {
+        Expiry::Requeue => entry,
+
+        Expiry::DeadLetter(name) => match resolve(&*name) {
+            Some(dead_letter) => match dead_letter.enqueue(entry) {
+                Ok(()) => {
+                    queue.confirm(&id);
+                    return Ok(())
+                },
+                Err(entry) => {
+                    warn!("Dead-letter queue {} full, requeueing object {}.", name, id);
+                    entry
+                }
+            },
+            None => {
+                warn!("Dead-letter queue {} does not exist, requeueing object {}.", name, id);
+                entry
+            }
+        },
+
+        Expiry::Discard => {
+            warn!("Discarding object {} after {} deliveries.", id, entry.deliveries);
+            queue.confirm(&id);
+            return Ok(())
+        }
+    };
+
+    match queue.requeue(entry) {
+        Ok(()) => Ok(()),
+        Err(entry) => Err((queue, entry))
+    }
+}
+
+impl<Q: Queue> Clone for Leases<Q> {
+    fn clone(&self) -> Leases<Q> { Leases(self.0.clone()) }
+}
+
+impl<Q: Queue> Default for Leases<Q> {
+    fn default() -> Leases<Q> { Leases::new() }
+}
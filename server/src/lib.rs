@@ -12,21 +12,30 @@ extern crate mio;
 extern crate iobuf;
 extern crate uuid;
 extern crate threadpool;
-extern crate comm;
+extern crate bincode;
+extern crate rustc_serialize;
+extern crate time;
 
 #[macro_use]
 extern crate log;
 
 pub use error::{Error, Result};
 pub use executor::Executor;
-pub use queue::{Queue, Queues};
+pub use queue::{Queue, Queues, Entry, Counters, Memory};
+pub use lease::{Leases, Confirmation, Nack, Renewal, Expiry};
+pub use topic::Topics;
 pub use queue::concurrent::{ConcurrentQueue, ConcurrentQueues};
+pub use queue::priority::{ConcurrentPriorityQueue, ConcurrentPriorityQueues};
+pub use queue::durable::{DurableQueue, DurableQueues, SyncPolicy};
 
 use mio::NonBlock;
 use std::net::TcpListener;
 use eventual::Future;
 
+use common::MAX_CLIENT_MESSAGE_LEN;
+
 use queue::rcqueue::RcQueues;
+use queue::priority::RcPriorityQueues;
 
 /// Contains the error type used throughout this crate.
 mod error;
@@ -46,13 +55,64 @@ mod executor;
 /// is located here.
 mod connection;
 
+/// The registry of objects which have been read but not confirmed, shared
+/// by all connections using the same Queues.
+mod lease;
+
+/// The Reads waiting on an event loop for objects to be enqueued.
+mod wait;
+
+/// The topics objects can be published to, and the queues bound to them.
+mod topic;
+
 /// The Queue and Queues traits, and some concrete implementations.
 ///
 /// Particularly RcQueue and RcQueues, a single threaded queue implementation,
 /// and ConcurrentQueue and ConcurrentQueues, a queue safe to share between
-/// threads which is lock free for enqueueing, requeueing, and dequeueing.
+/// threads which takes a short lock for each operation, their priority-aware
+/// counterparts in `priority`, and DurableQueue and DurableQueues, which
+/// persist the operations on another set of queues to disk.
 mod queue;
 
+/// Limits on the size of the messages and objects a Server accepts.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The largest message a client may send, in bytes.
+    ///
+    /// Clients are told this limit when they connect, and connections which
+    /// send a larger message are closed. Each connection buffers at most a
+    /// few messages of this size at a time.
+    pub max_message_len: u64,
+
+    /// The largest object a client may upload in chunks, in bytes.
+    pub max_object_len: u64,
+
+    /// The most bytes of objects held by the queues, by objects read but not
+    /// yet confirmed, and by responses waiting to be sent, before new objects
+    /// are refused with OverBudget.
+    ///
+    /// Usage is shared by every Server using the same queues. A value of 0
+    /// means no limit.
+    pub max_memory: u64,
+
+    /// The most bytes of objects ready to be read in any one queue before
+    /// new objects for it are refused with OverBudget.
+    ///
+    /// A value of 0 means no limit.
+    pub max_queue_memory: u64
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_message_len: MAX_CLIENT_MESSAGE_LEN,
+            max_object_len: 64 * 1024 * 1024,
+            max_memory: 0,
+            max_queue_memory: 0
+        }
+    }
+}
+
 /// Server serves as a communication point with a running server
 /// usually running on another thread.
 ///
@@ -94,6 +154,17 @@ impl Server {
         Server::with_queues(exec, config, slab_size, rcqueues)
     }
 
+    /// Create a server using a specific event loop configuration and slab size,
+    /// whose queues return objects with a higher priority first.
+    ///
+    /// Like `configured`, it will use a special single-threaded queue.
+    pub fn with_priorities<E>(exec: E, config: mio::EventLoopConfig,
+                              slab_size: usize) -> Result<Server>
+    where E: Executor {
+        let queues: RcPriorityQueues = Default::default();
+        Server::with_queues(exec, config, slab_size, queues)
+    }
+
     /// Create a server using a specific event loop configuration, and slab size,
     /// sharing an existing set of Queues, which may also be given to other Servers
     /// which are running concurrently.
@@ -104,11 +175,35 @@ impl Server {
     /// You cannot initialize a Server with the special single threaded queue
     /// when using this constructor, as it could cause memory unsafety is a single
     /// threaded queue is shared between multiple threads.
+    ///
+    /// Any state persisted by the queues, such as the log of `DurableQueues`,
+    /// is recovered before the server starts.
     pub fn with_queues<E, Q>(exec: E, config: mio::EventLoopConfig,
                              slab_size: usize, queues: Q) -> Result<Server>
     where E: Executor, Q: Queues {
+        Server::with_limits(exec, config, slab_size, queues, Default::default())
+    }
+
+    /// Create a server like `with_queues`, which enforces specific limits on
+    /// the size of messages and objects sent by clients.
+    pub fn with_limits<E, Q>(exec: E, config: mio::EventLoopConfig, slab_size: usize,
+                             queues: Q, limits: Limits) -> Result<Server>
+    where E: Executor, Q: Queues {
+         try!(queues.recover());
+
          let mut evloop = try!(mio::EventLoop::configured(config));
-         let mut handler = rt::Handler::new(slab_size, queues);
+         let delayed = queues.delayed();
+         let mut handler = rt::Handler::new(slab_size, queues, limits);
+
+         // Deliver the objects which were still delayed when the queues were
+         // last used, as if their timers had kept running. They are held by
+         // the server until then, whatever its budget.
+         for (name, entry) in delayed {
+             let delay = entry.enqueued.saturating_sub(queue::now_ms());
+             handler.memory().hold(entry.data.len());
+             try!(evloop.timeout_ms(rt::Timeout::Deliver(name, entry, 0), delay));
+         }
+
          let notify = evloop.channel();
 
          let shutdown = {
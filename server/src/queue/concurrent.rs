@@ -1,13 +1,23 @@
 use uuid::Uuid;
-use comm::mpmc::bounded::Channel;
 
-use queue::{Queue, Queues};
+use queue::{Queue, Queues, Entry, Signal, Counters, Memory, Expired};
+use queue::fifo::{self, Fifo, Plain};
+use lease::Leases;
+use topic::Topics;
+use common::QueueOptions;
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::collections::HashMap;
 
 #[derive(Clone)]
-pub struct ConcurrentQueues(usize, Arc<RwLock<HashMap<String, ConcurrentQueue>>>);
+pub struct ConcurrentQueues {
+    /// The capacity of newly created queues.
+    capacity: usize,
+    queues: Arc<RwLock<HashMap<String, ConcurrentQueue>>>,
+    leases: Leases<ConcurrentQueue>,
+    topics: Topics,
+    memory: Memory
+}
 
 impl ConcurrentQueues {
     /// Create a new collection of queueus.
@@ -15,53 +25,189 @@ impl ConcurrentQueues {
     /// `capacity` will be used to set the capacity of the innner queues,
     /// which are bounded.
     pub fn new(capacity: usize) -> ConcurrentQueues {
-        ConcurrentQueues(capacity, Arc::new(RwLock::new(HashMap::new())))
+        ConcurrentQueues {
+            capacity: capacity,
+            queues: Arc::new(RwLock::new(HashMap::new())),
+            leases: Leases::new(),
+            topics: Topics::new(),
+            memory: Memory::new()
+        }
     }
 
     /// Insert an existing queue into this collection of queues.
+    ///
+    /// Its objects are only counted in the memory of these queues if it
+    /// was created by them.
     pub fn insert_queue(&self, name: String, queue: ConcurrentQueue) {
-        self.1.write().unwrap().insert(name, queue);
+        self.queues.write().unwrap().insert(name, queue);
     }
 }
 
 impl Queues for ConcurrentQueues {
     type Queue = ConcurrentQueue;
 
-    fn insert(&self, name: String) {
-        self.1.write().unwrap().entry(name)
-            .or_insert_with(|| ConcurrentQueue::new(self.0));
+    fn insert(&self, name: String, options: QueueOptions) {
+        self.queues.write().unwrap().entry(name)
+            .or_insert_with(|| ConcurrentQueue::create(self.capacity, options,
+                                                       self.memory.clone()));
     }
 
     fn remove(&self, name: &str) -> Option<ConcurrentQueue> {
-        self.1.write().unwrap().remove(name)
+        self.queues.write().unwrap().remove(name)
     }
 
     fn queue(&self, name: &str) -> Option<ConcurrentQueue> {
-        self.1.read().unwrap().get(name).cloned()
+        self.queues.read().unwrap().get(name).cloned()
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.queues.read().unwrap().keys().cloned().collect()
     }
+
+    fn leases(&self) -> Option<&Leases<ConcurrentQueue>> { Some(&self.leases) }
+
+    fn topics(&self) -> Option<&Topics> { Some(&self.topics) }
+
+    fn memory(&self) -> Option<&Memory> { Some(&self.memory) }
 }
 
+/// A bounded queue which can be shared between threads.
+///
+/// This used to be a lock free channel, but every operation now takes a
+/// short lock instead. Requeued objects have to go back to the front, the
+/// length and size limits have to be checked along with the push they allow,
+/// and stats, peeking, browsing, removal and purging all have to look inside
+/// the queue, none of which a channel can do. The lock is only held to move
+/// entries in or out, never while waiting or doing IO.
 #[derive(Clone)]
-pub struct ConcurrentQueue(Arc<Channel<'static, (Uuid, Vec<u8>)>>);
+pub struct ConcurrentQueue {
+    entries: Arc<Mutex<Fifo<Plain>>>,
+    signal: Signal,
+    room: Signal,
+    counters: Counters,
+    expired: Expired,
+    memory: Memory
+}
 
 impl ConcurrentQueue {
     /// Creat a new queue with the passed capacity.
     pub fn new(capacity: usize) -> ConcurrentQueue {
-        ConcurrentQueue(Arc::new(Channel::new(capacity)))
+        ConcurrentQueue::with_options(capacity, Default::default())
+    }
+
+    /// Create a new queue with the passed capacity and options.
+    pub fn with_options(capacity: usize, options: QueueOptions) -> ConcurrentQueue {
+        ConcurrentQueue::create(capacity, options, Memory::new())
+    }
+
+    /// Create a new queue whose objects are counted in `memory`.
+    fn create(capacity: usize, options: QueueOptions, memory: Memory) -> ConcurrentQueue {
+        let (counters, expired) = (Counters::new(), Expired::new());
+        let entries = Fifo::new(capacity, options, counters.clone(), expired.clone(),
+                                memory.clone());
+
+        ConcurrentQueue {
+            entries: Arc::new(Mutex::new(entries)),
+            signal: Default::default(),
+            room: Default::default(),
+            counters: counters,
+            expired: expired,
+            memory: memory
+        }
     }
 }
 
 impl Queue for ConcurrentQueue {
-    fn enqueue(&self, id: Uuid, data: Vec<u8>) -> Result<(), (Uuid, Vec<u8>)> {
-        self.0.send_async((id, data)).map_err(|(data, _)| data)
+    fn enqueue(&self, entry: Entry) -> Result<(), Entry> {
+        try!(self.entries.lock().unwrap().enqueue(entry));
+        Ok(self.signal.notify())
     }
 
-    fn requeue(&self, id: Uuid, data: Vec<u8>) -> Result<(), (Uuid, Vec<u8>)> {
-        self.enqueue(id, data)
+    /// Discard entries under the same lock as the enqueue, so no other
+    /// thread can take the room made.
+    fn offer(&self, entry: Entry) -> Result<Vec<Entry>, Entry> {
+        let dropped = try!(self.entries.lock().unwrap().offer(entry));
+        self.signal.notify();
+        Ok(dropped)
     }
 
-    fn dequeue(&self) -> Option<(Uuid, Vec<u8>)> {
-        self.0.recv_async().ok()
+    fn requeue(&self, entry: Entry) -> Result<(), Entry> {
+        try!(self.entries.lock().unwrap().requeue(entry));
+        Ok(self.signal.notify())
+    }
+
+    fn dequeue(&self) -> Option<Entry> {
+        self.dequeue_batch(1).pop()
     }
-}
 
+    /// Enqueue the whole batch under one lock, unlike the default, so other
+    /// threads never see part of it, and its entries stay together in order.
+    fn enqueue_batch(&self, entries: Vec<Entry>) -> Result<(), Vec<Entry>> {
+        let count = entries.len();
+        let rest = self.entries.lock().unwrap().enqueue_batch(entries);
+        self.signal.notify_many(count - rest.len());
+        if rest.is_empty() { Ok(()) } else { Err(rest) }
+    }
+
+    /// Dequeue the whole batch under one lock, so it holds consecutive
+    /// entries even while other threads read the queue.
+    fn dequeue_batch(&self, max: usize) -> Vec<Entry> {
+        let (entries, left) = self.entries.lock().unwrap().dequeue_batch(max);
+        self.room.notify_many(left);
+        entries
+    }
+
+    fn options(&self) -> QueueOptions {
+        self.entries.lock().unwrap().options().clone()
+    }
+
+    fn alter(&self, options: QueueOptions) {
+        self.entries.lock().unwrap().alter(options);
+        self.room.notify_all();
+    }
+
+    fn take(&self, count: usize) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap().take(count);
+        self.room.notify_many(entries.len());
+        entries
+    }
+
+    /// Move the entries under the locks of both queues, so other threads
+    /// never see them in neither queue or both, or take the room they fit in.
+    fn move_to(&self, to: &ConcurrentQueue, count: usize) -> Vec<Entry> {
+        let (moved, popped) = match fifo::lock_both(&*self.entries, &*to.entries) {
+            Some((mut from, mut into)) => from.move_to(&mut *into, count),
+            None => return Vec::new()
+        };
+
+        self.room.notify_many(popped);
+        to.signal.notify_many(moved.len());
+        moved
+    }
+
+    fn remove(&self, id: &Uuid) -> Option<Entry> {
+        let entry = self.entries.lock().unwrap().remove(id);
+        if entry.is_some() { self.room.notify() }
+        entry
+    }
+
+    fn visit<F: FnMut(&Entry) -> bool>(&self, visit: F) {
+        self.entries.lock().unwrap().visit(visit)
+    }
+
+    fn len(&self) -> usize { self.entries.lock().unwrap().len() }
+
+    fn bytes(&self) -> u64 { self.entries.lock().unwrap().bytes() }
+
+    fn oldest(&self) -> Option<u64> { self.entries.lock().unwrap().oldest() }
+
+    fn expired(&self) -> Vec<Entry> { self.expired.take() }
+
+    fn counters(&self) -> Option<&Counters> { Some(&self.counters) }
+
+    fn memory(&self) -> &Memory { &self.memory }
+
+    fn signal(&self) -> Option<&Signal> { Some(&self.signal) }
+
+    fn room(&self) -> Option<&Signal> { Some(&self.room) }
+}
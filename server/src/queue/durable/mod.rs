@@ -0,0 +1,592 @@
+use uuid::Uuid;
+
+use common::QueueOptions;
+use queue::{Queue, Queues, Entry, Signal, Counters, Memory};
+use lease::Leases;
+use topic::Topics;
+use {Error};
+
+use std::{cmp, mem};
+use std::thread;
+use std::path::Path;
+use std::hash::{Hash, Hasher, SipHasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub use self::wal::{SyncPolicy, DEFAULT_SEGMENT_SIZE, DEFAULT_SNAPSHOT_EVERY};
+
+use self::wal::{Wal, Event};
+
+/// The number of locks ordering the operations on queues, each of which is
+/// shared by every queue whose name hashes to it.
+const ORDER_LOCKS: usize = 64;
+
+/// The on-disk log of queue operations.
+mod wal;
+
+/// Rebuilding the contents of queues from the log.
+mod state;
+
+/// Point-in-time snapshots of the contents of queues, which allow old
+/// log segments to be discarded.
+mod snapshot;
+
+/// A set of queues which records every operation in an on-disk write-ahead
+/// log, so that their contents survive a restart of the server.
+///
+/// DurableQueues wraps another `Queues` implementation which actually holds
+/// the data, and replays the log into it when a Server is started with
+/// `Server::with_queues`. Entries which were read but never confirmed before
+/// the restart are requeued during recovery, and entries which were still
+/// delayed are enqueued once their delay elapses.
+///
+/// To keep recovery fast, a snapshot of the contents of every queue,
+/// including entries which are read but not yet confirmed, is periodically
+/// written and the log segments it covers are deleted. Recovery then only
+/// has to load the snapshot and replay the log written after it.
+///
+/// Like the wrapped queues, DurableQueues can be cloned and shared among
+/// several Servers, in which case they all write to the same log. Only
+/// operations on the same queue are serialized, so different queues can
+/// still be used concurrently.
+#[derive(Clone)]
+pub struct DurableQueues<Q: Queues> {
+    inner: Q,
+    shared: Arc<Shared>,
+    leases: Leases<DurableQueue<Q::Queue>>
+}
+
+/// The state shared by a set of DurableQueues and all of their queues.
+struct Shared {
+    /// The log, which is only locked while events are written to it.
+    wal: Mutex<Wal>,
+
+    /// Locked around every operation on a queue and the events it appends,
+    /// so that the events of each queue are logged in the order in which
+    /// they were applied.
+    ///
+    /// Events of different queues do not depend on each other when they
+    /// are replayed, so their relative order in the log does not matter.
+    order: Vec<Mutex<()>>,
+
+    /// Held while recovering or taking a snapshot, so that only one of them
+    /// is reading and deleting old segments at a time.
+    compaction: Mutex<()>,
+
+    /// Set while a snapshot is being taken in the background.
+    snapshotting: AtomicBool,
+
+    /// Set once the log has been replayed into the inner queues, so that
+    /// Servers sharing these queues only recover once.
+    recovered: AtomicBool,
+
+    /// The entries still delayed when the log was replayed, until a Server
+    /// takes them to deliver when their delay elapses.
+    delayed: Mutex<Vec<(String, Entry)>>
+}
+
+impl<Q: Queues> DurableQueues<Q> {
+    /// Open the log in the passed directory, creating it if it does not
+    /// exist, using the default segment size and snapshot interval.
+    ///
+    /// The existing contents of the log are not replayed until these queues
+    /// are used to start a Server.
+    pub fn open<P>(dir: P, queues: Q, policy: SyncPolicy) -> ::Result<DurableQueues<Q>>
+    where P: AsRef<Path> {
+        DurableQueues::configured(dir, queues, policy, DEFAULT_SEGMENT_SIZE,
+                                  Some(DEFAULT_SNAPSHOT_EVERY))
+    }
+
+    /// Open the log in the passed directory, with a specific maximum size
+    /// for each log segment.
+    ///
+    /// If `snapshot_every` is set, a snapshot is taken in the background
+    /// whenever that many segments have been written since the last one.
+    pub fn configured<P>(dir: P, queues: Q, policy: SyncPolicy, segment_size: u64,
+                         snapshot_every: Option<u64>) -> ::Result<DurableQueues<Q>>
+    where P: AsRef<Path> {
+        let wal = try!(Wal::open(dir.as_ref(), policy, segment_size, snapshot_every));
+
+        Ok(DurableQueues {
+            inner: queues,
+            shared: Arc::new(Shared {
+                wal: Mutex::new(wal),
+                order: (0..ORDER_LOCKS).map(|_| Mutex::new(())).collect(),
+                compaction: Mutex::new(()),
+                snapshotting: AtomicBool::new(false),
+                recovered: AtomicBool::new(false),
+                delayed: Mutex::new(Vec::new())
+            }),
+            leases: Leases::new()
+        })
+    }
+
+    /// Take a snapshot of every queue now, and delete the log segments
+    /// it covers.
+    pub fn snapshot(&self) -> ::Result<()> {
+        self.shared.snapshot(false)
+    }
+
+    fn wrap(&self, name: &str, queue: Q::Queue) -> DurableQueue<Q::Queue> {
+        DurableQueue {
+            name: name.to_string(),
+            inner: queue,
+            shared: self.shared.clone()
+        }
+    }
+}
+
+impl<Q: Queues> Queues for DurableQueues<Q> {
+    type Queue = DurableQueue<Q::Queue>;
+
+    fn insert(&self, name: String, options: QueueOptions) {
+        let due = {
+            let _order = self.shared.order(&name);
+            self.inner.insert(name.clone(), options.clone());
+            append(&self.shared, Event::CreateQueue(name, options))
+        };
+
+        snapshot_if_due(&self.shared, due);
+    }
+
+    fn remove(&self, name: &str) -> Option<DurableQueue<Q::Queue>> {
+        let (queue, due) = {
+            let _order = self.shared.order(name);
+            match self.inner.remove(name) {
+                Some(queue) => {
+                    let due = append(&self.shared, Event::DeleteQueue(name.to_string()));
+                    (Some(self.wrap(name, queue)), due)
+                },
+                None => (None, false)
+            }
+        };
+
+        snapshot_if_due(&self.shared, due);
+        queue
+    }
+
+    fn queue(&self, name: &str) -> Option<DurableQueue<Q::Queue>> {
+        self.inner.queue(name).map(|queue| self.wrap(name, queue))
+    }
+
+    fn names(&self) -> Vec<String> { self.inner.names() }
+
+    fn leases(&self) -> Option<&Leases<DurableQueue<Q::Queue>>> { Some(&self.leases) }
+
+    fn topics(&self) -> Option<&Topics> { self.inner.topics() }
+
+    fn memory(&self) -> Option<&Memory> { self.inner.memory() }
+
+    fn bind(&self, topic: String, queue: String, pattern: String) -> bool {
+        let (bound, due) = {
+            let _order = self.shared.order(&queue);
+            if !self.inner.bind(topic.clone(), queue.clone(), pattern.clone()) { return false }
+            (true, append(&self.shared, Event::Bind(topic, queue, pattern)))
+        };
+
+        snapshot_if_due(&self.shared, due);
+        bound
+    }
+
+    fn unbind(&self, topic: &str, queue: &str) -> bool {
+        let (unbound, due) = {
+            let _order = self.shared.order(queue);
+            if !self.inner.unbind(topic, queue) { return false }
+            (true, append(&self.shared, Event::Unbind(topic.to_string(), queue.to_string())))
+        };
+
+        snapshot_if_due(&self.shared, due);
+        unbound
+    }
+
+    fn recover(&self) -> ::Result<()> {
+        if self.shared.recovered.swap(true, Ordering::SeqCst) { return Ok(()) }
+
+        let _compacting = self.shared.compaction.lock().unwrap();
+        let mut wal = self.shared.wal.lock().unwrap();
+
+        let (mut state, next) = try!(snapshot::load(wal.dir()));
+        try!(wal::replay(wal.dir(), next, wal.first(), |event| state.apply(event)));
+
+        for (name, contents) in state.queues {
+            // The queue may have been altered to hold fewer entries than it
+            // already did, so its limits only apply once it is restored.
+            let unlimited = QueueOptions { max_len: 0, max_bytes: 0, ..contents.options.clone() };
+            self.inner.insert(name.clone(), unlimited);
+            let queue = self.inner.queue(&name).unwrap();
+
+            for entry in contents.ready {
+                try!(queue.enqueue(entry).map_err(|_| Error::Full(name.clone())));
+            }
+
+            // The connections which read these entries are gone, so they can
+            // never be confirmed. The most recently read are requeued first,
+            // so the entries end up at the front in the order they were read.
+            for entry in contents.leased.into_entries().into_iter().rev() {
+                let id = entry.id.clone();
+                try!(queue.requeue(entry).map_err(|_| Error::Full(name.clone())));
+                try!(wal.append(&Event::Requeue(name.clone(), id)));
+            }
+
+            // The timers of the delayed entries are gone with the server
+            // which set them, so they are handed to the next one started.
+            let mut delayed = self.shared.delayed.lock().unwrap();
+            for entry in contents.delayed.into_entries() {
+                queue.delay(&entry);
+                delayed.push((name.clone(), entry));
+            }
+
+            queue.alter(contents.options);
+        }
+
+        for (topic, bindings) in state.topics {
+            for (queue, pattern) in bindings { self.inner.bind(topic.clone(), queue, pattern); }
+        }
+
+        Ok(())
+    }
+
+    fn delayed(&self) -> Vec<(String, Entry)> {
+        mem::replace(&mut *self.shared.delayed.lock().unwrap(), Vec::new())
+    }
+}
+
+impl Shared {
+    /// Lock the order of the operations on the named queue.
+    fn order(&self, name: &str) -> MutexGuard<()> {
+        self.order[order_lock(name)].lock().unwrap()
+    }
+
+    /// Lock the order of the operations on two queues at once.
+    ///
+    /// The locks are always taken in the same order, so that two moves
+    /// between the same queues in opposite directions cannot deadlock.
+    fn order_both(&self, a: &str, b: &str) -> (MutexGuard<()>, Option<MutexGuard<()>>) {
+        let (a, b) = (order_lock(a), order_lock(b));
+        if a == b { return (self.order[a].lock().unwrap(), None) }
+
+        let first = self.order[cmp::min(a, b)].lock().unwrap();
+        (first, Some(self.order[cmp::max(a, b)].lock().unwrap()))
+    }
+
+    /// Take a snapshot by folding all sealed log segments into the previous
+    /// snapshot, then delete those segments.
+    ///
+    /// The log is only locked to start a new segment, so operations on the
+    /// queues can continue while the snapshot is written.
+    fn snapshot(&self, only_if_due: bool) -> ::Result<()> {
+        let _compacting = self.compaction.lock().unwrap();
+
+        let (dir, cutoff) = {
+            let mut wal = self.wal.lock().unwrap();
+            if only_if_due && !wal.snapshot_due() { return Ok(()) }
+
+            try!(wal.rotate());
+            (wal.dir().to_path_buf(), wal.segment())
+        };
+
+        let (mut state, next) = try!(snapshot::load(&dir));
+        try!(wal::replay(&dir, next, cutoff, |event| state.apply(event)));
+        try!(snapshot::write(&dir, &state, cutoff));
+
+        self.wal.lock().unwrap().snapshotted(cutoff);
+
+        try!(wal::remove_segments(&dir, cutoff));
+        snapshot::remove_before(&dir, cutoff)
+    }
+}
+
+/// Start taking a snapshot on a new thread, if one is due and none is
+/// already in progress.
+fn snapshot_if_due(shared: &Arc<Shared>, due: bool) {
+    if !due || shared.snapshotting.swap(true, Ordering::SeqCst) { return }
+
+    let shared = shared.clone();
+    thread::spawn(move || {
+        if let Err(e) = shared.snapshot(true) {
+            error!("Failed to take a snapshot of durable queues: {:?}", e);
+        }
+
+        shared.snapshotting.store(false, Ordering::SeqCst);
+    });
+}
+
+/// A single queue in a set of DurableQueues.
+#[derive(Clone)]
+pub struct DurableQueue<Q: Queue> {
+    name: String,
+    inner: Q,
+    shared: Arc<Shared>
+}
+
+impl<Q: Queue> Queue for DurableQueue<Q> {
+    fn enqueue(&self, entry: Entry) -> Result<(), Entry> {
+        let due = {
+            let _order = self.shared.order(&self.name);
+            // The event is encoded before the entry is moved into the queue.
+            let event = wal::encode(&Event::Enqueue(self.name.clone(), &entry));
+            try!(self.inner.enqueue(entry));
+            append_encoded(&self.shared, Some(event))
+        };
+
+        snapshot_if_due(&self.shared, due);
+        Ok(())
+    }
+
+    fn offer(&self, entry: Entry) -> Result<Vec<Entry>, Entry> {
+        let (dropped, due) = {
+            let _order = self.shared.order(&self.name);
+            let event = wal::encode(&Event::Enqueue(self.name.clone(), &entry));
+            let dropped = try!(self.inner.offer(entry));
+
+            // The entries discarded to make room are logged as removed first.
+            let removed = dropped.iter()
+                .map(|entry| Event::Remove(self.name.clone(), entry.id.clone()))
+                .collect::<Vec<Event>>();
+            let events = removed.iter().map(|event| wal::encode(event)).chain(Some(event));
+            (dropped, append_encoded(&self.shared, events))
+        };
+
+        snapshot_if_due(&self.shared, due);
+        Ok(dropped)
+    }
+
+    fn requeue(&self, entry: Entry) -> Result<(), Entry> {
+        let due = {
+            let _order = self.shared.order(&self.name);
+            let id = entry.id.clone();
+            try!(self.inner.requeue(entry));
+            append(&self.shared, Event::Requeue(self.name.clone(), id))
+        };
+
+        snapshot_if_due(&self.shared, due);
+        Ok(())
+    }
+
+    fn dequeue(&self) -> Option<Entry> {
+        let (top, due) = {
+            let _order = self.shared.order(&self.name);
+            match self.inner.dequeue() {
+                Some(entry) => {
+                    let due = append(&self.shared, Event::Dequeue(self.name.clone(),
+                                                                  entry.id.clone()));
+                    (Some(entry), due)
+                },
+                None => (None, false)
+            }
+        };
+
+        snapshot_if_due(&self.shared, due);
+        top
+    }
+
+    fn enqueue_batch(&self, entries: Vec<Entry>) -> Result<(), Vec<Entry>> {
+        let (result, due) = {
+            let _order = self.shared.order(&self.name);
+
+            // The events are encoded before the entries are moved into the
+            // queue, and only those of the entries which fit are logged.
+            let events = entries.iter()
+                .map(|entry| wal::encode(&Event::Enqueue(self.name.clone(), entry)))
+                .collect::<Vec<_>>();
+
+            let result = self.inner.enqueue_batch(entries);
+            let queued = events.len() - result.as_ref().err().map(|rest| rest.len()).unwrap_or(0);
+            (result, append_encoded(&self.shared, events.into_iter().take(queued)))
+        };
+
+        snapshot_if_due(&self.shared, due);
+        result
+    }
+
+    fn dequeue_batch(&self, max: usize) -> Vec<Entry> {
+        let (entries, due) = {
+            let _order = self.shared.order(&self.name);
+            let entries = self.inner.dequeue_batch(max);
+            let events = entries.iter()
+                .map(|entry| Event::Dequeue(self.name.clone(), entry.id.clone()));
+
+            let due = append_all(&self.shared, events);
+            (entries, due)
+        };
+
+        snapshot_if_due(&self.shared, due);
+        entries
+    }
+
+    fn expired(&self) -> Vec<Entry> {
+        let (entries, due) = {
+            let _order = self.shared.order(&self.name);
+            let entries = self.inner.expired();
+            let events = entries.iter()
+                .map(|entry| Event::Remove(self.name.clone(), entry.id.clone()));
+
+            let due = append_all(&self.shared, events);
+            (entries, due)
+        };
+
+        snapshot_if_due(&self.shared, due);
+        entries
+    }
+
+    fn confirm(&self, id: &Uuid) {
+        let due = {
+            let _order = self.shared.order(&self.name);
+            self.inner.confirm(id);
+            append(&self.shared, Event::Confirm(self.name.clone(), id.clone()))
+        };
+
+        snapshot_if_due(&self.shared, due);
+    }
+
+    fn delay(&self, entry: &Entry) {
+        let due = {
+            let _order = self.shared.order(&self.name);
+            self.inner.delay(entry);
+            append_encoded(&self.shared, Some(wal::encode(&Event::Delay(self.name.clone(), entry))))
+        };
+
+        snapshot_if_due(&self.shared, due);
+    }
+
+    fn discard_delayed(&self, id: &Uuid) {
+        let due = {
+            let _order = self.shared.order(&self.name);
+            self.inner.discard_delayed(id);
+            append(&self.shared, Event::Remove(self.name.clone(), id.clone()))
+        };
+
+        snapshot_if_due(&self.shared, due);
+    }
+
+    fn options(&self) -> QueueOptions {
+        self.inner.options()
+    }
+
+    fn alter(&self, options: QueueOptions) {
+        let due = {
+            let _order = self.shared.order(&self.name);
+            self.inner.alter(options.clone());
+            append(&self.shared, Event::AlterQueue(self.name.clone(), options))
+        };
+
+        snapshot_if_due(&self.shared, due);
+    }
+
+    fn take(&self, count: usize) -> Vec<Entry> {
+        let (entries, due) = {
+            let _order = self.shared.order(&self.name);
+            let entries = self.inner.take(count);
+            let events = entries.iter()
+                .map(|entry| Event::Remove(self.name.clone(), entry.id.clone()));
+
+            let due = append_all(&self.shared, events);
+            (entries, due)
+        };
+
+        snapshot_if_due(&self.shared, due);
+        entries
+    }
+
+    /// Log the moved entries as removed from this queue and enqueued on the
+    /// other, in the order of the operations on both queues.
+    fn move_to(&self, to: &DurableQueue<Q>, count: usize) -> Vec<Entry> {
+        let (moved, due) = {
+            let _order = self.shared.order_both(&self.name, &to.name);
+            let moved = self.inner.move_to(&to.inner, count);
+            let events = moved.iter().flat_map(|entry| {
+                vec![Event::Remove(self.name.clone(), entry.id.clone()),
+                     Event::Enqueue(to.name.clone(), entry.clone())]
+            });
+
+            let due = append_all(&self.shared, events);
+            (moved, due)
+        };
+
+        snapshot_if_due(&self.shared, due);
+        moved
+    }
+
+    fn purge(&self) -> usize {
+        let (count, due) = {
+            let _order = self.shared.order(&self.name);
+            match self.inner.purge() {
+                0 => (0, false),
+                count => (count, append(&self.shared, Event::Purge(self.name.clone(), count)))
+            }
+        };
+
+        snapshot_if_due(&self.shared, due);
+        count
+    }
+
+    fn remove(&self, id: &Uuid) -> Option<Entry> {
+        let (entry, due) = {
+            let _order = self.shared.order(&self.name);
+            match self.inner.remove(id) {
+                Some(entry) => {
+                    let due = append(&self.shared, Event::Remove(self.name.clone(), id.clone()));
+                    (Some(entry), due)
+                },
+                None => (None, false)
+            }
+        };
+
+        snapshot_if_due(&self.shared, due);
+        entry
+    }
+
+    fn visit<F: FnMut(&Entry) -> bool>(&self, visit: F) { self.inner.visit(visit) }
+
+    fn len(&self) -> usize { self.inner.len() }
+
+    fn bytes(&self) -> u64 { self.inner.bytes() }
+
+    fn oldest(&self) -> Option<u64> { self.inner.oldest() }
+
+    fn counters(&self) -> Option<&Counters> { self.inner.counters() }
+
+    fn memory(&self) -> &Memory { self.inner.memory() }
+
+    fn signal(&self) -> Option<&Signal> { self.inner.signal() }
+
+    fn room(&self) -> Option<&Signal> { self.inner.room() }
+}
+
+/// Append an event to the log from an operation which cannot fail, returning
+/// whether a snapshot is now due.
+///
+/// The operation has already been applied in memory, so the best we can do
+/// is report that it may not survive a restart.
+fn append(shared: &Shared, event: Event) -> bool {
+    append_all(shared, Some(event))
+}
+
+/// Append several events to the log at once, so that they are not
+/// interleaved with the events of other queues.
+fn append_all<I>(shared: &Shared, events: I) -> bool where I: IntoIterator<Item=Event> {
+    append_encoded(shared, events.into_iter().map(|event| wal::encode(&event)))
+}
+
+/// Append several events encoded by `wal::encode` at once, as for
+/// `append_all`.
+fn append_encoded<I>(shared: &Shared, events: I) -> bool
+where I: IntoIterator<Item=::Result<Vec<u8>>> {
+    let mut wal = shared.wal.lock().unwrap();
+
+    for event in events {
+        if let Err(e) = event.and_then(|bytes| wal.append_encoded(&bytes)) {
+            error!("Failed to write to the write-ahead log, operation will not be durable: {:?}",
+                   e);
+        }
+    }
+
+    wal.snapshot_due()
+}
+
+/// The index of the lock ordering the operations on the named queue.
+fn order_lock(name: &str) -> usize {
+    let mut hasher = SipHasher::new();
+    name.hash(&mut hasher);
+    (hasher.finish() % ORDER_LOCKS as u64) as usize
+}
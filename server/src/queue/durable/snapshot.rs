@@ -0,0 +1,60 @@
+use bincode::{self, SizeLimit, DecodingResult};
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::state::State;
+use super::wal::{self, numbered, HEADER_LEN, SNAPSHOT_MAGIC};
+use {Result};
+
+/// Load the latest snapshot in the directory.
+///
+/// Returns the snapshotted state and the number of the first log segment
+/// which is not included in it, or an empty state and segment 0 if no
+/// snapshot has been taken yet.
+pub fn load(dir: &Path) -> Result<(State, u64)> {
+    match try!(numbered(dir, "snapshot")).pop() {
+        Some((next, path)) => {
+            let mut buf = Vec::new();
+            try!(try!(File::open(&path)).read_to_end(&mut buf));
+            try!(wal::check_header(&path, &buf, SNAPSHOT_MAGIC));
+
+            let decoded: DecodingResult<(State, u64)> = bincode::decode(&buf[HEADER_LEN..]);
+            Ok((try!(decoded).0, next))
+        },
+        None => Ok((State::default(), 0))
+    }
+}
+
+/// Write a snapshot of `state`, which includes every log segment before `next`.
+///
+/// The snapshot is written to a temporary file and then renamed, so a crash
+/// while writing it leaves the previous snapshot in place.
+pub fn write(dir: &Path, state: &State, next: u64) -> Result<()> {
+    let bytes = try!(bincode::encode(state, SizeLimit::Infinite));
+    let tmp = dir.join(format!("{:020}.snapshot.tmp", next));
+
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(file.write_all(&wal::header(SNAPSHOT_MAGIC)));
+        try!(file.write_all(&bytes));
+        try!(file.sync_all());
+    }
+
+    try!(fs::rename(&tmp, &snapshot_path(dir, next)));
+    Ok(())
+}
+
+/// Delete every snapshot older than the one including segments before `next`.
+pub fn remove_before(dir: &Path, next: u64) -> Result<()> {
+    for (n, path) in try!(numbered(dir, "snapshot")) {
+        if n < next { try!(fs::remove_file(&path)) }
+    }
+
+    Ok(())
+}
+
+fn snapshot_path(dir: &Path, next: u64) -> PathBuf {
+    dir.join(format!("{:020}.snapshot", next))
+}
@@ -0,0 +1,219 @@
+use uuid::Uuid;
+
+use common::QueueOptions;
+use queue::Entry;
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::wal::Event;
+
+/// The contents of a set of queues, rebuilt by applying the events
+/// recorded in the write-ahead log in order.
+#[derive(Debug, Default, RustcEncodable, RustcDecodable)]
+pub struct State {
+    pub queues: HashMap<String, QueueState>,
+
+    /// The bindings of each topic, as the name of the queue and its
+    /// pattern.
+    pub topics: HashMap<String, Vec<(String, String)>>
+}
+
+/// The contents of a single queue.
+#[derive(Debug, Default, RustcEncodable, RustcDecodable)]
+pub struct QueueState {
+    /// The options the queue was created with, or last altered to.
+    pub options: QueueOptions,
+
+    /// Entries waiting to be read, keyed by their position in the order
+    /// they will be read.
+    pub ready: BTreeMap<i64, Entry>,
+
+    /// The position of every entry waiting to be read, so entries can be
+    /// found by id without scanning the queue.
+    positions: HashMap<Uuid, i64>,
+
+    /// Entries which were read, but not yet confirmed or requeued, in the
+    /// order they were read.
+    pub leased: Sequence,
+
+    /// Entries which are held back until their delay elapses, and not yet
+    /// enqueued, in the order they were delayed.
+    pub delayed: Sequence
+}
+
+/// Entries kept in the order they were added, which can also be found by id.
+#[derive(Debug, Default, RustcEncodable, RustcDecodable)]
+pub struct Sequence {
+    entries: BTreeMap<u64, Entry>,
+
+    /// The place of every entry, keyed by its id.
+    places: HashMap<Uuid, u64>
+}
+
+impl Sequence {
+    /// Add an entry after every other.
+    fn push(&mut self, entry: Entry) {
+        let place = self.entries.keys().next_back().map(|&last| last + 1).unwrap_or(0);
+        self.places.insert(entry.id.clone(), place);
+        self.entries.insert(place, entry);
+    }
+
+    /// Remove the entry with the given id.
+    fn remove(&mut self, id: &Uuid) -> Option<Entry> {
+        match self.places.remove(id) {
+            Some(place) => self.entries.remove(&place),
+            None => None
+        }
+    }
+
+    /// The entries, in the order they were added.
+    pub fn into_entries(self) -> Vec<Entry> {
+        self.entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+}
+
+impl QueueState {
+    fn new(options: QueueOptions) -> QueueState {
+        QueueState {
+            options: options,
+            ready: BTreeMap::new(),
+            positions: HashMap::new(),
+            leased: Sequence::default(),
+            delayed: Sequence::default()
+        }
+    }
+
+    /// Add an entry to the back of the queue.
+    fn push_back(&mut self, entry: Entry) {
+        let position = self.ready.keys().next_back().map(|&last| last + 1).unwrap_or(0);
+        self.insert(position, entry);
+    }
+
+    /// Add an entry to the front of the queue.
+    fn push_front(&mut self, entry: Entry) {
+        let position = self.ready.keys().next().map(|&first| first - 1).unwrap_or(0);
+        self.insert(position, entry);
+    }
+
+    /// Remove the entry at the front of the queue.
+    fn pop_front(&mut self) -> Option<Entry> {
+        let position = match self.ready.keys().next() {
+            Some(&first) => first,
+            None => return None
+        };
+
+        let entry = self.ready.remove(&position);
+        if let Some(ref entry) = entry { self.positions.remove(&entry.id); }
+        entry
+    }
+
+    /// Remove the entry with the given id, wherever it is in the queue.
+    fn take(&mut self, id: &Uuid) -> Option<Entry> {
+        match self.positions.remove(id) {
+            Some(position) => self.ready.remove(&position),
+            None => None
+        }
+    }
+
+    fn insert(&mut self, position: i64, entry: Entry) {
+        self.positions.insert(entry.id.clone(), position);
+        self.ready.insert(position, entry);
+    }
+}
+
+impl State {
+    /// Apply a single event.
+    ///
+    /// Events which refer to queues or entries we do not know about are
+    /// ignored, they can only occur if the queue was deleted concurrently.
+    pub fn apply(&mut self, event: Event) {
+        match event {
+            Event::CreateQueue(name, options) => {
+                self.queues.entry(name).or_insert_with(|| QueueState::new(options));
+            },
+
+            Event::DeleteQueue(name) => {
+                self.queues.remove(&name);
+
+                let topics = self.topics.keys().cloned().collect::<Vec<_>>();
+                for topic in topics { self.unbind(&topic, &name) }
+            },
+
+            Event::Enqueue(name, entry) => {
+                if let Some(queue) = self.queues.get_mut(&name) {
+                    queue.delayed.remove(&entry.id);
+                    queue.push_back(entry);
+                }
+            },
+
+            Event::Delay(name, entry) => {
+                if let Some(queue) = self.queues.get_mut(&name) {
+                    queue.delayed.push(entry);
+                }
+            },
+
+            Event::Dequeue(name, id) => {
+                if let Some(queue) = self.queues.get_mut(&name) {
+                    if let Some(mut entry) = queue.take(&id) {
+                        entry.deliveries += 1;
+                        queue.leased.push(entry);
+                    }
+                }
+            },
+
+            Event::Requeue(name, id) => {
+                if let Some(queue) = self.queues.get_mut(&name) {
+                    if let Some(entry) = queue.leased.remove(&id) {
+                        queue.push_front(entry);
+                    }
+                }
+            },
+
+            Event::Remove(name, id) => {
+                if let Some(queue) = self.queues.get_mut(&name) {
+                    if queue.take(&id).is_none() { queue.delayed.remove(&id); }
+                }
+            },
+
+            Event::Purge(name, count) => {
+                if let Some(queue) = self.queues.get_mut(&name) {
+                    for _ in 0..count { queue.pop_front(); }
+                }
+            },
+
+            Event::AlterQueue(name, options) => {
+                if let Some(queue) = self.queues.get_mut(&name) {
+                    queue.options = options;
+                }
+            },
+
+            Event::Confirm(name, id) => {
+                if let Some(queue) = self.queues.get_mut(&name) {
+                    queue.leased.remove(&id);
+                }
+            },
+
+            Event::Bind(topic, queue, pattern) => {
+                let bindings = self.topics.entry(topic).or_insert_with(Vec::new);
+                let binding = (queue, pattern);
+                if !bindings.contains(&binding) { bindings.push(binding) }
+            },
+
+            Event::Unbind(topic, queue) => self.unbind(&topic, &queue)
+        }
+    }
+
+    /// Unbind a queue from a topic, removing the topic once no queues are
+    /// bound to it.
+    fn unbind(&mut self, topic: &str, queue: &str) {
+        let empty = match self.topics.get_mut(topic) {
+            Some(bindings) => {
+                bindings.retain(|&(ref name, _)| name != queue);
+                bindings.is_empty()
+            },
+            None => false
+        };
+
+        if empty { self.topics.remove(topic); }
+    }
+}
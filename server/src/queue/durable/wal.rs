@@ -0,0 +1,359 @@
+use bincode::{self, SizeLimit, DecodingResult};
+use rustc_serialize::Encodable;
+use uuid::Uuid;
+
+use common::QueueOptions;
+use queue::Entry;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use {Result, Error};
+
+/// The default maximum size of a single log segment, 64 MiB.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The default number of segments written between automatic snapshots.
+pub const DEFAULT_SNAPSHOT_EVERY: u64 = 16;
+
+/// The version of the format of log segments and snapshots, bumped whenever
+/// the encoding of an Event or of the snapshotted State changes.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The length of the header starting every log segment and snapshot: a
+/// magic number telling them apart, then the format version.
+pub const HEADER_LEN: usize = 8;
+
+pub const SEGMENT_MAGIC: &'static [u8] = b"DBQL";
+pub const SNAPSHOT_MAGIC: &'static [u8] = b"DBQS";
+
+/// How often the write-ahead log is flushed to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Call fsync after every event is written.
+    ///
+    /// This is the slowest policy, but no acknowledged operation can
+    /// be lost, even if the machine goes down.
+    Always,
+
+    /// Call fsync after every `n` events are written.
+    Batched(usize),
+
+    /// Never call fsync, and leave flushing to the operating system.
+    ///
+    /// Events are still written out of our own buffers immediately, so
+    /// they survive a crash of the server process, but not of the machine.
+    Never
+}
+
+/// A single operation recorded in the log.
+///
+/// Events are always read back with their entries, but can be written with
+/// references to them, as an `Event<&Entry>`, which encodes the same way.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum Event<E = Entry> {
+    CreateQueue(String, QueueOptions),
+    DeleteQueue(String),
+    Enqueue(String, E),
+    Dequeue(String, Uuid),
+    Requeue(String, Uuid),
+    Confirm(String, Uuid),
+    Bind(String, String, String),
+    Unbind(String, String),
+
+    /// An entry was removed from a queue without being read, or a delayed
+    /// entry was discarded before it was enqueued.
+    Remove(String, Uuid),
+
+    /// The options of a queue were replaced.
+    AlterQueue(String, QueueOptions),
+
+    /// An entry was held back, and will be logged again as enqueued once
+    /// its delay elapses.
+    Delay(String, E),
+
+    /// The given number of entries were removed from the front of a queue
+    /// without being read, which was every entry waiting to be read.
+    Purge(String, usize)
+}
+
+/// Encode an event as it is written to the log.
+pub fn encode<E: Encodable>(event: &Event<E>) -> Result<Vec<u8>> {
+    Ok(try!(bincode::encode(event, SizeLimit::Infinite)))
+}
+
+/// An append-only log of Events, split over several segment files
+/// in a single directory.
+///
+/// Segments are numbered, and a new segment is started whenever the
+/// current segment grows past the configured segment size, or the log
+/// is reopened.
+pub struct Wal {
+    /// The directory holding all segments.
+    dir: PathBuf,
+
+    /// The first segment created by this Wal.
+    ///
+    /// All segments before this one were written before the log was
+    /// opened, and are the ones replayed during recovery.
+    first: u64,
+
+    /// The segment currently being written to.
+    segment: u64,
+
+    /// The file of the current segment.
+    file: File,
+
+    /// The number of bytes written to the current segment.
+    written: u64,
+
+    /// The number of events written since the last fsync.
+    unsynced: usize,
+
+    policy: SyncPolicy,
+    segment_size: u64,
+
+    /// The first segment not included in the latest snapshot taken since
+    /// the log was opened.
+    snapshotted: u64,
+
+    /// How many segments to write between snapshots, if snapshots should be
+    /// taken automatically.
+    snapshot_every: Option<u64>
+}
+
+impl Wal {
+    /// Open the log in the passed directory, creating the directory if needed.
+    pub fn open(dir: &Path, policy: SyncPolicy, segment_size: u64,
+                snapshot_every: Option<u64>) -> Result<Wal> {
+        try!(fs::create_dir_all(dir));
+
+        // Only the segment which was being written when the server stopped
+        // can end in a torn write.
+        if let Some(&(_, ref path)) = try!(numbered(dir, "log")).last() {
+            try!(repair(path));
+        }
+
+        // Never reuse the number of an existing segment, or of a segment
+        // already included in a snapshot.
+        let first = ::std::cmp::max(
+            try!(numbered(dir, "log")).last().map(|&(n, _)| n + 1).unwrap_or(0),
+            try!(numbered(dir, "snapshot")).last().map(|&(n, _)| n).unwrap_or(0));
+        let file = try!(create_segment(dir, first));
+
+        Ok(Wal {
+            dir: dir.to_path_buf(),
+            first: first,
+            segment: first,
+            file: file,
+            written: HEADER_LEN as u64,
+            unsynced: 0,
+            policy: policy,
+            segment_size: segment_size,
+            snapshotted: first,
+            snapshot_every: snapshot_every
+        })
+    }
+
+    /// Append an event to the log, syncing according to the sync policy.
+    pub fn append(&mut self, event: &Event) -> Result<()> {
+        let bytes = try!(encode(event));
+        self.append_encoded(&bytes)
+    }
+
+    /// Append an event already encoded by `encode`, as for `append`.
+    pub fn append_encoded(&mut self, bytes: &[u8]) -> Result<()> {
+        if let Err(e) = self.file.write_all(bytes) {
+            // Cut off whatever part of the event was written, so the next
+            // event does not follow a torn one.
+            try!(self.file.set_len(self.written));
+            try!(self.file.seek(SeekFrom::Start(self.written)));
+            return Err(Error::from(e))
+        }
+
+        self.written += bytes.len() as u64;
+        self.unsynced += 1;
+
+        match self.policy {
+            SyncPolicy::Always => try!(self.sync()),
+            SyncPolicy::Batched(n) if self.unsynced >= n => try!(self.sync()),
+            _ => {}
+        }
+
+        if self.written >= self.segment_size {
+            try!(self.rotate());
+        }
+
+        Ok(())
+    }
+
+    /// Flush the current segment to stable storage.
+    pub fn sync(&mut self) -> Result<()> {
+        try!(self.file.sync_data());
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Close the current segment and start writing to a new one.
+    pub fn rotate(&mut self) -> Result<()> {
+        try!(self.sync());
+        self.file = try!(create_segment(&self.dir, self.segment + 1));
+        self.segment += 1;
+        self.written = HEADER_LEN as u64;
+        Ok(())
+    }
+
+    /// The directory holding the log.
+    pub fn dir(&self) -> &Path { &self.dir }
+
+    /// The first segment created by this Wal.
+    ///
+    /// All segments before this one were written before the log was
+    /// opened, and are the ones replayed during recovery.
+    pub fn first(&self) -> u64 { self.first }
+
+    /// The segment currently being written to.
+    pub fn segment(&self) -> u64 { self.segment }
+
+    /// Record that all segments before `next` are now part of a snapshot.
+    pub fn snapshotted(&mut self, next: u64) { self.snapshotted = next }
+
+    /// Whether enough segments have been written since the last snapshot
+    /// that a new one should be taken.
+    pub fn snapshot_due(&self) -> bool {
+        self.snapshot_every.map_or(false, |every| self.segment - self.snapshotted >= every)
+    }
+}
+
+/// Feed every event in the segments numbered from `from` up to but not
+/// including `to` to `apply`, in the order they were written.
+///
+/// Torn writes are cut off when the log is opened, so any bytes which
+/// cannot be decoded mean the log is corrupt, and an error is returned
+/// rather than silently losing the events after them.
+pub fn replay<F>(dir: &Path, from: u64, to: u64, mut apply: F) -> Result<()>
+where F: FnMut(Event) {
+    for (n, path) in try!(numbered(dir, "log")) {
+        if n < from || n >= to { continue }
+
+        let (events, end, len) = try!(read_segment(&path));
+        if end < len {
+            error!("Log segment {:?} is corrupt after {} of {} bytes", path, end, len);
+            return Err(Error::Corrupt(path))
+        }
+
+        for event in events { apply(event) }
+    }
+
+    Ok(())
+}
+
+/// Read every event which can be decoded from the start of a segment.
+///
+/// Returns the events, how many bytes they and the header take up and the
+/// length of the whole segment.
+fn read_segment(path: &Path) -> Result<(Vec<Event>, u64, u64)> {
+    let mut buf = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut buf));
+
+    // The server may have stopped while writing the header of a new segment.
+    if buf.len() < HEADER_LEN && header(SEGMENT_MAGIC).starts_with(&buf) {
+        return Ok((Vec::new(), 0, buf.len() as u64))
+    }
+
+    try!(check_header(path, &buf, SEGMENT_MAGIC));
+
+    let mut events = Vec::new();
+    let mut pos = HEADER_LEN;
+    while pos < buf.len() {
+        let decoded: DecodingResult<(Event, u64)> = bincode::decode(&buf[pos..]);
+        match decoded {
+            Ok((event, len)) => {
+                events.push(event);
+                pos += len as usize;
+            },
+            Err(_) => break
+        }
+    }
+
+    Ok((events, pos as u64, buf.len() as u64))
+}
+
+/// Cut off a torn write at the end of a segment, which is expected if
+/// the server crashed while writing to it.
+fn repair(path: &Path) -> Result<()> {
+    let (_, end, len) = try!(read_segment(path));
+    if end < len {
+        warn!("Discarding {} trailing bytes of log segment {:?}", len - end, path);
+        try!(try!(OpenOptions::new().write(true).open(path)).set_len(end));
+    }
+
+    Ok(())
+}
+
+/// Delete every segment numbered before `before`.
+pub fn remove_segments(dir: &Path, before: u64) -> Result<()> {
+    for (n, path) in try!(numbered(dir, "log")) {
+        if n < before { try!(fs::remove_file(&path)) }
+    }
+
+    Ok(())
+}
+
+/// Get the number and path of every file in the directory named with
+/// a number and the passed extension, in order.
+pub fn numbered(dir: &Path, extension: &str) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+
+        if path.extension().and_then(|ext| ext.to_str()) != Some(extension) { continue }
+
+        if let Some(n) = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok()) {
+            files.push((n, path.clone()));
+        }
+    }
+
+    files.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+    Ok(files)
+}
+
+fn segment_path(dir: &Path, n: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", n))
+}
+
+fn create_segment(dir: &Path, n: u64) -> Result<File> {
+    let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true)
+                            .open(&segment_path(dir, n)));
+    try!(file.write_all(&header(SEGMENT_MAGIC)));
+    Ok(file)
+}
+
+/// The header starting every file with this magic number.
+pub fn header(magic: &[u8]) -> Vec<u8> {
+    let mut header = magic.to_vec();
+    header.extend((0..4).map(|i| (FORMAT_VERSION >> (8 * i)) as u8));
+    header
+}
+
+/// Check that the contents of the file at `path` start with the header of
+/// the current format and this magic number.
+pub fn check_header(path: &Path, buf: &[u8], magic: &[u8]) -> Result<()> {
+    if buf.len() < HEADER_LEN || &buf[..magic.len()] != magic {
+        return Err(Error::UnknownFormat(path.to_path_buf(), None))
+    }
+
+    let version = buf[magic.len()..HEADER_LEN].iter().rev()
+        .fold(0, |version, &byte| (version << 8) | byte as u32);
+    if version != FORMAT_VERSION {
+        error!("{:?} was written in version {} of the log format, not version {}",
+               path, version, FORMAT_VERSION);
+        return Err(Error::UnknownFormat(path.to_path_buf(), Some(version)))
+    }
+
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use uuid::Uuid;
+
+use common::QueueOptions;
+use queue::{self, Entry, Counters, Expired, Memory, Ages};
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+
+/// The order in which a queue keeps its entries.
+pub trait Order: Default {
+    /// Add an entry behind every entry dequeued before it.
+    fn push_back(&mut self, entry: Entry);
+
+    /// Add an entry in front of every entry dequeued after it.
+    fn push_front(&mut self, entry: Entry);
+
+    /// Remove the next entry to be dequeued.
+    fn pop(&mut self) -> Option<Entry>;
+
+    /// Remove the entry with the given id, wherever it is.
+    fn remove(&mut self, id: &Uuid) -> Option<Entry>;
+
+    /// Call `visit` with each entry, in the order they would be dequeued,
+    /// until it returns false.
+    fn visit<F: FnMut(&Entry) -> bool>(&self, visit: F);
+}
+
+/// Entries dequeued in the order they were enqueued.
+#[derive(Debug, Default)]
+pub struct Plain(VecDeque<Entry>);
+
+impl Order for Plain {
+    fn push_back(&mut self, entry: Entry) { self.0.push_back(entry) }
+
+    fn push_front(&mut self, entry: Entry) { self.0.push_front(entry) }
+
+    fn pop(&mut self) -> Option<Entry> { self.0.pop_front() }
+
+    fn remove(&mut self, id: &Uuid) -> Option<Entry> {
+        let position = self.0.iter().position(|entry| entry.id == *id);
+        position.and_then(|position| self.0.remove(position))
+    }
+
+    fn visit<F: FnMut(&Entry) -> bool>(&self, mut visit: F) {
+        for entry in self.0.iter() {
+            if !visit(entry) { break }
+        }
+    }
+}
+
+/// The entries of a queue, kept in the order `O` within the capacity and
+/// options of the queue, along with the total length of their data, which
+/// is also counted in the memory of their set of queues.
+///
+/// Every queue keeps its entries in a Fifo behind its own lock, and only
+/// raises its signals itself, so the limits, overflow policies and expiry
+/// of entries work the same whichever order the entries are kept in.
+#[derive(Debug)]
+pub struct Fifo<O: Order> {
+    entries: O,
+    len: usize,
+    bytes: u64,
+    ages: Ages,
+
+    /// The most entries the queue holds, whatever its options.
+    capacity: usize,
+    options: QueueOptions,
+    counters: Counters,
+    expired: Expired,
+    memory: Memory
+}
+
+impl<O: Order> Fifo<O> {
+    /// An empty queue holding up to `capacity` entries, which counts its
+    /// operations in `counters`, holds the entries it skips because they
+    /// expired in `expired`, and counts its entries in `memory`.
+    pub fn new(capacity: usize, options: QueueOptions, counters: Counters, expired: Expired,
+               memory: Memory) -> Fifo<O> {
+        Fifo {
+            entries: Default::default(),
+            len: 0,
+            bytes: 0,
+            ages: Ages::default(),
+            capacity: capacity,
+            options: options,
+            counters: counters,
+            expired: expired,
+            memory: memory
+        }
+    }
+
+    /// Enqueue an entry at the back, if it fits.
+    pub fn enqueue(&mut self, entry: Entry) -> Result<(), Entry> {
+        if !self.has_room(entry.data.len()) { return Err(entry) }
+
+        self.push_back(entry);
+        self.counters.enqueue(1);
+        Ok(())
+    }
+
+    /// Enqueue an entry at the back as for `Queue::offer`, discarding the
+    /// entries at the front to make room for it if the queue drops its
+    /// oldest entries, and returning them.
+    pub fn offer(&mut self, entry: Entry) -> Result<Vec<Entry>, Entry> {
+        let size = entry.data.len();
+        let mut dropped = Vec::new();
+
+        if !self.has_room(size) {
+            if !queue::drops_oldest(&self.options, &entry) { return Err(entry) }
+
+            while !self.has_room(size) {
+                match self.pop() {
+                    Some(oldest) => dropped.push(oldest),
+                    None => break
+                }
+            }
+        }
+
+        self.push_back(entry);
+        self.counters.enqueue(1);
+        self.counters.discard(dropped.len());
+        Ok(dropped)
+    }
+
+    /// Put a dequeued entry back at the front.
+    ///
+    /// Requeued entries were already let in, so only the capacity of the
+    /// queue applies to them, not the limits in its options.
+    pub fn requeue(&mut self, entry: Entry) -> Result<(), Entry> {
+        if self.len >= self.capacity { return Err(entry) }
+
+        self.push_front(entry);
+        self.counters.requeue();
+        Ok(())
+    }
+
+    /// Enqueue entries in order until one does not fit, returning it along
+    /// with every entry after it.
+    pub fn enqueue_batch(&mut self, entries: Vec<Entry>) -> Vec<Entry> {
+        let mut rest = Vec::new();
+        let mut queued = 0;
+        let mut entries = entries.into_iter();
+
+        while let Some(entry) = entries.next() {
+            if !self.has_room(entry.data.len()) {
+                rest.push(entry);
+                rest.extend(entries.by_ref());
+                break
+            }
+
+            self.push_back(entry);
+            queued += 1;
+        }
+
+        self.counters.enqueue(queued);
+        rest
+    }
+
+    /// Dequeue up to `max` entries whose time-to-live has not elapsed, along
+    /// with how many entries left the queue.
+    ///
+    /// Expired entries popped along the way are counted as dequeued and
+    /// finished with, and held until they are taken with `Queue::expired`.
+    pub fn dequeue_batch(&mut self, max: usize) -> (Vec<Entry>, usize) {
+        let now = queue::now_ms();
+        let mut entries = Vec::new();
+        let mut skipped = Vec::new();
+
+        while entries.len() < max {
+            match self.pop() {
+                Some(entry) => if entry.expired(now) {
+                    skipped.push(entry)
+                } else {
+                    entries.push(entry)
+                },
+                None => break
+            }
+        }
+
+        let popped = entries.len() + skipped.len();
+        self.counters.dequeue(popped);
+        for _ in 0..skipped.len() { self.counters.confirm() }
+        self.expired.hold(skipped);
+        (entries, popped)
+    }
+
+    /// Move up to `count` entries from the front to the back of `to`, as for
+    /// `Queue::move_to`, returning copies of the moved entries along with how
+    /// many entries left the queue.
+    ///
+    /// The first entry which does not fit in `to` stays at the front, and
+    /// expired entries are skipped as for `dequeue_batch`.
+    pub fn move_to<P: Order>(&mut self, to: &mut Fifo<P>, count: usize) -> (Vec<Entry>, usize) {
+        let now = queue::now_ms();
+        let mut moved = Vec::new();
+        let mut skipped = Vec::new();
+
+        while moved.len() < count {
+            let mut entry = match self.pop() {
+                Some(entry) => entry,
+                None => break
+            };
+
+            if entry.expired(now) {
+                skipped.push(entry);
+                continue
+            }
+
+            // It just left this queue, so there is always room to put it back.
+            if !to.has_room(entry.data.len()) {
+                self.push_front(entry);
+                break
+            }
+
+            // Moved entries start afresh, so objects moved out of a
+            // dead-letter queue can be delivered again.
+            entry.deliveries = 0;
+            moved.push(entry.clone());
+            to.push_back(entry);
+        }
+
+        let popped = moved.len() + skipped.len();
+        self.counters.dequeue(skipped.len());
+        for _ in 0..skipped.len() { self.counters.confirm() }
+        self.expired.hold(skipped);
+        to.counters.enqueue(moved.len());
+        (moved, popped)
+    }
+
+    /// Remove up to `max` entries from the front, without reading them.
+    pub fn take(&mut self, max: usize) -> Vec<Entry> {
+        let len = cmp::min(max, self.len);
+        (0..len).filter_map(|_| self.pop()).collect()
+    }
+
+    /// Remove the entry with the given id, wherever it is.
+    pub fn remove(&mut self, id: &Uuid) -> Option<Entry> {
+        let entry = self.entries.remove(id);
+        if let Some(ref entry) = entry { self.removed(entry) }
+        entry
+    }
+
+    /// Call `visit` with each entry, from the front, until it returns false.
+    pub fn visit<F: FnMut(&Entry) -> bool>(&self, visit: F) {
+        self.entries.visit(visit)
+    }
+
+    pub fn options(&self) -> &QueueOptions { &self.options }
+
+    /// Replace the options of the queue, keeping every entry already queued.
+    pub fn alter(&mut self, options: QueueOptions) { self.options = options }
+
+    /// When the entry which has been ready the longest became ready.
+    pub fn oldest(&self) -> Option<u64> { self.ages.oldest() }
+
+    pub fn len(&self) -> usize { self.len }
+
+    /// The total length of the data of every entry.
+    pub fn bytes(&self) -> u64 { self.bytes }
+
+    /// Whether another entry with `size` bytes of data fits in the queue.
+    fn has_room(&self, size: usize) -> bool {
+        queue::has_room(&self.options, self.capacity, self.len, self.bytes, size)
+    }
+
+    fn push_back(&mut self, entry: Entry) {
+        self.added(&entry);
+        self.entries.push_back(entry);
+    }
+
+    fn push_front(&mut self, entry: Entry) {
+        self.added(&entry);
+        self.entries.push_front(entry);
+    }
+
+    fn pop(&mut self) -> Option<Entry> {
+        let entry = self.entries.pop();
+        if let Some(ref entry) = entry { self.removed(entry) }
+        entry
+    }
+
+    fn added(&mut self, entry: &Entry) {
+        self.len += 1;
+        self.bytes += entry.data.len() as u64;
+        self.ages.add(entry.enqueued);
+        self.memory.queue(entry.data.len());
+    }
+
+    fn removed(&mut self, entry: &Entry) {
+        self.len -= 1;
+        self.bytes -= entry.data.len() as u64;
+        self.ages.remove(entry.enqueued);
+        self.memory.unqueue(entry.data.len());
+    }
+}
+
+/// Lock the entries of two queues, always in the same order whichever is
+/// passed first, so two threads moving entries between the same queues in
+/// opposite directions can not deadlock.
+///
+/// Returns None if both are the same queue.
+pub fn lock_both<'a, O: Order>(a: &'a Mutex<Fifo<O>>, b: &'a Mutex<Fifo<O>>)
+        -> Option<(MutexGuard<'a, Fifo<O>>, MutexGuard<'a, Fifo<O>>)> {
+    let first = a as *const Mutex<Fifo<O>> as usize;
+    let second = b as *const Mutex<Fifo<O>> as usize;
+
+    if first == second {
+        None
+    } else if first < second {
+        let a = a.lock().unwrap();
+        Some((a, b.lock().unwrap()))
+    } else {
+        let b = b.lock().unwrap();
+        Some((a.lock().unwrap(), b))
+    }
+}
+
+impl<O: Order> Drop for Fifo<O> {
+    /// Stop counting the entries of a deleted queue.
+    fn drop(&mut self) {
+        self.memory.unqueue(self.bytes as usize);
+    }
+}
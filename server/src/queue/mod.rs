@@ -1,20 +1,834 @@
 use uuid::Uuid;
+use mio::Token;
+
+use common::{QueueOptions, QueueStats, MemoryStats, RequestId, Overflow};
+use lease::Leases;
+use topic::Topics;
+use wait::Waiter;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{cmp, fmt, mem, usize};
+
+use time;
 
 pub mod rcqueue;
 pub mod concurrent;
+pub mod priority;
+pub mod durable;
+
+/// The entries of the queues, kept within their limits in either FIFO or
+/// priority order.
+mod fifo;
 
 pub trait Queues: Clone + Send + 'static {
     type Queue: Queue;
 
-    fn insert(&self, name: String);
+    /// Create a new queue with the passed options, unless it already exists.
+    fn insert(&self, name: String, options: QueueOptions);
     fn remove(&self, name: &str) -> Option<Self::Queue>;
 
     fn queue(&self, name: &str) -> Option<Self::Queue>;
+
+    /// The names of every queue, in no particular order.
+    fn names(&self) -> Vec<String>;
+
+    /// The objects read from these queues which are not confirmed yet, if
+    /// they are shared by every clone of these queues, so objects can be
+    /// confirmed through any connection to any Server using them.
+    ///
+    /// Otherwise each Server keeps its own leases, and objects can only be
+    /// confirmed through the Server which read them.
+    fn leases(&self) -> Option<&Leases<Self::Queue>> { None }
+
+    /// The topics of these queues, and the queues bound to them, if they
+    /// support publishing.
+    ///
+    /// Shared by every clone of these queues. Servers using queues without
+    /// topics do not agree to the topics and routing features.
+    fn topics(&self) -> Option<&Topics> { None }
+
+    /// The memory held by these queues, the objects leased from them, and
+    /// the servers and connections using them, if they keep count of it.
+    ///
+    /// Shared by every clone of these queues, so every Server using them
+    /// keeps within one budget. Servers using queues which do not keep count
+    /// count everything but the queued objects themselves.
+    fn memory(&self) -> Option<&Memory> { None }
+
+    /// Bind the named queue to a topic with a routing key pattern, returning
+    /// false if it was already bound with the same pattern.
+    fn bind(&self, topic: String, queue: String, pattern: String) -> bool {
+        self.topics().map(|topics| topics.bind(topic, queue, pattern)).unwrap_or(false)
+    }
+
+    /// Unbind the named queue from a topic, whatever patterns it was bound
+    /// with, returning false if it was not bound.
+    fn unbind(&self, topic: &str, queue: &str) -> bool {
+        self.topics().map(|topics| topics.unbind(topic, queue)).unwrap_or(false)
+    }
+
+    /// Move up to `count` entries from the front of one queue to the back of
+    /// another, as for `Queue::move_to`, returning how many were moved, or the
+    /// name of the first queue which does not exist.
+    fn move_entries(&self, from: &str, to: &str, count: usize) -> Result<usize, String> {
+        match (self.queue(from), self.queue(to)) {
+            (Some(source), Some(target)) => Ok(source.move_to(&target, count).len()),
+            (None, _) => Err(from.to_string()),
+            (_, None) => Err(to.to_string())
+        }
+    }
+
+    /// Restore any state persisted by a previous run of the server.
+    ///
+    /// Called once by every Server started with these queues, before it
+    /// begins accepting connections.
+    fn recover(&self) -> ::Result<()> { Ok(()) }
+
+    /// Take the entries which were held back by a previous run of the server
+    /// and are still to be enqueued once their delay elapses, along with the
+    /// names of their queues.
+    ///
+    /// Called by every Server after `recover`, so only the first Server
+    /// started with these queues delivers them.
+    fn delayed(&self) -> Vec<(String, Entry)> { Vec::new() }
 }
 
 pub trait Queue: Clone + Send + 'static {
-    fn enqueue(&self, id: Uuid, data: Vec<u8>) -> Result<(), (Uuid, Vec<u8>)>;
-    fn requeue(&self, id: Uuid, data: Vec<u8>) -> Result<(), (Uuid, Vec<u8>)>;
-    fn dequeue(&self) -> Option<(Uuid, Vec<u8>)>;
+    fn enqueue(&self, entry: Entry) -> Result<(), Entry>;
+    fn requeue(&self, entry: Entry) -> Result<(), Entry>;
+
+    /// Enqueue a new entry sent by a client, as for `enqueue`, but if the
+    /// queue is full and its overflow policy is DropOldest, first discard
+    /// entries from the front of the queue until it fits, returning them.
+    ///
+    /// Entries which could never fit are rejected without discarding
+    /// anything. Queues which can not make room as a single operation
+    /// always reject entries which do not fit.
+    fn offer(&self, entry: Entry) -> Result<Vec<Entry>, Entry> {
+        self.enqueue(entry).map(|()| Vec::new())
+    }
+
+    /// Remove the next entry, skipping over any entries whose time-to-live
+    /// has elapsed.
+    fn dequeue(&self) -> Option<Entry>;
+
+    /// Enqueue several entries in order, as a single operation.
+    ///
+    /// If the queue fills up part way through, or reaches the limits in its
+    /// options, the entries which did not fit are returned, and the entries
+    /// before them stay queued.
+    fn enqueue_batch(&self, entries: Vec<Entry>) -> Result<(), Vec<Entry>> {
+        let mut entries = entries.into_iter();
+
+        while let Some(entry) = entries.next() {
+            if let Err(entry) = self.enqueue(entry) {
+                let mut rest = vec![entry];
+                rest.extend(entries);
+                return Err(rest)
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Dequeue up to `max` entries, as a single operation, skipping expired
+    /// entries like `dequeue`.
+    fn dequeue_batch(&self, max: usize) -> Vec<Entry> {
+        let mut entries = Vec::new();
+
+        while entries.len() < max {
+            match self.dequeue() {
+                Some(entry) => entries.push(entry),
+                None => break
+            }
+        }
+
+        entries
+    }
+
+    /// Remove up to `count` entries, in the order they would be dequeued,
+    /// without reading them.
+    fn take(&self, count: usize) -> Vec<Entry>;
+
+    /// Move up to `count` entries from the front of this queue to the back
+    /// of `to`, as a single operation, returning copies of the moved entries.
+    ///
+    /// Moved entries start with no deliveries, so objects moved out of a
+    /// dead-letter queue can be delivered again. Moving stops at the first
+    /// entry which does not fit in `to`, which stays where it was. Expired
+    /// entries are skipped as for `dequeue`, and entries are never moved to
+    /// the queue they are in.
+    fn move_to(&self, to: &Self, count: usize) -> Vec<Entry>;
+
+    /// Remove a single entry without reading it.
+    ///
+    /// Returns None if the entry is not queued.
+    fn remove(&self, id: &Uuid) -> Option<Entry>;
+
+    /// Remove every entry without reading them, returning how many were
+    /// removed.
+    fn purge(&self) -> usize { self.take(usize::MAX).len() }
+
+    /// Call `visit` with each entry, in the order they would be dequeued,
+    /// until it returns false, leaving the entries queued.
+    ///
+    /// Queues which can not be looked inside visit no entries.
+    fn visit<F: FnMut(&Entry) -> bool>(&self, _: F) { }
+
+    /// Copies of up to `count` entries, in the order they would be dequeued,
+    /// after skipping the first `offset`.
+    ///
+    /// Expired entries are left out, since they will never be dequeued.
+    fn peek(&self, offset: usize, count: usize) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        let mut skipped = 0;
+        let now = now_ms();
+
+        self.visit(|entry| {
+            if entry.expired(now) { return true }
+
+            if skipped < offset {
+                skipped += 1;
+                return true
+            }
+
+            if entries.len() == count { return false }
+            entries.push(entry.clone());
+            true
+        });
+
+        entries
+    }
+
+    /// Copies of up to `count` entries which would be dequeued after the
+    /// entry with id `after`, or None if that entry is no longer queued.
+    ///
+    /// Expired entries are left out, as for `peek`.
+    fn browse(&self, after: &Uuid, count: usize) -> Option<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut found = false;
+        let now = now_ms();
+
+        self.visit(|entry| {
+            if !found {
+                found = entry.id == *after;
+                return true
+            }
+
+            if entry.expired(now) { return true }
+
+            if entries.len() == count { return false }
+            entries.push(entry.clone());
+            true
+        });
+
+        if found { Some(entries) } else { None }
+    }
+
+    /// Take the entries skipped by `dequeue` and `dequeue_batch` because
+    /// their time-to-live elapsed while they were queued.
+    ///
+    /// Skipped entries are held until they are taken, so that they can be
+    /// moved to the dead-letter queue of this queue.
+    fn expired(&self) -> Vec<Entry> { Vec::new() }
+
+    /// Called when a dequeued object has been confirmed, or otherwise
+    /// finished with, and will never be requeued.
+    fn confirm(&self, _: &Uuid) {
+        if let Some(counters) = self.counters() { counters.confirm() }
+    }
+
+    /// Called when an entry is held back, to be enqueued once its delay
+    /// elapses at the time it was `enqueued`.
+    fn delay(&self, _: &Entry) {
+        if let Some(counters) = self.counters() { counters.delay() }
+    }
+
+    /// Called when an entry held back by `delay` is discarded, or moved to
+    /// another queue, instead of being enqueued on this one.
+    fn discard_delayed(&self, _: &Uuid) {
+        if let Some(counters) = self.counters() { counters.deliver() }
+    }
+
+    /// The options this queue was created with, or last altered to.
+    fn options(&self) -> QueueOptions;
+
+    /// Replace the options of this queue.
+    ///
+    /// Entries already queued are kept, even if they no longer fit.
+    fn alter(&self, options: QueueOptions);
+
+    /// The number of objects ready to be read.
+    fn len(&self) -> usize {
+        let mut len = 0;
+        self.visit(|_| { len += 1; true });
+        len
+    }
+
+    /// The total length of the data of the objects ready to be read.
+    fn bytes(&self) -> u64;
+
+    /// When the object which has been ready to read the longest became
+    /// ready, in milliseconds since the Unix epoch, or None if the queue is
+    /// empty.
+    fn oldest(&self) -> Option<u64> {
+        let mut oldest = None;
+        self.visit(|entry| {
+            oldest = Some(oldest.map_or(entry.enqueued, |oldest| cmp::min(oldest, entry.enqueued)));
+            true
+        });
+        oldest
+    }
+
+    /// The running totals of operations on this queue, shared by all of its
+    /// clones, if it keeps them.
+    fn counters(&self) -> Option<&Counters> { None }
+
+    /// The memory of the set of queues this queue belongs to.
+    fn memory(&self) -> &Memory;
+
+    /// A snapshot of the contents of this queue and the totals so far.
+    fn stats(&self) -> QueueStats {
+        let mut stats = self.counters().map(|counters| counters.stats())
+            .unwrap_or(QueueStats::default());
+        stats.depth = self.len() as u64;
+        stats.bytes = self.bytes();
+        stats.oldest = self.oldest().map(|since| now_ms().saturating_sub(since));
+        stats
+    }
+
+    /// The signal raised whenever objects are enqueued or requeued on this
+    /// queue, which hands them out to Reads waiting for objects.
+    ///
+    /// Reads on queues without a signal wait for their full wait timeout.
+    fn signal(&self) -> Option<&Signal> { None }
+
+    /// The signal raised whenever objects leave this queue, or its options
+    /// are altered, which hands out the room made to the producers held back
+    /// until the queue has room.
+    ///
+    /// Queues without one never hold back producers, and reject objects
+    /// which do not fit even if they block producers.
+    fn room(&self) -> Option<&Signal> { None }
+}
+
+/// Hands out the objects enqueued on a queue to the Reads waiting for them,
+/// which may belong to any of the Servers sharing the queue.
+///
+/// Each time the signal is raised, the Read which has been waiting longest
+/// is woken to take a single object. Reads which can take more wait again
+/// behind the rest, so objects are handed out in turn and no Read is starved
+/// by others on the same queue.
+///
+/// Only the Reads which wait take turns. Reads which do not wait dequeue
+/// objects directly, ahead of the line, and the Reads they leave without an
+/// object keep their place in it.
+///
+/// Raising the signal only takes its lock while Reads are waiting, so queues
+/// which are read without waiting pay for little more than an atomic add.
+///
+/// Queues which block producers hand out the room made in them the same way,
+/// using a second signal on which the producers held back wait instead.
+#[derive(Clone)]
+pub struct Signal(Arc<Shared>);
+
+struct Shared {
+    /// Incremented every time the signal is raised.
+    generation: AtomicUsize,
+
+    /// The number of Reads waiting, kept equal to the length of `waiting`.
+    ///
+    /// Reads update it before checking the generation, and the signal is
+    /// raised before checking it, so one of them always sees the other.
+    count: AtomicUsize,
+
+    waiters: Mutex<Waiters>
+}
+
+struct Waiters {
+    /// How many times the signal was raised for a Read which was woken, or
+    /// for an object which has since been read some other way.
+    ///
+    /// While Reads are waiting, this is always equal to the generation.
+    handed: u64,
+
+    /// The Reads to wake, in the order they will be woken.
+    waiting: VecDeque<Waiter>
+}
+
+impl Signal {
+    /// The number of times the signal has been raised so far.
+    pub fn generation(&self) -> u64 {
+        self.0.generation.load(Ordering::SeqCst) as u64
+    }
+
+    /// Whether any Reads are waiting for the signal to be raised.
+    pub fn is_waiting(&self) -> bool {
+        self.0.count.load(Ordering::SeqCst) > 0
+    }
+
+    /// Wait behind every other Read for the signal to be raised, after
+    /// finding the queue empty when it had been raised `seen` times.
+    ///
+    /// If the signal was raised since, the Read is woken straight away.
+    pub fn wait(&self, waiter: Waiter, seen: u64) {
+        self.park(waiter, Some(seen), false)
+    }
+
+    /// Wait ahead of every other Read, after being woken and finding that
+    /// another Read took the object first, when the signal had been raised
+    /// `seen` times.
+    pub fn wait_first(&self, waiter: Waiter, seen: u64) {
+        self.park(waiter, Some(seen), true)
+    }
+
+    /// Wait behind every other Read, after being woken and taking an object.
+    pub fn wait_again(&self, waiter: Waiter) {
+        self.park(waiter, None, false)
+    }
+
+    /// Raise the signal once for each of `count` objects enqueued, waking up
+    /// that many of the Reads which have been waiting longest.
+    pub fn notify_many(&self, count: usize) {
+        self.0.generation.fetch_add(count, Ordering::SeqCst);
+        if !self.is_waiting() { return }
+
+        let mut waiters = self.0.waiters.lock().unwrap();
+        self.0.hand_out(&mut waiters);
+    }
+
+    /// Raise the signal for a single object.
+    pub fn notify(&self) { self.notify_many(1) }
+
+    /// Raise the signal, waking up every waiting Read.
+    pub fn notify_all(&self) {
+        self.0.generation.fetch_add(1, Ordering::SeqCst);
+        if !self.is_waiting() { return }
+
+        let waiting = {
+            let mut waiters = self.0.waiters.lock().unwrap();
+            waiters.handed = self.generation();
+            self.0.count.store(0, Ordering::SeqCst);
+            mem::replace(&mut waiters.waiting, VecDeque::new())
+        };
+
+        // The event loop may have shut down, in which case nothing is waiting.
+        for waiter in waiting { waiter.wake(); }
+    }
+
+    /// Hand a wakeup which a Read could not use on to the next one.
+    pub fn pass(&self) {
+        let mut waiters = self.0.waiters.lock().unwrap();
+        waiters.handed = waiters.handed.saturating_sub(1);
+        self.0.hand_out(&mut waiters);
+    }
+
+    /// Stop waiting for a Read, identified by the id of its event loop, the
+    /// token of its connection, and the id of its request.
+    pub fn cancel(&self, event_loop: usize, token: Token, request: RequestId) {
+        if !self.is_waiting() { return }
+
+        let mut waiters = self.0.waiters.lock().unwrap();
+        let position = waiters.waiting.iter()
+            .position(|waiter| waiter.is(event_loop, token, request));
+
+        if let Some(position) = position {
+            waiters.waiting.remove(position);
+            self.0.count.store(waiters.waiting.len(), Ordering::SeqCst);
+        }
+    }
+
+    fn park(&self, waiter: Waiter, seen: Option<u64>, first: bool) {
+        let mut waiters = self.0.waiters.lock().unwrap();
+
+        // The objects enqueued before the queue was found empty were already
+        // read, so there is no point waking anyone for them.
+        if let Some(seen) = seen {
+            waiters.handed = cmp::max(waiters.handed, seen);
+        }
+
+        if first { waiters.waiting.push_front(waiter) } else { waiters.waiting.push_back(waiter) }
+        self.0.count.store(waiters.waiting.len(), Ordering::SeqCst);
+        self.0.hand_out(&mut waiters);
+    }
+}
+
+impl Shared {
+    /// Wake a waiting Read for every time the signal was raised without
+    /// one being woken.
+    fn hand_out(&self, waiters: &mut Waiters) {
+        while waiters.handed < self.generation.load(Ordering::SeqCst) as u64 {
+            match waiters.waiting.pop_front() {
+                // The event loop may have shut down, in which case try the next.
+                Some(waiter) => if waiter.wake() { waiters.handed += 1 },
+                None => break
+            }
+        }
+
+        self.count.store(waiters.waiting.len(), Ordering::SeqCst);
+    }
+}
+
+impl Default for Signal {
+    fn default() -> Signal {
+        Signal(Arc::new(Shared {
+            generation: AtomicUsize::new(0),
+            count: AtomicUsize::new(0),
+            waiters: Mutex::new(Waiters {
+                handed: 0,
+                waiting: VecDeque::new()
+            })
+        }))
+    }
 }
 
+impl fmt::Debug for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Signal({})", self.generation())
+    }
+}
+
+/// Running totals of the operations on a queue, along with the number of
+/// its objects which are leased or delayed, and so not in the queue itself.
+///
+/// Queues count the objects passing through them, and Leases count the
+/// objects they hold.
+#[derive(Clone)]
+pub struct Counters(Arc<Totals>);
+
+struct Totals {
+    enqueued: AtomicUsize,
+    dequeued: AtomicUsize,
+    confirmed: AtomicUsize,
+    requeued: AtomicUsize,
+    leased: AtomicUsize,
+    delayed: AtomicUsize,
+    dropped: AtomicUsize
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters(Arc::new(Totals {
+            enqueued: AtomicUsize::new(0),
+            dequeued: AtomicUsize::new(0),
+            confirmed: AtomicUsize::new(0),
+            requeued: AtomicUsize::new(0),
+            leased: AtomicUsize::new(0),
+            delayed: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0)
+        }))
+    }
+
+    /// Count `count` objects enqueued.
+    pub fn enqueue(&self, count: usize) { self.0.enqueued.fetch_add(count, Ordering::Relaxed); }
+
+    /// Count `count` objects dequeued.
+    pub fn dequeue(&self, count: usize) { self.0.dequeued.fetch_add(count, Ordering::Relaxed); }
+
+    /// Count an object finished with for good.
+    pub fn confirm(&self) { self.0.confirmed.fetch_add(1, Ordering::Relaxed); }
+
+    /// Count an object requeued.
+    pub fn requeue(&self) { self.0.requeued.fetch_add(1, Ordering::Relaxed); }
+
+    /// Count `count` objects discarded to make room for a new one.
+    pub fn discard(&self, count: usize) { self.0.dropped.fetch_add(count, Ordering::Relaxed); }
+
+    /// Count an object leased until it is confirmed or given back.
+    pub fn lease(&self) { self.0.leased.fetch_add(1, Ordering::Relaxed); }
+
+    /// Count the end of a lease counted by `lease`.
+    pub fn release(&self) { self.0.leased.fetch_sub(1, Ordering::Relaxed); }
+
+    /// Count an object held back until a delay elapses.
+    pub fn delay(&self) { self.0.delayed.fetch_add(1, Ordering::Relaxed); }
+
+    /// Count the end of a delay counted by `delay`.
+    pub fn deliver(&self) { self.0.delayed.fetch_sub(1, Ordering::Relaxed); }
+
+    /// The totals so far, without the depth, age or size of the queue.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: 0,
+            in_flight: self.0.leased.load(Ordering::Relaxed) as u64,
+            delayed: self.0.delayed.load(Ordering::Relaxed) as u64,
+            enqueued: self.0.enqueued.load(Ordering::Relaxed) as u64,
+            dequeued: self.0.dequeued.load(Ordering::Relaxed) as u64,
+            confirmed: self.0.confirmed.load(Ordering::Relaxed) as u64,
+            requeued: self.0.requeued.load(Ordering::Relaxed) as u64,
+            dropped: self.0.dropped.load(Ordering::Relaxed) as u64,
+            oldest: None,
+            bytes: 0
+        }
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Counters { Counters::new() }
+}
+
+impl fmt::Debug for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Counters({:?})", self.stats())
+    }
+}
+
+/// The entries skipped by the reads of a queue because their time-to-live
+/// elapsed, held until they are taken with `Queue::expired`.
+#[derive(Clone)]
+pub struct Expired(Arc<Mutex<Vec<Entry>>>);
+
+impl Expired {
+    pub fn new() -> Expired {
+        Expired(Arc::new(Mutex::new(Vec::new())))
+    }
+
+    /// Hold more skipped entries.
+    pub fn hold(&self, entries: Vec<Entry>) {
+        if !entries.is_empty() { self.0.lock().unwrap().extend(entries) }
+    }
+
+    /// Take every entry held so far.
+    pub fn take(&self) -> Vec<Entry> {
+        mem::replace(&mut *self.0.lock().unwrap(), Vec::new())
+    }
+}
+
+impl Default for Expired {
+    fn default() -> Expired { Expired::new() }
+}
+
+impl fmt::Debug for Expired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expired({})", self.0.lock().unwrap().len())
+    }
+}
+
+/// How many entries in a queue became ready at each time, so the queue can
+/// tell when its oldest entry became ready without looking at every entry,
+/// wherever requeued entries end up.
+#[derive(Debug, Default)]
+pub struct Ages(BTreeMap<u64, usize>);
+
+impl Ages {
+    pub fn add(&mut self, enqueued: u64) {
+        *self.0.entry(enqueued).or_insert(0) += 1;
+    }
+
+    pub fn remove(&mut self, enqueued: u64) {
+        let last = match self.0.get_mut(&enqueued) {
+            Some(count) => { *count -= 1; *count == 0 },
+            None => false
+        };
+
+        if last { self.0.remove(&enqueued); }
+    }
+
+    /// When the oldest entry became ready.
+    pub fn oldest(&self) -> Option<u64> {
+        self.0.keys().next().cloned()
+    }
+}
+
+/// The number of bytes of object data held by a set of queues, by the
+/// leases on objects read from them, by the responses waiting to be sent
+/// to their clients, and by the servers outside any queue, which servers
+/// keep within their memory budget.
+///
+/// Queues count the objects they hold, Leases count the objects leased, and
+/// connections count their responses. Objects on their way into a queue are
+/// held by the server, and count against its budget, from the time they are
+/// accepted until they are queued or dropped: delayed objects, objects being
+/// uploaded in chunks, and objects held back by full queues. So are read
+/// objects whose lease ended while their queue was full, until they are
+/// requeued or handed back.
+#[derive(Clone)]
+pub struct Memory(Arc<Usage>);
+
+struct Usage {
+    queued: AtomicUsize,
+    leased: AtomicUsize,
+    outgoing: AtomicUsize,
+    held: AtomicUsize,
+
+    /// The sum of every other count, so that reservations can be checked
+    /// against the budget and made at once.
+    total: AtomicUsize
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory(Arc::new(Usage {
+            queued: AtomicUsize::new(0),
+            leased: AtomicUsize::new(0),
+            outgoing: AtomicUsize::new(0),
+            held: AtomicUsize::new(0),
+            total: AtomicUsize::new(0)
+        }))
+    }
+
+    /// Count `bytes` of objects added to a queue.
+    pub fn queue(&self, bytes: usize) { self.add(&self.0.queued, bytes) }
+
+    /// Count `bytes` of objects removed from a queue.
+    pub fn unqueue(&self, bytes: usize) { self.sub(&self.0.queued, bytes) }
+
+    /// Count `bytes` of an object held by a lease.
+    pub fn lease(&self, bytes: usize) { self.add(&self.0.leased, bytes) }
+
+    /// Count the end of a lease counted by `lease`.
+    pub fn release(&self, bytes: usize) { self.sub(&self.0.leased, bytes) }
+
+    /// Count `bytes` of a response waiting to be sent.
+    pub fn send(&self, bytes: usize) { self.add(&self.0.outgoing, bytes) }
+
+    /// Count a response counted by `send` which was sent, or dropped.
+    pub fn sent(&self, bytes: usize) { self.sub(&self.0.outgoing, bytes) }
+
+    /// Count `bytes` of objects held outside any queue, whatever the budget.
+    pub fn hold(&self, bytes: usize) { self.add(&self.0.held, bytes) }
+
+    /// Count `bytes` of objects held outside any queue, if every byte
+    /// counted fits within `budget` with them, or `budget` is 0.
+    ///
+    /// Returns false, counting nothing, if they do not fit. Reservations
+    /// made at the same time cannot take the total over the budget between
+    /// them.
+    pub fn reserve(&self, bytes: usize, budget: u64) -> bool {
+        let mut total = self.0.total.load(Ordering::SeqCst);
+        loop {
+            if budget != 0 && (total + bytes) as u64 > budget { return false }
+
+            let previous = self.0.total.compare_and_swap(total, total + bytes,
+                                                         Ordering::SeqCst);
+            if previous == total { break }
+            total = previous;
+        }
+
+        self.0.held.fetch_add(bytes, Ordering::Relaxed);
+        true
+    }
+
+    /// Count objects counted by `hold` or `reserve` which were queued, or
+    /// dropped.
+    pub fn unhold(&self, bytes: usize) { self.sub(&self.0.held, bytes) }
+
+    /// The total number of bytes held.
+    pub fn total(&self) -> u64 { self.0.total.load(Ordering::SeqCst) as u64 }
+
+    /// The number of bytes held so far, without the budget of the server.
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            queued: self.0.queued.load(Ordering::Relaxed) as u64,
+            leased: self.0.leased.load(Ordering::Relaxed) as u64,
+            outgoing: self.0.outgoing.load(Ordering::Relaxed) as u64,
+            held: self.0.held.load(Ordering::Relaxed) as u64,
+            budget: 0
+        }
+    }
+
+    fn add(&self, count: &AtomicUsize, bytes: usize) {
+        count.fetch_add(bytes, Ordering::Relaxed);
+        self.0.total.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn sub(&self, count: &AtomicUsize, bytes: usize) {
+        count.fetch_sub(bytes, Ordering::Relaxed);
+        self.0.total.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Memory { Memory::new() }
+}
+
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Memory({:?})", self.stats())
+    }
+}
+
+/// An object held in a queue, along with its metadata.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Entry {
+    pub id: Uuid,
+    pub data: Vec<u8>,
+
+    /// The number of times this object has been read.
+    pub deliveries: u32,
+
+    /// When this object expires, in milliseconds since the Unix epoch.
+    pub expires: Option<u64>,
+
+    /// The priority of this object, higher priorities are read first from
+    /// queues which support them.
+    pub priority: u8,
+
+    /// When this object became ready to read, in milliseconds since the
+    /// Unix epoch. Requeueing it does not change this.
+    pub enqueued: u64
+}
+
+impl Entry {
+    /// Create a new entry, which has never been read.
+    pub fn new(id: Uuid, data: Vec<u8>) -> Entry {
+        Entry { id: id, data: data, deliveries: 0, expires: None, priority: 0, enqueued: now_ms() }
+    }
+
+    /// Whether this object's time-to-live had elapsed at `now`.
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires.map(|expires| expires <= now).unwrap_or(false)
+    }
+}
+
+/// Whether a queue holding `len` entries with `bytes` bytes of data between
+/// them, which can never hold more than `capacity` entries, has room under
+/// its options for another entry with `size` bytes of data.
+pub fn has_room(options: &QueueOptions, capacity: usize, len: usize, bytes: u64,
+                size: usize) -> bool {
+    let max_len = match options.max_len {
+        0 => capacity as u64,
+        max_len => cmp::min(max_len, capacity as u64)
+    };
+
+    (len as u64) < max_len && (options.max_bytes == 0 || bytes + size as u64 <= options.max_bytes)
+}
+
+/// Whether a queue which is full should discard the entries at the front
+/// to make room for an entry, under the options of the queue.
+///
+/// Entries which could never fit never make room.
+pub fn drops_oldest(options: &QueueOptions, entry: &Entry) -> bool {
+    options.overflow == Overflow::DropOldest &&
+        (options.max_bytes == 0 || entry.data.len() as u64 <= options.max_bytes)
+}
+
+/// Enqueue several entries in order as for `Queue::offer`, returning the
+/// entries which did not fit.
+pub fn offer_batch<Q: Queue>(queue: &Q, entries: Vec<Entry>) -> Result<(), Vec<Entry>> {
+    let rest = match queue.enqueue_batch(entries) {
+        Ok(()) => return Ok(()),
+        Err(rest) => rest
+    };
+
+    if queue.options().overflow != Overflow::DropOldest { return Err(rest) }
+
+    let mut rest = rest.into_iter();
+    while let Some(entry) = rest.next() {
+        if let Err(entry) = queue.offer(entry) {
+            let mut unqueued = vec![entry];
+            unqueued.extend(rest);
+            return Err(unqueued)
+        }
+    }
+
+    Ok(())
+}
+
+/// The current time, in milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    let now = time::get_time();
+    now.sec as u64 * 1000 + now.nsec as u64 / 1_000_000
+}
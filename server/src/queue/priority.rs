@@ -0,0 +1,417 @@
+use uuid::Uuid;
+
+use queue::{Queue, Queues, Entry, Signal, Counters, Memory, Expired};
+use queue::fifo::{self, Fifo, Order};
+use lease::Leases;
+use topic::Topics;
+use common::QueueOptions;
+
+use std::usize;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Entries grouped by priority, and kept in FIFO order within each priority.
+///
+/// Levels with no entries are removed, so the highest level always has an
+/// entry to dequeue.
+#[derive(Debug, Default)]
+struct Levels(BTreeMap<u8, VecDeque<Entry>>);
+
+impl Order for Levels {
+    fn push_back(&mut self, entry: Entry) {
+        self.0.entry(entry.priority).or_insert_with(VecDeque::new).push_back(entry);
+    }
+
+    fn push_front(&mut self, entry: Entry) {
+        self.0.entry(entry.priority).or_insert_with(VecDeque::new).push_front(entry);
+    }
+
+    /// Remove the oldest entry with the highest priority.
+    fn pop(&mut self) -> Option<Entry> {
+        let (priority, entry, empty) = match self.0.iter_mut().next_back() {
+            Some((&priority, level)) => (priority, level.pop_front(), level.is_empty()),
+            None => return None
+        };
+
+        if empty { self.0.remove(&priority); }
+        entry
+    }
+
+    fn remove(&mut self, id: &Uuid) -> Option<Entry> {
+        let mut found = None;
+        for (&priority, level) in self.0.iter_mut() {
+            if let Some(position) = level.iter().position(|entry| entry.id == *id) {
+                found = Some((priority, level.remove(position), level.is_empty()));
+                break
+            }
+        }
+
+        let (priority, entry, empty) = match found {
+            Some(found) => found,
+            None => return None
+        };
+
+        if empty { self.0.remove(&priority); }
+        entry
+    }
+
+    fn visit<F: FnMut(&Entry) -> bool>(&self, mut visit: F) {
+        for level in self.0.values().rev() {
+            for entry in level.iter() {
+                if !visit(entry) { return }
+            }
+        }
+    }
+}
+
+/// A single-threaded queue which returns entries with a higher priority
+/// before any entries with a lower priority, and otherwise in the order
+/// they were enqueued.
+#[derive(Clone, Debug)]
+pub struct RcPriorityQueue {
+    entries: Rc<RefCell<Fifo<Levels>>>,
+    signal: Signal,
+    room: Signal,
+    counters: Counters,
+    expired: Expired,
+    memory: Memory
+}
+
+#[derive(Clone, Default)]
+pub struct RcPriorityQueues {
+    queues: Rc<RefCell<HashMap<String, RcPriorityQueue>>>,
+    topics: Topics,
+    memory: Memory
+}
+
+// As with RcQueue, Server only uses these on the thread of a single event loop.
+unsafe impl Send for RcPriorityQueue { }
+unsafe impl Send for RcPriorityQueues { }
+
+impl Queues for RcPriorityQueues {
+    type Queue = RcPriorityQueue;
+
+    fn insert(&self, name: String, options: QueueOptions) {
+        self.queues.borrow_mut().entry(name)
+            .or_insert_with(|| RcPriorityQueue::create(options, self.memory.clone()));
+    }
+
+    fn remove(&self, name: &str) -> Option<RcPriorityQueue> {
+        self.queues.borrow_mut().remove(name)
+    }
+
+    fn queue(&self, name: &str) -> Option<RcPriorityQueue> {
+        self.queues.borrow().get(name).cloned()
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.queues.borrow().keys().cloned().collect()
+    }
+
+    fn topics(&self) -> Option<&Topics> { Some(&self.topics) }
+
+    fn memory(&self) -> Option<&Memory> { Some(&self.memory) }
+}
+
+impl RcPriorityQueue {
+    /// Create a new queue whose objects are counted in `memory`.
+    fn create(options: QueueOptions, memory: Memory) -> RcPriorityQueue {
+        let (counters, expired) = (Counters::new(), Expired::new());
+        let entries = Fifo::new(usize::MAX, options, counters.clone(), expired.clone(),
+                                memory.clone());
+
+        RcPriorityQueue {
+            entries: Rc::new(RefCell::new(entries)),
+            signal: Default::default(),
+            room: Default::default(),
+            counters: counters,
+            expired: expired,
+            memory: memory
+        }
+    }
+}
+
+impl Default for RcPriorityQueue {
+    fn default() -> RcPriorityQueue {
+        RcPriorityQueue::create(Default::default(), Memory::new())
+    }
+}
+
+impl Queue for RcPriorityQueue {
+    fn enqueue(&self, entry: Entry) -> Result<(), Entry> {
+        try!(self.entries.borrow_mut().enqueue(entry));
+        Ok(self.signal.notify())
+    }
+
+    fn offer(&self, entry: Entry) -> Result<Vec<Entry>, Entry> {
+        let dropped = try!(self.entries.borrow_mut().offer(entry));
+        self.signal.notify();
+        Ok(dropped)
+    }
+
+    fn requeue(&self, entry: Entry) -> Result<(), Entry> {
+        try!(self.entries.borrow_mut().requeue(entry));
+        Ok(self.signal.notify())
+    }
+
+    fn dequeue(&self) -> Option<Entry> {
+        self.dequeue_batch(1).pop()
+    }
+
+    fn enqueue_batch(&self, entries: Vec<Entry>) -> Result<(), Vec<Entry>> {
+        let count = entries.len();
+        let rest = self.entries.borrow_mut().enqueue_batch(entries);
+        self.signal.notify_many(count - rest.len());
+        if rest.is_empty() { Ok(()) } else { Err(rest) }
+    }
+
+    fn dequeue_batch(&self, max: usize) -> Vec<Entry> {
+        let (entries, left) = self.entries.borrow_mut().dequeue_batch(max);
+        self.room.notify_many(left);
+        entries
+    }
+
+    fn options(&self) -> QueueOptions {
+        self.entries.borrow().options().clone()
+    }
+
+    fn alter(&self, options: QueueOptions) {
+        self.entries.borrow_mut().alter(options);
+        self.room.notify_all();
+    }
+
+    fn take(&self, count: usize) -> Vec<Entry> {
+        let entries = self.entries.borrow_mut().take(count);
+        self.room.notify_many(entries.len());
+        entries
+    }
+
+    fn move_to(&self, to: &RcPriorityQueue, count: usize) -> Vec<Entry> {
+        // Entries are never moved to the queue they are in.
+        let source = &*self.entries as *const RefCell<Fifo<Levels>>;
+        if source == &*to.entries as *const RefCell<Fifo<Levels>> { return Vec::new() }
+
+        let (moved, popped) = {
+            let (mut from, mut into) = (self.entries.borrow_mut(), to.entries.borrow_mut());
+            from.move_to(&mut *into, count)
+        };
+
+        self.room.notify_many(popped);
+        to.signal.notify_many(moved.len());
+        moved
+    }
+
+    fn remove(&self, id: &Uuid) -> Option<Entry> {
+        let entry = self.entries.borrow_mut().remove(id);
+        if entry.is_some() { self.room.notify() }
+        entry
+    }
+
+    fn visit<F: FnMut(&Entry) -> bool>(&self, visit: F) {
+        self.entries.borrow().visit(visit)
+    }
+
+    fn len(&self) -> usize { self.entries.borrow().len() }
+
+    fn bytes(&self) -> u64 { self.entries.borrow().bytes() }
+
+    fn oldest(&self) -> Option<u64> { self.entries.borrow().oldest() }
+
+    fn expired(&self) -> Vec<Entry> { self.expired.take() }
+
+    fn counters(&self) -> Option<&Counters> { Some(&self.counters) }
+
+    fn memory(&self) -> &Memory { &self.memory }
+
+    fn signal(&self) -> Option<&Signal> { Some(&self.signal) }
+
+    fn room(&self) -> Option<&Signal> { Some(&self.room) }
+}
+
+/// A set of bounded priority queues which can be shared between Servers.
+#[derive(Clone)]
+pub struct ConcurrentPriorityQueues {
+    /// The capacity of newly created queues.
+    capacity: usize,
+    queues: Arc<RwLock<HashMap<String, ConcurrentPriorityQueue>>>,
+    leases: Leases<ConcurrentPriorityQueue>,
+    topics: Topics,
+    memory: Memory
+}
+
+impl ConcurrentPriorityQueues {
+    /// Create a new collection of queues, each of which can hold up to
+    /// `capacity` entries.
+    pub fn new(capacity: usize) -> ConcurrentPriorityQueues {
+        ConcurrentPriorityQueues {
+            capacity: capacity,
+            queues: Arc::new(RwLock::new(HashMap::new())),
+            leases: Leases::new(),
+            topics: Topics::new(),
+            memory: Memory::new()
+        }
+    }
+}
+
+impl Queues for ConcurrentPriorityQueues {
+    type Queue = ConcurrentPriorityQueue;
+
+    fn insert(&self, name: String, options: QueueOptions) {
+        self.queues.write().unwrap().entry(name)
+            .or_insert_with(|| ConcurrentPriorityQueue::create(self.capacity, options,
+                                                               self.memory.clone()));
+    }
+
+    fn remove(&self, name: &str) -> Option<ConcurrentPriorityQueue> {
+        self.queues.write().unwrap().remove(name)
+    }
+
+    fn queue(&self, name: &str) -> Option<ConcurrentPriorityQueue> {
+        self.queues.read().unwrap().get(name).cloned()
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.queues.read().unwrap().keys().cloned().collect()
+    }
+
+    fn leases(&self) -> Option<&Leases<ConcurrentPriorityQueue>> { Some(&self.leases) }
+
+    fn topics(&self) -> Option<&Topics> { Some(&self.topics) }
+
+    fn memory(&self) -> Option<&Memory> { Some(&self.memory) }
+}
+
+/// A bounded priority queue which can be shared between threads.
+///
+/// Like ConcurrentQueue, every operation takes a lock.
+#[derive(Clone)]
+pub struct ConcurrentPriorityQueue {
+    entries: Arc<Mutex<Fifo<Levels>>>,
+    signal: Signal,
+    room: Signal,
+    counters: Counters,
+    expired: Expired,
+    memory: Memory
+}
+
+impl ConcurrentPriorityQueue {
+    /// Create a new queue with the passed capacity.
+    pub fn new(capacity: usize) -> ConcurrentPriorityQueue {
+        ConcurrentPriorityQueue::with_options(capacity, Default::default())
+    }
+
+    /// Create a new queue with the passed capacity and options.
+    pub fn with_options(capacity: usize, options: QueueOptions) -> ConcurrentPriorityQueue {
+        ConcurrentPriorityQueue::create(capacity, options, Memory::new())
+    }
+
+    /// Create a new queue whose objects are counted in `memory`.
+    fn create(capacity: usize, options: QueueOptions,
+              memory: Memory) -> ConcurrentPriorityQueue {
+        let (counters, expired) = (Counters::new(), Expired::new());
+        let entries = Fifo::new(capacity, options, counters.clone(), expired.clone(),
+                                memory.clone());
+
+        ConcurrentPriorityQueue {
+            entries: Arc::new(Mutex::new(entries)),
+            signal: Default::default(),
+            room: Default::default(),
+            counters: counters,
+            expired: expired,
+            memory: memory
+        }
+    }
+}
+
+impl Queue for ConcurrentPriorityQueue {
+    fn enqueue(&self, entry: Entry) -> Result<(), Entry> {
+        try!(self.entries.lock().unwrap().enqueue(entry));
+        Ok(self.signal.notify())
+    }
+
+    /// Discard entries under the same lock as the enqueue, so no other
+    /// thread can take the room made.
+    fn offer(&self, entry: Entry) -> Result<Vec<Entry>, Entry> {
+        let dropped = try!(self.entries.lock().unwrap().offer(entry));
+        self.signal.notify();
+        Ok(dropped)
+    }
+
+    fn requeue(&self, entry: Entry) -> Result<(), Entry> {
+        try!(self.entries.lock().unwrap().requeue(entry));
+        Ok(self.signal.notify())
+    }
+
+    fn dequeue(&self) -> Option<Entry> {
+        self.dequeue_batch(1).pop()
+    }
+
+    fn enqueue_batch(&self, entries: Vec<Entry>) -> Result<(), Vec<Entry>> {
+        let count = entries.len();
+        let rest = self.entries.lock().unwrap().enqueue_batch(entries);
+        self.signal.notify_many(count - rest.len());
+        if rest.is_empty() { Ok(()) } else { Err(rest) }
+    }
+
+    fn dequeue_batch(&self, max: usize) -> Vec<Entry> {
+        let (entries, left) = self.entries.lock().unwrap().dequeue_batch(max);
+        self.room.notify_many(left);
+        entries
+    }
+
+    fn options(&self) -> QueueOptions {
+        self.entries.lock().unwrap().options().clone()
+    }
+
+    fn alter(&self, options: QueueOptions) {
+        self.entries.lock().unwrap().alter(options);
+        self.room.notify_all();
+    }
+
+    fn take(&self, count: usize) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap().take(count);
+        self.room.notify_many(entries.len());
+        entries
+    }
+
+    /// Move the entries under the locks of both queues, so other threads
+    /// never see them in neither queue or both, or take the room they fit in.
+    fn move_to(&self, to: &ConcurrentPriorityQueue, count: usize) -> Vec<Entry> {
+        let (moved, popped) = match fifo::lock_both(&*self.entries, &*to.entries) {
+            Some((mut from, mut into)) => from.move_to(&mut *into, count),
+            None => return Vec::new()
+        };
+
+        self.room.notify_many(popped);
+        to.signal.notify_many(moved.len());
+        moved
+    }
+
+    fn remove(&self, id: &Uuid) -> Option<Entry> {
+        let entry = self.entries.lock().unwrap().remove(id);
+        if entry.is_some() { self.room.notify() }
+        entry
+    }
+
+    fn visit<F: FnMut(&Entry) -> bool>(&self, visit: F) {
+        self.entries.lock().unwrap().visit(visit)
+    }
+
+    fn len(&self) -> usize { self.entries.lock().unwrap().len() }
+
+    fn bytes(&self) -> u64 { self.entries.lock().unwrap().bytes() }
+
+    fn oldest(&self) -> Option<u64> { self.entries.lock().unwrap().oldest() }
+
+    fn expired(&self) -> Vec<Entry> { self.expired.take() }
+
+    fn counters(&self) -> Option<&Counters> { Some(&self.counters) }
+
+    fn memory(&self) -> &Memory { &self.memory }
+
+    fn signal(&self) -> Option<&Signal> { Some(&self.signal) }
+
+    fn room(&self) -> Option<&Signal> { Some(&self.room) }
+}
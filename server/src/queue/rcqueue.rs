@@ -1,16 +1,32 @@
+use uuid::Uuid;
+
 use std::rc::Rc;
+use std::usize;
 use std::cell::RefCell;
-use std::collections::{VecDeque, HashMap};
-use queue::{Queue, Queues};
-use uuid::Uuid;
+use std::collections::HashMap;
+use queue::{Queue, Queues, Entry, Signal, Counters, Memory, Expired};
+use queue::fifo::{Fifo, Plain};
+use topic::Topics;
+use common::QueueOptions;
 
 /// In the single-threaded case, we can get away without the vast majority
 /// of synchronization overhead and use a simple ring buffer for our queue.
-#[derive(Clone, Debug, Default)]
-pub struct RcQueue(pub Rc<RefCell<VecDeque<(Uuid, Vec<u8>)>>>);
+#[derive(Clone, Debug)]
+pub struct RcQueue {
+    entries: Rc<RefCell<Fifo<Plain>>>,
+    signal: Signal,
+    room: Signal,
+    counters: Counters,
+    expired: Expired,
+    memory: Memory
+}
 
-#[derive(Clone, Debug, Default)]
-pub struct RcQueues(pub Rc<RefCell<HashMap<String, RcQueue>>>);
+#[derive(Clone, Default)]
+pub struct RcQueues {
+    queues: Rc<RefCell<HashMap<String, RcQueue>>>,
+    topics: Topics,
+    memory: Memory
+}
 
 // We lie to the compiler here about RcQueue's Send-ness, and will instead
 // use the public API of Server to prevent RcQueue from being shared
@@ -21,30 +37,137 @@ unsafe impl Send for RcQueues { }
 impl Queues for RcQueues {
     type Queue = RcQueue;
 
-    fn insert(&self, name: String) {
-        self.0.borrow_mut().entry(name).or_insert_with(Default::default);
+    fn insert(&self, name: String, options: QueueOptions) {
+        self.queues.borrow_mut().entry(name)
+            .or_insert_with(|| RcQueue::create(options, self.memory.clone()));
     }
 
     fn remove(&self, name: &str) -> Option<RcQueue> {
-        self.0.borrow_mut().remove(name)
+        self.queues.borrow_mut().remove(name)
     }
 
     fn queue(&self, name: &str) -> Option<RcQueue> {
-        self.0.borrow().get(name).cloned()
+        self.queues.borrow().get(name).cloned()
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.queues.borrow().keys().cloned().collect()
+    }
+
+    fn topics(&self) -> Option<&Topics> { Some(&self.topics) }
+
+    fn memory(&self) -> Option<&Memory> { Some(&self.memory) }
+}
+
+impl RcQueue {
+    /// Create a new queue whose objects are counted in `memory`.
+    fn create(options: QueueOptions, memory: Memory) -> RcQueue {
+        let (counters, expired) = (Counters::new(), Expired::new());
+        let entries = Fifo::new(usize::MAX, options, counters.clone(), expired.clone(),
+                                memory.clone());
+
+        RcQueue {
+            entries: Rc::new(RefCell::new(entries)),
+            signal: Default::default(),
+            room: Default::default(),
+            counters: counters,
+            expired: expired,
+            memory: memory
+        }
     }
 }
 
+impl Default for RcQueue {
+    fn default() -> RcQueue { RcQueue::create(Default::default(), Memory::new()) }
+}
+
 impl Queue for RcQueue {
-    fn enqueue(&self, id: Uuid, data: Vec<u8>) -> Result<(), (Uuid, Vec<u8>)> {
-        Ok(self.0.borrow_mut().push_back((id, data)))
+    fn enqueue(&self, entry: Entry) -> Result<(), Entry> {
+        try!(self.entries.borrow_mut().enqueue(entry));
+        Ok(self.signal.notify())
     }
 
-    fn requeue(&self, id: Uuid, data: Vec<u8>) -> Result<(), (Uuid, Vec<u8>)> {
-        Ok(self.0.borrow_mut().push_front((id, data)))
+    fn offer(&self, entry: Entry) -> Result<Vec<Entry>, Entry> {
+        let dropped = try!(self.entries.borrow_mut().offer(entry));
+        self.signal.notify();
+        Ok(dropped)
     }
 
-    fn dequeue(&self) -> Option<(Uuid, Vec<u8>)> {
-        self.0.borrow_mut().pop_front()
+    fn requeue(&self, entry: Entry) -> Result<(), Entry> {
+        try!(self.entries.borrow_mut().requeue(entry));
+        Ok(self.signal.notify())
+    }
+
+    fn dequeue(&self) -> Option<Entry> {
+        self.dequeue_batch(1).pop()
+    }
+
+    fn enqueue_batch(&self, entries: Vec<Entry>) -> Result<(), Vec<Entry>> {
+        let count = entries.len();
+        let rest = self.entries.borrow_mut().enqueue_batch(entries);
+        self.signal.notify_many(count - rest.len());
+        if rest.is_empty() { Ok(()) } else { Err(rest) }
+    }
+
+    fn dequeue_batch(&self, max: usize) -> Vec<Entry> {
+        let (entries, left) = self.entries.borrow_mut().dequeue_batch(max);
+        self.room.notify_many(left);
+        entries
     }
-}
 
+    fn options(&self) -> QueueOptions {
+        self.entries.borrow().options().clone()
+    }
+
+    fn alter(&self, options: QueueOptions) {
+        self.entries.borrow_mut().alter(options);
+        self.room.notify_all();
+    }
+
+    fn take(&self, count: usize) -> Vec<Entry> {
+        let entries = self.entries.borrow_mut().take(count);
+        self.room.notify_many(entries.len());
+        entries
+    }
+
+    fn move_to(&self, to: &RcQueue, count: usize) -> Vec<Entry> {
+        // Entries are never moved to the queue they are in.
+        let source = &*self.entries as *const RefCell<Fifo<Plain>>;
+        if source == &*to.entries as *const RefCell<Fifo<Plain>> { return Vec::new() }
+
+        let (moved, popped) = {
+            let (mut from, mut into) = (self.entries.borrow_mut(), to.entries.borrow_mut());
+            from.move_to(&mut *into, count)
+        };
+
+        self.room.notify_many(popped);
+        to.signal.notify_many(moved.len());
+        moved
+    }
+
+    fn remove(&self, id: &Uuid) -> Option<Entry> {
+        let entry = self.entries.borrow_mut().remove(id);
+        if entry.is_some() { self.room.notify() }
+        entry
+    }
+
+    fn visit<F: FnMut(&Entry) -> bool>(&self, visit: F) {
+        self.entries.borrow().visit(visit)
+    }
+
+    fn len(&self) -> usize { self.entries.borrow().len() }
+
+    fn bytes(&self) -> u64 { self.entries.borrow().bytes() }
+
+    fn oldest(&self) -> Option<u64> { self.entries.borrow().oldest() }
+
+    fn expired(&self) -> Vec<Entry> { self.expired.take() }
+
+    fn counters(&self) -> Option<&Counters> { Some(&self.counters) }
+
+    fn memory(&self) -> &Memory { &self.memory }
+
+    fn signal(&self) -> Option<&Signal> { Some(&self.signal) }
+
+    fn room(&self) -> Option<&Signal> { Some(&self.room) }
+}
@@ -4,10 +4,14 @@ use mio::{self, EventLoop, Token, ReadHint, Interest, PollOpt, NonBlock};
 use mio::util::Slab;
 
 use eventual::Complete;
+use uuid::Uuid;
 
-use queue::{Queue, Queues};
+use common::RequestId;
+use queue::{Queue, Queues, Entry, Memory};
+use lease::Leases;
 use connection::Connection;
-use {Error};
+use wait::{self, Waiting, Retry};
+use {Error, Limits};
 
 /// Messages sent from the Server handle to the actual event loop,
 /// through the event loop's notify queue.
@@ -19,9 +23,66 @@ pub enum Message {
     /// Start listening on this acceptor. The future will be completed
     /// when the server is ready to accept new connections from this
     /// acceptor.
-    Acceptor(NonBlock<TcpListener>, Complete<(), Error>)
+    Acceptor(NonBlock<TcpListener>, Complete<(), Error>),
+
+    /// An object was enqueued on the named queue for the Read with this
+    /// token and request id, which was waiting for one, to take.
+    ///
+    /// Sent by the signal of the queue, possibly from another Server.
+    Ready(String, Token, RequestId),
+
+    /// Objects left the named queue, making room for the objects held back
+    /// by it on the connection with this token, which was waiting for room.
+    ///
+    /// Sent by the room signal of the queue, possibly from another Server.
+    Room(String, Token),
+
+    /// The lease with this id on an object read by the connection with this
+    /// token was ended by another connection, possibly on another Server.
+    Released(Token, Uuid, u64),
+
+    /// The lease on an object read by the connection with this token was
+    /// extended by another connection, possibly on another Server.
+    ///
+    /// Holds the id of the object, the ids of the old and new leases, and
+    /// the new timeout of the Read.
+    Renewed(Token, Uuid, u64, u64, u64)
 }
 
+/// Timers set on the event loop.
+pub enum Timeout {
+    /// The timeout of a Read elapsed.
+    ///
+    /// Holds the token of the connection which set the timer, the id of the
+    /// object that was read, and the id of its lease.
+    Lease(Token, Uuid, u64),
+
+    /// The delay of a Nack elapsed, and the object should be requeued.
+    ///
+    /// Holds the id of the object and of the lease it was given when it was
+    /// nacked, which is not held by any connection.
+    Requeue(Uuid, u64),
+
+    /// The delay of an EnqueueDelayed elapsed, and the object should be
+    /// enqueued on the named queue.
+    ///
+    /// Holds the number of times the queue was already found full.
+    Deliver(String, Entry, u32),
+
+    /// The wait of a ReadWait elapsed without an object being enqueued.
+    ///
+    /// Holds the token of the connection and the id of the request.
+    Wait(Token, RequestId)
+}
+
+/// How long to wait before trying again to enqueue a delayed object whose
+/// queue was full, in milliseconds.
+const DELIVERY_RETRY_MS: u64 = 100;
+
+/// How many times to try again to enqueue a delayed object whose queue stays
+/// full, before moving it to the dead-letter queue or discarding it.
+const DELIVERY_RETRIES: u32 = 600;
+
 /// Handler holds acceptors and connections and will manage
 /// interfacing with the event loop.
 ///
@@ -36,7 +97,24 @@ pub struct Handler<Q: Queues> {
     /// The queues used by this handler.
     ///
     /// They may be shared with over Handlers.
-    queues: Q
+    queues: Q,
+
+    /// The objects read through this handler, and any others sharing its
+    /// queues, which are not confirmed yet.
+    leases: Leases<Q::Queue>,
+
+    /// The memory used by the queues and this handler, shared with every
+    /// connection.
+    ///
+    /// Queues which do not keep count of their memory only share it with
+    /// the connections of this handler.
+    memory: Memory,
+
+    /// The limits given to every new connection.
+    limits: Limits,
+
+    /// The Reads on this event loop waiting for objects to be enqueued.
+    waiting: Waiting
 }
 
 /// Either an Acceptor or a Connection.
@@ -49,11 +127,47 @@ enum Registration<Q: Queue> {
 
 impl<Q: Queues + Send> Handler<Q> {
     /// Create a new Handler with the specified slab capacity.
-    pub fn new(capacity: usize, queues: Q) -> Handler<Q> {
+    pub fn new(capacity: usize, queues: Q, limits: Limits) -> Handler<Q> {
+        // Queues which do not share their leases are only used by this handler.
+        let leases = queues.leases().map(|leases| leases.clone()).unwrap_or_else(Leases::new);
+        let memory = queues.memory().map(|memory| memory.clone()).unwrap_or_else(Memory::new);
+
         Handler {
             slab: Slab::new(capacity),
-            queues: queues
+            queues: queues,
+            leases: leases,
+            memory: memory,
+            limits: limits,
+            waiting: Waiting::new()
+        }
+    }
+
+    /// The memory used by the queues and this handler.
+    pub fn memory(&self) -> &Memory { &self.memory }
+
+    /// Stop trying to deliver a delayed object to its queue, moving it to the
+    /// dead-letter queue of the queue if there is one, or discarding it.
+    fn abandon(&self, queue: &Q::Queue, mut entry: Entry) {
+        let id = entry.id.clone();
+        self.memory.unhold(entry.data.len());
+
+        match queue.options().dead_letter {
+            Some(name) => match self.queues.queue(&name) {
+                Some(dead_letter) => {
+                    // It would otherwise expire in the dead-letter queue.
+                    entry.expires = None;
+                    if let Err(entry) = dead_letter.enqueue(entry) {
+                        warn!("Dead-letter queue {} full, discarding delayed object {}.",
+                              name, entry.id);
+                    }
+                },
+                None => warn!("Dead-letter queue {} does not exist, discarding delayed \
+                               object {}.", name, id)
+            },
+            None => warn!("Discarding delayed object {}.", id)
         }
+
+        queue.discard_delayed(&id);
     }
 
     /// Accept a new connection on the acceptor with the specified token.
@@ -72,8 +186,13 @@ impl<Q: Queues + Send> Handler<Q> {
 
         match connection {
             Ok(Some(connection)) => {
+                let queues = self.queues.clone();
+                let resolve = Box::new(move |name: &str| queues.queue(name));
+
                 let token = self.register(
-                    Registration::Connection(Connection::new(connection)));
+                    Registration::Connection(
+                        Connection::new(connection, self.waiting.id(), self.leases.clone(),
+                                        resolve, self.memory.clone(), self.limits)));
 
                 match evloop.register_opt(
                     self.connection_at(token).connection(),
@@ -111,7 +230,101 @@ impl<Q: Queues + Send> Handler<Q> {
     fn disconnect(&mut self, token: Token, evloop: &mut EventLoop<Handler<Q>>) {
         match self.slab.remove(token).unwrap() {
             Registration::Acceptor(acc) => evloop.deregister(&acc).unwrap(),
-            Registration::Connection(conn) => evloop.deregister(conn.connection()).unwrap(),
+            Registration::Connection(conn) => {
+                conn.clear_timeouts(evloop);
+                evloop.deregister(conn.connection()).unwrap();
+
+                for (name, request) in conn.parked() {
+                    self.waiting.unpark(&self.queues, &name, token, request);
+                }
+
+                for name in conn.held() {
+                    self.waiting.release(&self.queues, &name, token);
+                }
+
+                // Dropping the connection requeues everything it read but
+                // did not confirm.
+            }
+        }
+    }
+
+    /// Let a Read which was waiting for an object on the named queue try to
+    /// take one, and put it back in line if it should keep waiting.
+    fn ready(&mut self, evloop: &mut EventLoop<Handler<Q>>, name: String, token: Token,
+             request: RequestId) {
+        let signal = wait::note(&self.queues, &name);
+
+        let retry = if self.slab.contains(token) {
+            match &mut self.slab[token] {
+                &mut Registration::Connection(ref mut conn) =>
+                    match conn.retry(token, &name, request, &self.queues, evloop) {
+                        Ok(retry) => retry,
+                        Err(e) => {
+                            // The Read will be answered when its wait elapses.
+                            error!("Error retrying a waiting Read: {:?}", e);
+                            Retry::Unused
+                        }
+                    },
+                _ => Retry::Unused
+            }
+        } else {
+            // The connection was closed.
+            Retry::Unused
+        };
+
+        // The queue was deleted.
+        let (signal, seen) = match signal {
+            Some(signal) => signal,
+            None => return
+        };
+
+        match retry {
+            Retry::Empty =>
+                signal.wait_first(self.waiting.waiter(evloop.channel(), name, token, request),
+                                  seen),
+            Retry::More =>
+                signal.wait_again(self.waiting.waiter(evloop.channel(), name, token, request)),
+            Retry::Done => {},
+            Retry::Unused => signal.pass()
+        }
+    }
+
+    /// Let a connection which was waiting for room in the named queue try to
+    /// enqueue the objects held back by it, and put it back in line if some
+    /// are still held back.
+    fn room(&mut self, evloop: &mut EventLoop<Handler<Q>>, name: String, token: Token) {
+        let room = wait::note_room(&self.queues, &name);
+
+        let retry = if self.slab.contains(token) {
+            match &mut self.slab[token] {
+                &mut Registration::Connection(ref mut conn) =>
+                    match conn.unblock(&name, &self.queues) {
+                        Ok(retry) => retry,
+                        Err(e) => {
+                            error!("Error enqueueing objects held back by a full queue: {:?}",
+                                   e);
+                            Retry::Unused
+                        }
+                    },
+                _ => Retry::Unused
+            }
+        } else {
+            // The connection was closed.
+            Retry::Unused
+        };
+
+        // The queue was deleted.
+        let (room, seen) = match room {
+            Some(room) => room,
+            None => return
+        };
+
+        match retry {
+            Retry::Empty => room.wait_first(self.waiting.producer(evloop.channel(), name, token),
+                                            seen),
+            Retry::More => room.wait_again(self.waiting.producer(evloop.channel(), name, token)),
+            Retry::Done => {},
+            Retry::Unused => room.pass()
         }
     }
 
@@ -142,9 +355,18 @@ impl<Q: Queues + Send> Handler<Q> {
     }
 }
 
+impl<Q: Queues> Drop for Handler<Q> {
+    /// Requeue the objects nacked through this handler's connections, since
+    /// the timers which would requeue them never fire once it is gone.
+    fn drop(&mut self) {
+        let queues = &self.queues;
+        self.leases.release_nacked(self.waiting.id(), &|name: &str| queues.queue(name));
+    }
+}
+
 impl<Q: Queues + Send> mio::Handler for Handler<Q> {
     type Message = Message;
-    type Timeout = Complete<(), Error>;
+    type Timeout = Timeout;
 
     /// Respond to readable events on acceptors or connections.
     fn readable(&mut self, evloop: &mut EventLoop<Handler<Q>>,
@@ -157,8 +379,12 @@ impl<Q: Queues + Send> mio::Handler for Handler<Q> {
         // after the match has exited.
         let next = match &mut self.slab[token] {
             &mut Registration::Connection(ref mut conn) =>
-                match conn.readable(&mut self.queues, evloop) {
+                match conn.readable(token, &mut self.queues, &self.waiting, evloop) {
                     Ok(()) => return,
+                    Err(Error::Disconnected) => {
+                        debug!("Connection closed by client.");
+                        true
+                    },
                     Err(e) => {
                         error!("Connection readable error: {:?}", e);
                         true
@@ -167,7 +393,7 @@ impl<Q: Queues + Send> mio::Handler for Handler<Q> {
             _ => false
         };
 
-        if next { // A connection hit a fatal error.
+        if next { // A connection was closed or hit a fatal error.
             self.disconnect(token, evloop)
         } else { // An acceptor is ready to accept a new connection.
             self.accept(evloop, token)
@@ -208,13 +434,125 @@ impl<Q: Queues + Send> mio::Handler for Handler<Q> {
                         future.fail(Error::from(e));
                     }
                 }
+            },
+            Message::Ready(name, token, request) => self.ready(evloop, name, token, request),
+            Message::Room(name, token) => self.room(evloop, name, token),
+
+            // Forget an object the connection no longer holds.
+            Message::Released(token, uuid, lease) => {
+                if !self.slab.contains(token) { return }
+
+                match &mut self.slab[token] {
+                    &mut Registration::Connection(ref mut conn) => {
+                        conn.forget(evloop, &uuid, lease);
+
+                        // Make up for the object if it was pushed to a
+                        // subscription.
+                        if let Err(e) = conn.pump(token, &self.queues, &self.waiting, evloop) {
+                            error!("Error pushing objects to a subscription: {:?}", e);
+                        }
+                    },
+                    _ => { error!("Received a released lease for an acceptor.") }
+                }
+            },
+
+            // Restart the timeout of a Read whose lease was extended elsewhere.
+            Message::Renewed(token, uuid, old, lease, timeout) => {
+                let renewed = self.slab.contains(token) && match &mut self.slab[token] {
+                    &mut Registration::Connection(ref mut conn) =>
+                        conn.renewed(token, evloop, &uuid, old, lease, timeout),
+                    _ => false
+                };
+
+                // The Read already ended, so nothing else would ever end the
+                // new lease.
+                if !renewed {
+                    let queues = &self.queues;
+                    self.leases.release(&uuid, lease, &|name: &str| queues.queue(name));
+                }
             }
         }
     }
 
-    /// Respond to timeouts, when they have elapsed.
-    fn timeout(&mut self, _: &mut EventLoop<Handler<Q>>, future: Complete<(), Error>) {
-        future.complete(());
+    /// Respond to a timer elapsing.
+    fn timeout(&mut self, evloop: &mut EventLoop<Handler<Q>>, timeout: Timeout) {
+        match timeout {
+            // Requeue the object if it has not been confirmed.
+            Timeout::Lease(token, uuid, lease) => {
+                // If the connection was closed, the object was already requeued.
+                if !self.slab.contains(token) { return }
+
+                match &mut self.slab[token] {
+                    &mut Registration::Connection(ref mut conn) => {
+                        conn.expire(&uuid, lease);
+
+                        // Make up for the object if it was pushed to a
+                        // subscription.
+                        if let Err(e) = conn.pump(token, &self.queues, &self.waiting, evloop) {
+                            error!("Error pushing objects to a subscription: {:?}", e);
+                        }
+                    },
+                    _ => { error!("Received a Read timeout for an acceptor.") }
+                }
+            },
+
+            // Requeue a nacked object, unless it was requeued some other way.
+            Timeout::Requeue(uuid, lease) => {
+                let queues = &self.queues;
+                self.leases.release(&uuid, lease, &|name: &str| queues.queue(name));
+            },
+
+            // Make a delayed object available to Read. The object is held by
+            // the handler until it is queued or discarded.
+            Timeout::Deliver(name, entry, retries) => {
+                let bytes = entry.data.len();
+                let queue = match self.queues.queue(&name) {
+                    Some(queue) => queue,
+                    None => {
+                        warn!("Queue {} was deleted, discarding delayed object {}.",
+                              name, entry.id);
+                        self.memory.unhold(bytes);
+                        return
+                    }
+                };
+
+                match queue.offer(entry) {
+                    Ok(_) => {
+                        self.memory.unhold(bytes);
+                        if let Some(counters) = queue.counters() { counters.deliver() }
+                    },
+                    Err(entry) => {
+                        if retries >= DELIVERY_RETRIES {
+                            warn!("Queue {} stayed full, giving up on delayed object {}.",
+                                  name, entry.id);
+                            self.abandon(&queue, entry);
+                            return
+                        }
+
+                        let id = entry.id.clone();
+                        let retry = Timeout::Deliver(name, entry, retries + 1);
+                        if let Err(e) = evloop.timeout_ms(retry, DELIVERY_RETRY_MS) {
+                            error!("Could not retry enqueueing delayed object {}: {:?}", id, e);
+                            self.memory.unhold(bytes);
+                            queue.discard_delayed(&id);
+                        }
+                    }
+                }
+            },
+
+            // Answer a Read which is still waiting with Empty.
+            Timeout::Wait(token, request) => {
+                if !self.slab.contains(token) { return }
+
+                let name = match &mut self.slab[token] {
+                    &mut Registration::Connection(ref mut conn) => conn.give_up(request),
+                    _ => None
+                };
+
+                if let Some(name) = name {
+                    self.waiting.unpark(&self.queues, &name, token, request);
+                }
+            }
+        }
     }
 }
-
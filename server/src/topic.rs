@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// The topics of a set of queues, and the queues bound to each of them.
+///
+/// Publishing an object to a topic with a routing key enqueues a separate
+/// copy of it on every queue bound to the topic with a pattern matching the
+/// key. A topic exists for as long as any queue is bound to it.
+///
+/// Routing keys are made up of words separated by dots, such as
+/// `orders.new.eu`. Patterns are too, except that the word `*` matches any
+/// single word, and the word `#` matches any number of words, including
+/// none, so `orders.*.eu` and `orders.#` both match `orders.new.eu`, and `#`
+/// matches every key.
+///
+/// Topics are shared by every clone of the queues, like their leases, so
+/// bindings made through one Server apply to objects published through any
+/// other Server using the same queues.
+#[derive(Clone)]
+pub struct Topics(Arc<Mutex<HashMap<String, Topic>>>);
+
+/// The bindings of a single topic.
+struct Topic {
+    /// Every binding, as the name of the queue and its pattern, in the
+    /// order they were made.
+    bindings: Vec<(String, String)>,
+
+    /// The same bindings, arranged for matching routing keys.
+    patterns: Patterns
+}
+
+impl Topics {
+    pub fn new() -> Topics {
+        Topics(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    /// Bind a queue to a topic with a pattern, creating the topic if it does
+    /// not exist.
+    ///
+    /// Returns false if the queue was already bound to the topic with the
+    /// same pattern.
+    pub fn bind(&self, topic: String, queue: String, pattern: String) -> bool {
+        let mut topics = self.0.lock().unwrap();
+        let topic = topics.entry(topic).or_insert_with(|| Topic {
+            bindings: Vec::new(),
+            patterns: Patterns::new()
+        });
+
+        if !topic.patterns.insert(&words(&pattern), &queue) { return false }
+        topic.bindings.push((queue, pattern));
+        true
+    }
+
+    /// Unbind a queue from a topic, whatever patterns it was bound with,
+    /// removing the topic if no queues are bound to it anymore.
+    ///
+    /// Returns false if the queue was not bound to the topic.
+    pub fn unbind(&self, topic: &str, queue: &str) -> bool {
+        let mut topics = self.0.lock().unwrap();
+
+        let (unbound, empty) = match topics.get_mut(topic) {
+            Some(topic) => {
+                let unbound = topic.unbind(queue);
+                (unbound, topic.bindings.is_empty())
+            },
+            None => (false, false)
+        };
+
+        if empty { topics.remove(topic); }
+        unbound
+    }
+
+    /// Unbind a queue from every topic, because it was deleted.
+    pub fn unbind_all(&self, queue: &str) {
+        let mut topics = self.0.lock().unwrap();
+        let names = topics.keys().cloned().collect::<Vec<_>>();
+
+        for name in names {
+            let empty = {
+                let topic = topics.get_mut(&name).unwrap();
+                topic.unbind(queue);
+                topic.bindings.is_empty()
+            };
+
+            if empty { topics.remove(&name); }
+        }
+    }
+
+    /// The names of the queues bound to a topic with a pattern matching the
+    /// routing key, each named once, or None if the topic does not exist.
+    pub fn route(&self, topic: &str, key: &str) -> Option<Vec<String>> {
+        self.0.lock().unwrap().get(topic).map(|topic| {
+            let mut matched = Vec::new();
+            topic.patterns.matches(&words(key), &mut matched);
+
+            // A queue bound with several matching patterns gets one copy.
+            let mut seen = HashSet::new();
+            matched.into_iter().filter(|queue| seen.insert(queue.clone())).collect()
+        })
+    }
+}
+
+impl Default for Topics {
+    fn default() -> Topics { Topics::new() }
+}
+
+impl Topic {
+    /// Remove every binding of a queue, returning false if it had none.
+    fn unbind(&mut self, queue: &str) -> bool {
+        let patterns = self.bindings.iter()
+            .filter(|&&(ref name, _)| name == queue)
+            .map(|&(_, ref pattern)| pattern.clone())
+            .collect::<Vec<_>>();
+
+        for pattern in &patterns {
+            self.patterns.remove(&words(pattern), queue);
+        }
+
+        self.bindings.retain(|&(ref name, _)| name != queue);
+        !patterns.is_empty()
+    }
+}
+
+/// Split a routing key or pattern into its words. The empty key has none.
+fn words(key: &str) -> Vec<&str> {
+    if key.is_empty() { Vec::new() } else { key.split('.').collect() }
+}
+
+/// A trie of patterns, keyed by their words, so a routing key is matched
+/// against every pattern in a single walk instead of one at a time.
+///
+/// Wildcards are stored as children like any other word.
+struct Patterns {
+    children: HashMap<String, Patterns>,
+
+    /// The queues bound with the pattern ending at this node.
+    queues: Vec<String>
+}
+
+impl Patterns {
+    fn new() -> Patterns {
+        Patterns { children: HashMap::new(), queues: Vec::new() }
+    }
+
+    /// Add a queue under a pattern, returning false if it was already there.
+    fn insert(&mut self, pattern: &[&str], queue: &str) -> bool {
+        if pattern.is_empty() {
+            if self.queues.iter().any(|name| name == queue) { return false }
+            self.queues.push(queue.to_string());
+            return true
+        }
+
+        self.children.entry(pattern[0].to_string()).or_insert_with(Patterns::new)
+            .insert(&pattern[1..], queue)
+    }
+
+    /// Remove a queue from under a pattern, pruning nodes left empty.
+    fn remove(&mut self, pattern: &[&str], queue: &str) {
+        if pattern.is_empty() {
+            self.queues.retain(|name| name != queue);
+            return
+        }
+
+        let empty = match self.children.get_mut(pattern[0]) {
+            Some(child) => {
+                child.remove(&pattern[1..], queue);
+                child.is_empty()
+            },
+            None => false
+        };
+
+        if empty { self.children.remove(pattern[0]); }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.is_empty() && self.children.is_empty()
+    }
+
+    /// Add the queues under every pattern matching the words of a routing
+    /// key to `matched`.
+    fn matches(&self, key: &[&str], matched: &mut Vec<String>) {
+        if key.is_empty() {
+            matched.extend(self.queues.iter().cloned());
+        } else {
+            if let Some(child) = self.children.get(key[0]) { child.matches(&key[1..], matched) }
+            if let Some(child) = self.children.get("*") { child.matches(&key[1..], matched) }
+        }
+
+        // `#` takes up any number of the remaining words.
+        if let Some(child) = self.children.get("#") {
+            for skip in 0..key.len() + 1 {
+                child.matches(&key[skip..], matched);
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use mio::{self, Token};
+
+use common::RequestId;
+use queue::{Queue, Queues, Signal};
+use rt::Message;
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+/// Used to tell event loops apart, since their Reads may wait on the same
+/// signals.
+static NEXT_LOOP: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The Reads on a single event loop which are waiting for objects to be
+/// enqueued on their queues.
+///
+/// Each Read is identified by the token of its connection and the id of
+/// its request. Subscriptions wait like Reads, identified by the id of their
+/// Subscribe request.
+///
+/// The Reads themselves wait in line on the signal of their queue, along with
+/// the Reads of every other event loop using the queue, so objects are handed
+/// out in turn however many Servers share the queue.
+///
+/// Connections with objects held back by a full queue which blocks producers
+/// wait in line for room the same way, on the room signal of the queue.
+pub struct Waiting {
+    id: usize
+}
+
+impl Waiting {
+    pub fn new() -> Waiting {
+        Waiting { id: NEXT_LOOP.fetch_add(1, Ordering::Relaxed) }
+    }
+
+    /// The id of this event loop.
+    pub fn id(&self) -> usize { self.id }
+
+    /// A Read on this event loop, to wait in line on a signal.
+    pub fn waiter(&self, notify: mio::Sender<Message>, name: String, token: Token,
+                  request: RequestId) -> Waiter {
+        Waiter {
+            notify: notify,
+            event_loop: self.id,
+            name: name,
+            token: token,
+            request: request,
+            producer: false
+        }
+    }
+
+    /// A connection on this event loop with objects held back by the named
+    /// queue, to wait in line for room on its room signal.
+    pub fn producer(&self, notify: mio::Sender<Message>, name: String, token: Token) -> Waiter {
+        Waiter { producer: true, ..self.waiter(notify, name, token, 0) }
+    }
+
+    /// Start waiting for objects on the named queue, behind every Read
+    /// already waiting on it.
+    ///
+    /// `signal` should have been noted before the queue was found empty.
+    pub fn park(&self, name: String, token: Token, request: RequestId,
+                signal: Option<(Signal, u64)>, notify: mio::Sender<Message>) {
+        if let Some((signal, seen)) = signal {
+            signal.wait(self.waiter(notify, name, token, request), seen);
+        }
+    }
+
+    /// Start waiting for room in the named queue, behind every connection
+    /// already waiting for room in it.
+    ///
+    /// `room` should have been noted before the queue was found full.
+    pub fn hold(&self, name: String, token: Token, room: Option<(Signal, u64)>,
+                notify: mio::Sender<Message>) {
+        if let Some((room, seen)) = room {
+            room.wait(self.producer(notify, name, token), seen);
+        }
+    }
+
+    /// Stop waiting for room in the named queue, because the connection
+    /// was closed.
+    pub fn release<Q: Queues>(&self, queues: &Q, name: &str, token: Token) {
+        if let Some(queue) = queues.queue(name) {
+            if let Some(room) = queue.room() {
+                room.cancel(self.id, token, 0);
+            }
+        }
+    }
+
+    /// Stop waiting for a single Read, because it was answered some other
+    /// way.
+    ///
+    /// If the Read was already woken, its wakeup is passed on when it arrives.
+    pub fn unpark<Q: Queues>(&self, queues: &Q, name: &str, token: Token, request: RequestId) {
+        if let Some(queue) = queues.queue(name) {
+            if let Some(signal) = queue.signal() {
+                signal.cancel(self.id, token, request);
+            }
+        }
+    }
+}
+
+/// A Read, or a connection with objects held back, waiting in line on a
+/// signal.
+pub struct Waiter {
+    notify: mio::Sender<Message>,
+    event_loop: usize,
+    name: String,
+    token: Token,
+    request: RequestId,
+
+    /// Whether this is a connection waiting for room rather than a Read.
+    producer: bool
+}
+
+impl Waiter {
+    /// Whether this is the Read with this token and request id on the event
+    /// loop with this id.
+    pub fn is(&self, event_loop: usize, token: Token, request: RequestId) -> bool {
+        self.event_loop == event_loop && self.token == token && self.request == request
+    }
+
+    /// Wake up the Read or connection, returning false if its event loop
+    /// has shut down.
+    pub fn wake(self) -> bool {
+        let message = if self.producer {
+            Message::Room(self.name, self.token)
+        } else {
+            Message::Ready(self.name, self.token, self.request)
+        };
+
+        self.notify.send(message).is_ok()
+    }
+}
+
+/// The outcome of trying a waiting Read, or the objects held back on a
+/// connection, again after it was woken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retry {
+    /// The queue was empty, because another Read took the object first, or
+    /// still full, because another producer took the room, so the Read or
+    /// connection should keep its place in line.
+    Empty,
+
+    /// The Read was given an object and can take more, or some of the
+    /// objects held back were enqueued but not all, so it should wait again
+    /// behind the others.
+    More,
+
+    /// The Read was answered, or every object held back was, and it is no
+    /// longer waiting.
+    Done,
+
+    /// The Read or connection was no longer waiting, so the wakeup should be
+    /// passed on to the next one.
+    Unused
+}
+
+/// Note the signal of the named queue and how many times it has been raised,
+/// before checking the queue for objects, so that no object enqueued after
+/// the check can be missed by waiting on the signal.
+pub fn note<Q: Queues>(queues: &Q, name: &str) -> Option<(Signal, u64)> {
+    queues.queue(name).and_then(|queue| {
+        queue.signal().map(|signal| (signal.clone(), signal.generation()))
+    })
+}
+
+/// Note the room signal of the named queue and how many times it has been
+/// raised, before trying to enqueue the objects held back by the queue, as
+/// for `note`.
+pub fn note_room<Q: Queues>(queues: &Q, name: &str) -> Option<(Signal, u64)> {
+    queues.queue(name).and_then(|queue| {
+        queue.room().map(|room| (room.clone(), room.generation()))
+    })
+}
+
+/// Whether any Reads, on any event loop, are waiting for objects on the
+/// queue whose signal was noted.
+pub fn is_waiting(signal: &Option<(Signal, u64)>) -> bool {
+    signal.as_ref().map(|&(ref signal, _)| signal.is_waiting()).unwrap_or(false)
+}
@@ -46,7 +46,7 @@ fn do_work(clients: &mut Vec<PipelinedClient<TcpStream>>) {
                 .unwrap();
         }
 
-        for response in client.iter() {
+        for (_, response) in client.iter() {
             match response {
                 ServerMessage::ObjectQueued(_) => {},
                 x => panic!("Received incorrect response: {:?}.", x)
@@ -61,9 +61,9 @@ fn do_work(clients: &mut Vec<PipelinedClient<TcpStream>>) {
             client.send(&ClientMessage::Read(StrBox::new("foo"), 1000)).unwrap();
         }
 
-        for response in client.iter().collect::<Vec<_>>() {
+        for (_, response) in client.iter().collect::<Vec<_>>() {
             match response {
-                ServerMessage::Read(id, _) => {
+                ServerMessage::Read(id, _, _) => {
                     client.send(&ClientMessage::Confirm(id)).unwrap();
                 },
                 ServerMessage::Empty => {}
@@ -71,7 +71,7 @@ fn do_work(clients: &mut Vec<PipelinedClient<TcpStream>>) {
             }
         }
 
-        for response in client.iter() {
+        for (_, response) in client.iter() {
             match response {
                 ServerMessage::Confirmed => {},
                 x => panic!("Received incorrect response: {:?}.", x)
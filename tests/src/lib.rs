@@ -12,19 +12,25 @@ extern crate test;
 
 #[cfg(test)]
 mod tests {
-    use dbqueue_server::{Server};
-    use dbqueue_client::{Client, Message, PipelinedClient};
-    use dbqueue_common::{ClientMessage, ServerMessage, StrBox, SliceBox};
+    use dbqueue_server::{Server, Limits, Queue, Entry, ConcurrentQueue, ConcurrentQueues,
+                         ConcurrentPriorityQueues, DurableQueues, SyncPolicy};
+    use dbqueue_client::{Client, Message, Pipeline, PipelinedClient, AsyncClient, QueueOptions,
+                          QueueId, Overflow, MAX_BATCH};
+    use dbqueue_common::{ClientMessage, ServerMessage, StrBox, SliceBox, Hello, Welcome, Header,
+                         ProtocolError, PROTOCOL_VERSION, HEADER_LEN, MAX_CLIENT_MESSAGE_LEN};
 
     use dbqueue_client::Error as ClientError;
+    use dbqueue_server::Error as ServerError;
 
     use mio::{EventLoopConfig, NonBlock, Socket, tcp};
-    use eventual::Async;
+    use eventual::{Async, AsyncError};
     use uuid::Uuid;
     use test::Bencher;
     use env_logger;
 
-    use std::{thread, net};
+    use std::{thread, net, env, fs};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
     use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
     static PORT: AtomicUsize = ATOMIC_USIZE_INIT;
@@ -44,6 +50,23 @@ mod tests {
         socket.listen(1024).unwrap()
     }
 
+    /// An event loop configuration with a 1ms timer tick, for tests
+    /// relying on short timeouts.
+    fn timer_config() -> EventLoopConfig {
+        EventLoopConfig {
+            io_poll_timeout_ms: 1000,
+            notify_capacity: 4096,
+            messages_per_tick: 256,
+            timer_tick_ms: 1,
+            timer_wheel_size: 1024,
+            timer_capacity: 65536
+        }
+    }
+
+    fn log_dir() -> PathBuf {
+        env::temp_dir().join(format!("dbqueue-test-{}", Uuid::new_v4()))
+    }
+
     #[test]
     fn test_single_create_send_read_confirm() {
         let addr = sock();
@@ -131,68 +154,1417 @@ mod tests {
     }
 
     #[test]
-    fn test_request_pipelining() {
+    fn test_requeue_on_disconnect() {
         let data: [&[u8]; 3] = [&[1; 128], &[2; 128], &[3; 128]];
 
-        let requests_phase_1 = [
-            ClientMessage::CreateQueue(StrBox::new("foo")),
-            ClientMessage::Enqueue(StrBox::new("foo"), SliceBox::new(data[0])),
-            ClientMessage::Enqueue(StrBox::new("foo"), SliceBox::new(data[1])),
-            ClientMessage::Read(StrBox::new("foo"), 1000),
-            ClientMessage::Enqueue(StrBox::new("foo"), SliceBox::new(data[2])),
-            ClientMessage::Read(StrBox::new("foo"), 1000),
-            ClientMessage::Read(StrBox::new("foo"), 1000),
-            // We will send Confirm requests once we get the data.
-        ];
-
         let addr = sock();
         let server = Server::start(|x| { thread::spawn(x); }).unwrap();
         server.listen(listener(&addr)).await().unwrap();
 
-        let mut client = PipelinedClient::connect(addr).unwrap();
+        let mut client = Client::connect(addr).unwrap();
+        let foo = client.create("foo").unwrap();
 
-        // Send all requests without waiting for responses.
-        for request in &requests_phase_1 {
-            client.send(request).unwrap();
+        for datum in &data {
+            client.send(foo.clone(), datum.as_ref()).unwrap();
+        }
+
+        // Read the first two without confirming, one with no timeout.
+        let mut consumer = Client::connect(addr).unwrap();
+        consumer.read_ms(foo.clone(), 0).unwrap();
+        consumer.read_ms(foo.clone(), 60 * 1000).unwrap();
+        drop(consumer);
+
+        // Give the server time to notice the closed connection.
+        thread::sleep_ms(100);
+
+        // Both reads are requeued in front of the third, in their original order.
+        for datum in &data {
+            let message = client.read_ms(foo.clone(), 1000).unwrap();
+            assert_eq!(&*message.data, *datum);
+            client.confirm(message.id).unwrap();
+        }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_confirm_from_another_server() {
+        let queues = ConcurrentQueues::new(128);
+        let (addr1, addr2) = (sock(), sock());
+
+        let server1 = Server::with_queues(|x| { thread::spawn(x); },
+                                          Default::default(), 128, queues.clone()).unwrap();
+        let server2 = Server::with_queues(|x| { thread::spawn(x); },
+                                          Default::default(), 128, queues).unwrap();
+        server1.listen(listener(&addr1)).await().unwrap();
+        server2.listen(listener(&addr2)).await().unwrap();
+
+        let mut reader = Client::connect(addr1).unwrap();
+        let mut confirmer = Client::connect(addr2).unwrap();
+
+        let foo = reader.create("foo").unwrap();
+        reader.send(foo.clone(), &[7; 128]).unwrap();
+
+        let message = reader.read_ms(foo.clone(), 1000).unwrap();
+        confirmer.confirm(message.id).unwrap();
+
+        // The lease is gone, so the original reader can no longer confirm it,
+        // and the object is not requeued when the timeout elapses.
+        if let ClientError::NoObject(_) = reader.confirm(message.id).unwrap_err() {}
+        else { panic!("Object confirmed twice.") }
+
+        if let ClientError::Empty = reader.read_ms(foo, 1000).unwrap_err() {}
+        else { panic!("Confirmed object was requeued.") }
+
+        server1.shutdown().await().unwrap();
+        server2.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_long_poll() {
+        let queues = ConcurrentQueues::new(128);
+        let (addr1, addr2) = (sock(), sock());
+
+        let server1 = Server::with_queues(|x| { thread::spawn(x); },
+                                          timer_config(), 128, queues.clone()).unwrap();
+        let server2 = Server::with_queues(|x| { thread::spawn(x); },
+                                          timer_config(), 128, queues).unwrap();
+        server1.listen(listener(&addr1)).await().unwrap();
+        server2.listen(listener(&addr2)).await().unwrap();
+
+        let mut reader = Client::connect(addr1).unwrap();
+        let mut sender = Client::connect(addr2).unwrap();
+        let foo = reader.create("foo").unwrap();
+
+        // Only answered once the wait elapses, after requests sent behind it.
+        let mut pipeline = Pipeline::new(net::TcpStream::connect(addr1).unwrap());
+        pipeline.handshake().unwrap();
+        let wait = pipeline.send(&ClientMessage::ReadWait(StrBox::new("foo"), 1000, 100)).unwrap();
+        let stats = pipeline.send(&ClientMessage::QueueStats(StrBox::new("foo"))).unwrap();
+        assert_eq!(pipeline.receive().unwrap().0, stats);
+        match pipeline.receive_for(wait).unwrap() {
+            ServerMessage::Empty => {},
+            x => panic!("Expected Empty, received {:?}", x)
+        }
+
+        // A second ReadWait with the id of one still waiting is refused.
+        let mut stream = pipeline.stream();
+        let wait = ClientMessage::ReadWait(StrBox::new("foo"), 1000, 100);
+        wait.encode_to(&mut stream, 100, MAX_CLIENT_MESSAGE_LEN).unwrap();
+        wait.encode_to(&mut stream, 100, MAX_CLIENT_MESSAGE_LEN).unwrap();
+        assert_eq!(ServerMessage::decode_from(&mut stream).unwrap(),
+                   (ServerMessage::BadRequest, 100));
+        assert_eq!(ServerMessage::decode_from(&mut stream).unwrap(), (ServerMessage::Empty, 100));
+
+        if let ClientError::Empty = reader.read_wait_ms(foo.clone(), 1000, 50).unwrap_err() {}
+        else { panic!("Read an object from an empty queue.") }
+
+        // Woken up by an object sent through the other server, long before
+        // the wait elapses.
+        let waiting = thread::spawn(move || {
+            reader.read_wait_ms(foo.clone(), 1000, 60 * 1000).unwrap()
+        });
+
+        thread::sleep_ms(100);
+        sender.send(QueueId::from("foo"), &[7; 128]).unwrap();
+
+        let message = waiting.join().unwrap();
+        assert_eq!(&*message.data, [7; 128].as_ref());
+        sender.confirm(message.id).unwrap();
+
+        server1.shutdown().await().unwrap();
+        server2.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let mut sender = Client::connect(addr).unwrap();
+
+        let foo = client.create("foo").unwrap();
+        for i in 1..4 { sender.send(foo.clone(), &[i; 128]).unwrap(); }
+
+        {
+            let mut consumer = client.subscribe(foo.clone(), 2, 60 * 1000).unwrap();
+            let first = consumer.receive().unwrap();
+            let second = consumer.receive().unwrap();
+            assert_eq!(&*first.data, [1; 128].as_ref());
+            assert_eq!(&*second.data, [2; 128].as_ref());
+
+            // Only two objects may be outstanding, so the third was not pushed.
+            let third = sender.read_ms(foo.clone(), 60 * 1000).unwrap();
+            assert_eq!(&*third.data, [3; 128].as_ref());
+            sender.nack(third.id, 0).unwrap();
+
+            // Confirming an object makes room for the next one.
+            consumer.confirm(first.id).unwrap();
+            let third = consumer.receive().unwrap();
+            assert_eq!(&*third.data, [3; 128].as_ref());
+            consumer.confirm(second.id).unwrap();
+            consumer.confirm(third.id).unwrap();
+
+            // Objects sent to the empty queue are pushed as they arrive.
+            sender.send(foo.clone(), &[4; 128]).unwrap();
+            let fourth = consumer.receive().unwrap();
+            assert_eq!(&*fourth.data, [4; 128].as_ref());
+            consumer.confirm(fourth.id).unwrap();
+
+            consumer.unsubscribe().unwrap();
+        }
+
+        // Nothing is pushed once unsubscribed.
+        sender.send(foo.clone(), &[5; 128]).unwrap();
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [5; 128].as_ref());
+        client.confirm(message.id).unwrap();
+
+        match client.subscribe(QueueId::from("bar"), 1, 1000) {
+            Err(ClientError::NoQueue(_)) => {},
+            _ => panic!("Subscribed to a queue which does not exist.")
+        }
+
+        match client.subscribe(foo.clone(), 0, 1000) {
+            Err(ClientError::BadRequest) => {},
+            _ => panic!("Subscribed without room for any objects.")
+        }
+
+        // Nothing else can wait with the id of a ReadWait which is waiting.
+        let mut pipeline = Pipeline::new(net::TcpStream::connect(addr).unwrap());
+        pipeline.handshake().unwrap();
+        let mut stream = pipeline.stream();
+        ClientMessage::ReadWait(StrBox::new("foo"), 1000, 100)
+            .encode_to(&mut stream, 100, MAX_CLIENT_MESSAGE_LEN).unwrap();
+        ClientMessage::Subscribe(StrBox::new("foo"), 1, 1000)
+            .encode_to(&mut stream, 100, MAX_CLIENT_MESSAGE_LEN).unwrap();
+        assert_eq!(ServerMessage::decode_from(&mut stream).unwrap(),
+                   (ServerMessage::BadRequest, 100));
+        assert_eq!(ServerMessage::decode_from(&mut stream).unwrap(), (ServerMessage::Empty, 100));
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_fair_dispatch() {
+        let queues = ConcurrentQueues::new(128);
+        let (addr1, addr2) = (sock(), sock());
+
+        let server1 = Server::with_queues(|x| { thread::spawn(x); },
+                                          timer_config(), 128, queues.clone()).unwrap();
+        let server2 = Server::with_queues(|x| { thread::spawn(x); },
+                                          timer_config(), 128, queues).unwrap();
+        server1.listen(listener(&addr1)).await().unwrap();
+        server2.listen(listener(&addr2)).await().unwrap();
+
+        let mut first = Client::connect(addr1).unwrap();
+        let mut second = Client::connect(addr2).unwrap();
+        let mut sender = Client::connect(addr1).unwrap();
+        let foo = sender.create("foo").unwrap();
+
+        {
+            // Subscribed through different servers, with room for every
+            // object, but the objects are handed out in turn.
+            let mut first = first.subscribe(foo.clone(), 16, 60 * 1000).unwrap();
+            let mut second = second.subscribe(foo.clone(), 16, 60 * 1000).unwrap();
+
+            // Each object is received before the next is sent, so the
+            // subscription which took it is back in line by then.
+            for i in 0..3 {
+                sender.send(foo.clone(), &[i * 2; 128]).unwrap();
+                let message = first.receive().unwrap();
+                assert_eq!(&*message.data, [i * 2; 128].as_ref());
+                first.confirm(message.id).unwrap();
+
+                sender.send(foo.clone(), &[i * 2 + 1; 128]).unwrap();
+                let message = second.receive().unwrap();
+                assert_eq!(&*message.data, [i * 2 + 1; 128].as_ref());
+                second.confirm(message.id).unwrap();
+            }
+        }
+
+        server1.shutdown().await().unwrap();
+        server2.shutdown().await().unwrap();
+
+        // Also in turn between consumers of the same single-threaded queues.
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut first = Client::connect(addr).unwrap();
+        let mut second = Client::connect(addr).unwrap();
+        let mut sender = Client::connect(addr).unwrap();
+        let foo = sender.create("foo").unwrap();
+
+        {
+            let mut first = first.subscribe(foo.clone(), 16, 60 * 1000).unwrap();
+            let mut second = second.subscribe(foo.clone(), 16, 60 * 1000).unwrap();
+
+            for i in 0..3 {
+                sender.send(foo.clone(), &[i * 2; 128]).unwrap();
+                let message = first.receive().unwrap();
+                assert_eq!(&*message.data, [i * 2; 128].as_ref());
+                first.confirm(message.id).unwrap();
+
+                sender.send(foo.clone(), &[i * 2 + 1; 128]).unwrap();
+                let message = second.receive().unwrap();
+                assert_eq!(&*message.data, [i * 2 + 1; 128].as_ref());
+                second.confirm(message.id).unwrap();
+            }
+        }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_topics() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let foo = client.create("foo").unwrap();
+        let bar = client.create("bar").unwrap();
+
+        client.bind("events", foo.clone()).unwrap();
+        client.bind("events", bar.clone()).unwrap();
+
+        // Every bound queue gets its own copy.
+        let ids = client.publish("events", &[1; 128]).unwrap();
+        assert_eq!(ids.len(), 2);
+        assert!(ids[0] != ids[1]);
+
+        for queue in vec![foo.clone(), bar.clone()] {
+            let message = client.read_ms(queue, 1000).unwrap();
+            assert_eq!(&*message.data, [1; 128].as_ref());
+            assert!(ids.contains(&message.id));
+            client.confirm(message.id).unwrap();
+        }
+
+        client.unbind("events", bar.clone()).unwrap();
+        assert_eq!(client.publish("events", &[2; 128]).unwrap().len(), 1);
+        if let ClientError::Empty = client.read_ms(bar.clone(), 1000).unwrap_err() {}
+        else { panic!("Read an object published after the queue was unbound.") }
+
+        if let ClientError::NoQueue(_) = client.bind("events", QueueId::from("baz")).unwrap_err() {}
+        else { panic!("Bound a queue which does not exist.") }
+
+        // Deleting the last bound queue removes the topic.
+        client.delete(foo).unwrap();
+        if let ClientError::NoTopic(_) = client.publish("events", &[3; 128]).unwrap_err() {}
+        else { panic!("Published to a topic with no bound queues.") }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_routing() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let eu = client.create("eu").unwrap();
+        let orders = client.create("orders").unwrap();
+        let all = client.create("all").unwrap();
+
+        client.bind_with_pattern("events", eu.clone(), "orders.*.eu").unwrap();
+        client.bind_with_pattern("events", orders.clone(), "orders.#").unwrap();
+        client.bind_with_pattern("events", all.clone(), "#").unwrap();
+
+        // Bound twice with matching patterns, but only one copy is sent.
+        client.bind_with_pattern("events", all.clone(), "orders.#").unwrap();
+
+        assert_eq!(client.publish_with_key("events", "orders.new.eu", &[1; 128]).unwrap().len(), 3);
+        assert_eq!(client.publish_with_key("events", "orders.new.us", &[2; 128]).unwrap().len(), 2);
+        assert_eq!(client.publish_with_key("events", "orders", &[3; 128]).unwrap().len(), 2);
+        assert_eq!(client.publish_with_key("events", "logs.eu", &[4; 128]).unwrap().len(), 1);
+
+        // Publishing without a key only reaches queues bound to every key.
+        assert_eq!(client.publish("events", &[5; 128]).unwrap().len(), 1);
+
+        let expected = vec![(eu, vec![1]), (orders, vec![1, 2, 3]), (all, vec![1, 2, 3, 4, 5])];
+        for (queue, objects) in expected {
+            for i in objects {
+                let message = client.read_ms(queue.clone(), 1000).unwrap();
+                assert_eq!(&*message.data, [i; 128].as_ref());
+                client.confirm(message.id).unwrap();
+            }
+
+            if let ClientError::Empty = client.read_ms(queue, 1000).unwrap_err() {}
+            else { panic!("Read an object whose routing key did not match.") }
         }
 
-        let (id1, id2, id3, data1, data2, data3) = {
-            let mut responses = client.iter();
-            assert_eq!(responses.next().unwrap(), ServerMessage::QueueCreated);
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_introspection() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let queue = client.create("stats").unwrap();
+
+        for i in 0..3 { client.send(queue.clone(), &[i; 128]).unwrap(); }
+        let stats = client.stats(queue.clone()).unwrap();
+        assert_eq!((stats.depth, stats.enqueued, stats.dequeued), (3, 3, 0));
+        assert!(stats.oldest.is_some());
+
+        let first = client.read_ms(queue.clone(), 0).unwrap();
+        assert_eq!(client.stats(queue.clone()).unwrap().in_flight, 1);
+
+        client.nack(first.id, 10_000).unwrap();
+        let second = client.read_ms(queue.clone(), 0).unwrap();
+        client.confirm(second.id).unwrap();
+        client.send_delayed(queue.clone(), &[3; 128], 10_000).unwrap();
+
+        let stats = client.stats(queue.clone()).unwrap();
+        assert_eq!((stats.depth, stats.in_flight, stats.delayed), (1, 0, 2));
+        assert_eq!((stats.enqueued, stats.dequeued, stats.confirmed, stats.requeued), (3, 2, 1, 0));
+
+        // Names which do not fit in a single response are listed in pages.
+        let mut names = (0..64).map(|i| format!("queue-with-a-long-name-{:02}", i))
+            .collect::<Vec<_>>();
+        for name in &names { client.create(name).unwrap(); }
+        names.push("stats".to_string());
+
+        let expected = names.into_iter().map(QueueId::from).collect::<Vec<_>>();
+        assert_eq!(client.list_queues().unwrap(), expected);
+
+        client.delete(queue.clone()).unwrap();
+        if let ClientError::NoQueue(_) = client.stats(queue).unwrap_err() {}
+        else { panic!("Fetched the statistics of a deleted queue.") }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_peek_and_browse() {
+        let addr = sock();
+        let server = Server::with_queues(|x| { thread::spawn(x); }, timer_config(), 128,
+                                         ConcurrentQueues::new(16)).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let queue = client.create("peek").unwrap();
+
+        let large = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+        for i in 0..5 { client.send(queue.clone(), &[i; 128]).unwrap(); }
+        client.send(queue.clone(), &large).unwrap();
+
+        let peeked = client.peek(queue.clone(), 1, 2).unwrap();
+        assert_eq!(peeked.iter().map(|object| object.data[0]).collect::<Vec<_>>(), vec![1, 2]);
+
+        let message = client.read_ms(queue.clone(), 0).unwrap();
+        client.nack(message.id, 0).unwrap();
 
-            let id1 = unwrap_queued_message(responses.next().unwrap());
-            let id2 = unwrap_queued_message(responses.next().unwrap());
-            let data1 = unwrap_data_message(responses.next().unwrap()).1;
-            let id3 = unwrap_queued_message(responses.next().unwrap());
-            let data2 = unwrap_data_message(responses.next().unwrap()).1;
-            let data3 = unwrap_data_message(responses.next().unwrap()).1;
+        let peeked = client.peek(queue.clone(), 0, 1).unwrap();
+        assert_eq!((peeked[0].id, peeked[0].deliveries), (message.id, 1));
 
-            (id1, id2, id3, data1, data2, data3)
+        // Browsing a page at a time sees every object, including large ones.
+        let browsed = client.browse(queue.clone(), 2).map(|object| object.unwrap().data)
+            .collect::<Vec<_>>();
+        let mut expected = (0..5).map(|i| vec![i; 128]).collect::<Vec<_>>();
+        expected.push(large);
+        assert_eq!(browsed, expected);
+
+        // Nothing was leased or counted as delivered.
+        let message = client.read_ms(queue.clone(), 0).unwrap();
+        assert_eq!((message.data[0], message.deliveries), (0, 2));
+        assert_eq!(client.stats(queue.clone()).unwrap().depth, 5);
+
+        // Objects read between two pages do not make browsing skip any others.
+        let mut reader = Client::connect(addr).unwrap();
+        let browsed = {
+            let mut browser = client.browse(queue.clone(), 2);
+            let mut browsed = vec![browser.next().unwrap().unwrap().data[0],
+                                   browser.next().unwrap().unwrap().data[0]];
+            assert_eq!(reader.read_ms(queue.clone(), 0).unwrap().data[0], 1);
+            browsed.extend(browser.map(|object| object.unwrap().data[0]));
+            browsed
         };
+        assert_eq!(browsed, vec![1, 2, 3, 4, 0]);
 
-        assert_eq!(&*data1, data[0]);
-        assert_eq!(&*data2, data[1]);
-        assert_eq!(&*data3, data[2]);
+        // Browsing ends with an error once the object to continue after is read.
+        {
+            let mut browser = client.browse(queue.clone(), 1);
+            assert_eq!(browser.next().unwrap().unwrap().data[0], 2);
+            assert_eq!(reader.read_ms(queue.clone(), 0).unwrap().data[0], 2);
+            match browser.next() {
+                Some(Err(ClientError::CursorLost)) => {},
+                _ => panic!("Browsed past an object which was read.")
+            }
+            assert!(browser.next().is_none());
+        }
 
-        let requests_phase_2 = [
-            ClientMessage::Confirm(id1),
-            ClientMessage::Confirm(id2),
-            ClientMessage::Confirm(id3),
-        ];
+        client.delete(queue.clone()).unwrap();
+        match client.peek(queue, 0, 1) {
+            Err(ClientError::NoQueue(_)) => {},
+            _ => panic!("Peeked at a deleted queue.")
+        }
 
-        for request in &requests_phase_2 {
-            client.send(request).unwrap();
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_admin_operations() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let dead = client.create("dead").unwrap();
+        let work = client.create_with_options("work", QueueOptions {
+            max_deliveries: 1,
+            dead_letter: Some("dead".to_string()),
+            ttl: 0,
+            ..Default::default()
+        }).unwrap();
+
+        let ids = (0..4).map(|i| client.send(work.clone(), &[i; 128]).unwrap())
+            .collect::<Vec<_>>();
+
+        // Moves the first object to the dead-letter queue.
+        let first = client.read_ms(work.clone(), 0).unwrap();
+        client.nack(first.id, 0).unwrap();
+
+        let leased = client.read_ms(work.clone(), 0).unwrap();
+        match client.delete_message(leased.id) {
+            Err(ClientError::Leased(id)) => assert_eq!(id, leased.id),
+            _ => panic!("Deleted a leased object.")
+        }
+
+        client.delete_message(ids[2]).unwrap();
+        match client.delete_message(ids[2]) {
+            Err(ClientError::NoObject(_)) => {},
+            _ => panic!("Deleted an object twice.")
+        }
+
+        // Moved objects can be delivered again.
+        assert_eq!(client.move_messages(dead.clone(), work.clone(), 16).unwrap(), 1);
+        for &(i, deliveries) in &[(3, 1), (0, 1)] {
+            let message = client.read_ms(work.clone(), 0).unwrap();
+            assert_eq!((message.data[0], message.deliveries), (i, deliveries));
+            client.confirm(message.id).unwrap();
+        }
+
+        for i in 0..5 { client.send(work.clone(), &[i; 128]).unwrap(); }
+        assert_eq!(client.purge(work.clone()).unwrap(), 5);
+        assert_eq!(client.stats(work.clone()).unwrap().depth, 0);
+
+        // Objects which do not fit in the other queue stay where they were.
+        let small = client.create_with_options("small", QueueOptions {
+            max_len: 1,
+            ..Default::default()
+        }).unwrap();
+        for i in 0..3 { client.send(dead.clone(), &[i; 128]).unwrap(); }
+        assert_eq!(client.move_messages(dead.clone(), small.clone(), 16).unwrap(), 1);
+        assert_eq!(client.stats(dead.clone()).unwrap().depth, 2);
+        assert_eq!(client.read_ms(small, 0).unwrap().data[0], 0);
+
+        match client.move_messages(dead.clone(), dead.clone(), 1) {
+            Err(ClientError::BadRequest) => {},
+            _ => panic!("Moved objects to the queue they are in.")
+        }
+
+        // Leased objects are left alone.
+        client.confirm(leased.id).unwrap();
+
+        match client.move_messages(QueueId::from("missing"), work.clone(), 1) {
+            Err(ClientError::NoQueue(ref queue)) if *queue == QueueId::from("missing") => {},
+            _ => panic!("Moved objects from a missing queue.")
+        }
+
+        match client.move_messages(work, QueueId::from("missing"), 1) {
+            Err(ClientError::NoQueue(ref queue)) if *queue == QueueId::from("missing") => {},
+            _ => panic!("Moved objects to a missing queue.")
+        }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_queue_config() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let options = QueueOptions { max_len: 2, lease_timeout: 50, ..Default::default() };
+        let foo = client.create_with_options("foo", options.clone()).unwrap();
+        assert_eq!(client.queue_options(foo.clone()).unwrap(), options);
+
+        client.send(foo.clone(), &[1; 128]).unwrap();
+        client.send(foo.clone(), &[2; 128]).unwrap();
+        match client.send(foo.clone(), &[3; 128]) {
+            Err(ClientError::Full(_, data)) => assert_eq!(&*data, [3; 128].as_ref()),
+            _ => panic!("Sent an object to a full queue.")
+        }
+
+        // Read without a timeout, the object is requeued once the lease
+        // timeout of the queue elapses.
+        let first = client.read_ms(foo.clone(), 0).unwrap();
+        thread::sleep_ms(200);
+        let message = client.read_ms(foo.clone(), 60 * 1000).unwrap();
+        assert_eq!(message.id, first.id);
+        client.confirm(message.id).unwrap();
+
+        client.alter_queue(foo.clone(), QueueOptions {
+            max_len: 2,
+            overflow: Overflow::DropOldest,
+            ..Default::default()
+        }).unwrap();
+
+        // Makes room by dropping the object with 2.
+        client.send(foo.clone(), &[3; 128]).unwrap();
+        client.send(foo.clone(), &[4; 128]).unwrap();
+        let stats = client.stats(foo.clone()).unwrap();
+        assert_eq!((stats.dropped, stats.confirmed), (1, 1));
+        for i in 3..5 {
+            let message = client.read_ms(foo.clone(), 0).unwrap();
+            assert_eq!(message.data[0], i);
+            client.confirm(message.id).unwrap();
+        }
+
+        client.alter_queue(foo.clone(), QueueOptions {
+            max_bytes: 256,
+            overflow: Overflow::Block,
+            ..Default::default()
+        }).unwrap();
+
+        client.send(foo.clone(), &[5; 128]).unwrap();
+        client.send(foo.clone(), &[6; 128]).unwrap();
+
+        // The next send is held back until another client makes room.
+        let reader = thread::spawn(move || {
+            let mut client = Client::connect(addr).unwrap();
+            thread::sleep_ms(100);
+
+            let message = client.read_ms(QueueId::from("foo"), 0).unwrap();
+            client.confirm(message.id).unwrap();
+            message.data[0]
+        });
+
+        client.send(foo.clone(), &[7; 128]).unwrap();
+        assert_eq!(reader.join().unwrap(), 5);
+        assert_eq!(client.stats(foo.clone()).unwrap().depth, 2);
+
+        // A batch is held back until all of it fits, as is a published copy.
+        let reader = thread::spawn(move || {
+            let mut client = Client::connect(addr).unwrap();
+            (0..3).map(|_| {
+                thread::sleep_ms(100);
+                let message = client.read_ms(QueueId::from("foo"), 0).unwrap();
+                client.confirm(message.id).unwrap();
+                message.data[0]
+            }).collect::<Vec<_>>()
+        });
+
+        assert_eq!(client.send_batch(foo.clone(), &[&[8; 128], &[9; 128]]).unwrap().len(), 2);
+        client.bind("overflow", foo.clone()).unwrap();
+        assert_eq!(client.publish("overflow", &[10; 128]).unwrap().len(), 1);
+        assert_eq!(reader.join().unwrap(), vec![6, 7, 8]);
+        assert_eq!(client.stats(foo.clone()).unwrap().depth, 2);
+
+        // Batches holding an object which could never fit are not held back.
+        match client.send_batch(foo.clone(), &[&[11; 128], &[11; 512]]) {
+            Err(ClientError::BatchFull(ref ids)) if ids.is_empty() => {},
+            _ => panic!("Held back a batch which could never fit.")
+        }
+
+        // Objects which could never fit are not held back.
+        match client.send(foo.clone(), &[8; 512]) {
+            Err(ClientError::Full(..)) => {},
+            _ => panic!("Sent an object larger than the queue.")
+        }
+
+        match client.queue_options(QueueId::from("missing")) {
+            Err(ClientError::NoQueue(_)) => {},
+            _ => panic!("Fetched the options of a missing queue.")
+        }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let addr = sock();
+        let limits = Limits { max_memory: 1024, max_queue_memory: 512, ..Default::default() };
+        let server = Server::with_limits(|x| { thread::spawn(x); }, timer_config(), 128,
+                                         ConcurrentQueues::new(16), limits).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let foo = client.create("foo").unwrap();
+        let bar = client.create("bar").unwrap();
+        let baz = client.create("baz").unwrap();
+
+        // Over the budget of the queue.
+        for i in 0..4 { client.send(foo.clone(), &[i; 128]).unwrap(); }
+        match client.send(foo.clone(), &[4; 128]) {
+            Err(ClientError::OverBudget) => {},
+            _ => panic!("Sent an object over the budget of the queue.")
+        }
+        assert_eq!(client.stats(foo.clone()).unwrap().bytes, 512);
+
+        // Over the budget of the server.
+        client.send_batch(bar.clone(), &[&[0; 128], &[1; 128], &[2; 128]]).unwrap();
+        match client.send(baz.clone(), &[0; 256]) {
+            Err(ClientError::OverBudget) => {},
+            _ => panic!("Sent an object over the budget of the server.")
+        }
+
+        let stats = client.memory_stats().unwrap();
+        assert_eq!((stats.queued, stats.leased, stats.budget), (896, 0, 1024));
+
+        // Read objects still count until they are confirmed.
+        let message = client.read_ms(foo.clone(), 0).unwrap();
+        let stats = client.memory_stats().unwrap();
+        assert_eq!((stats.queued, stats.leased), (768, 128));
+        assert_eq!(client.stats(foo.clone()).unwrap().bytes, 384);
+
+        client.confirm(message.id).unwrap();
+        client.send(baz.clone(), &[0; 256]).unwrap();
+
+        // Publishing is refused if any copy would not fit, and no copies are sent.
+        client.bind("all", foo.clone()).unwrap();
+        client.bind("all", bar.clone()).unwrap();
+        match client.publish("all", &[5; 128]) {
+            Err(ClientError::OverBudget) => {},
+            _ => panic!("Published an object over the budget of the server.")
         }
+        assert_eq!(client.stats(foo.clone()).unwrap().depth, 3);
 
-        let mut responses = client.iter();
+        // Deleting a queue frees the memory of its objects.
+        client.delete(baz).unwrap();
+        assert_eq!(client.memory_stats().unwrap().queued, 768);
 
-        assert_eq!(responses.next().unwrap(), ServerMessage::Confirmed);
-        assert_eq!(responses.next().unwrap(), ServerMessage::Confirmed);
-        assert_eq!(responses.next().unwrap(), ServerMessage::Confirmed);
+        // Delayed objects are held by the server until they are delivered.
+        client.send_delayed(bar.clone(), &[6; 128], 60_000).unwrap();
+        let stats = client.memory_stats().unwrap();
+        assert_eq!((stats.queued, stats.held), (768, 128));
+
+        let qux = client.create("qux").unwrap();
+        match client.send_delayed(qux, &[7; 256], 60_000) {
+            Err(ClientError::OverBudget) => {},
+            _ => panic!("Delayed an object over the budget of the server.")
+        }
 
         server.shutdown().await().unwrap();
     }
 
+    #[test]
+    fn test_nack() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+
+        let foo = client.create("foo").unwrap();
+        client.send(foo.clone(), &[1; 128]).unwrap();
+        client.send(foo.clone(), &[2; 128]).unwrap();
+
+        // Nacked without a delay, the object is available again right away.
+        let message = client.read_ms(foo.clone(), 60 * 1000).unwrap();
+        client.nack(message.id, 0).unwrap();
+
+        let message = client.read_ms(foo.clone(), 60 * 1000).unwrap();
+        assert_eq!(&*message.data, [1; 128].as_ref());
+
+        // Nacked with a delay, the next object is read first.
+        client.nack(message.id, 50).unwrap();
+        if let ClientError::Requeued = client.confirm(message.id).unwrap_err() {}
+        else { panic!("Confirmed a nacked object.") }
+
+        let second = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(&*second.data, [2; 128].as_ref());
+        client.confirm(second.id).unwrap();
+
+        thread::sleep_ms(100);
+
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [1; 128].as_ref());
+
+        // The delay outlives the connection the object was nacked through.
+        {
+            let mut other = Client::connect(addr).unwrap();
+            other.nack(message.id, 100).unwrap();
+        }
+
+        thread::sleep_ms(20);
+        if let ClientError::Empty = client.read_ms(foo.clone(), 0).unwrap_err() {}
+        else { panic!("Requeued a nacked object before its delay elapsed.") }
+
+        thread::sleep_ms(150);
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [1; 128].as_ref());
+        client.confirm(message.id).unwrap();
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_extend_lease() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+
+        let foo = client.create("foo").unwrap();
+        client.send(foo.clone(), &[1; 128]).unwrap();
+        client.send(foo.clone(), &[2; 128]).unwrap();
+
+        // Extended by hand, well past the original timeout.
+        let message = client.read_ms(foo.clone(), 20).unwrap();
+        client.extend_lease(message.id, 1000).unwrap();
+        thread::sleep_ms(50);
+        client.confirm(message.id).unwrap();
+
+        // A lease can not be extended forever.
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        match client.extend_lease(message.id, 0) {
+            Err(ClientError::BadRequest) => {},
+            _ => panic!("Extended a lease with no timeout.")
+        }
+        client.nack(message.id, 0).unwrap();
+
+        // Extended in the background while the guard is alive.
+        let message = client.read_ms(foo.clone(), 20).unwrap();
+        match client.keep_alive(message.id, 1) {
+            Err(ClientError::TimeoutTooShort(1)) => {},
+            _ => panic!("Kept a lease alive with a timeout too short to extend it in time.")
+        }
+
+        {
+            let guard = client.keep_alive(message.id, 20).unwrap();
+            thread::sleep_ms(100);
+            assert!(!guard.is_lost());
+        }
+        client.confirm(message.id).unwrap();
+
+        if let ClientError::Empty = client.read_ms(foo, 1000).unwrap_err() {}
+        else { panic!("Extended lease expired.") }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_enqueue_delayed() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+
+        let foo = client.create("foo").unwrap();
+        client.send_delayed(foo.clone(), &[1; 128], 50).unwrap();
+        client.send(foo.clone(), &[2; 128]).unwrap();
+
+        // The delayed object is not visible yet.
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [2; 128].as_ref());
+        client.confirm(message.id).unwrap();
+
+        if let ClientError::Empty = client.read_ms(foo.clone(), 1000).unwrap_err() {}
+        else { panic!("Delayed object delivered early.") }
+
+        thread::sleep_ms(100);
+
+        let message = client.read_ms(foo, 1000).unwrap();
+        assert_eq!(&*message.data, [1; 128].as_ref());
+        client.confirm(message.id).unwrap();
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_dead_letter_queue() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+
+        let dead = client.create("dead").unwrap();
+        let foo = client.create_with_options("foo", QueueOptions {
+            max_deliveries: 2,
+            dead_letter: Some("dead".to_string()),
+            ttl: 0,
+            ..Default::default()
+        }).unwrap();
+        client.send(foo.clone(), &[1; 128]).unwrap();
+
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(message.deliveries, 1);
+        client.nack(message.id, 0).unwrap();
+
+        // Nacked on its last delivery, the object moves to the dead-letter queue.
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(message.deliveries, 2);
+        client.nack(message.id, 0).unwrap();
+
+        if let ClientError::Empty = client.read_ms(foo, 1000).unwrap_err() {}
+        else { panic!("Object not moved to the dead-letter queue.") }
+
+        let message = client.read_ms(dead.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [1; 128].as_ref());
+        client.confirm(message.id).unwrap();
+
+        // Timed out on its last delivery, the object moves too.
+        client.send(foo.clone(), &[2; 128]).unwrap();
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        client.nack(message.id, 0).unwrap();
+        client.read_ms(foo.clone(), 20).unwrap();
+        thread::sleep_ms(100);
+
+        let message = client.read_ms(dead.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [2; 128].as_ref());
+        client.confirm(message.id).unwrap();
+
+        // If the dead-letter queue is deleted while the object is read, the
+        // object is requeued instead of being lost.
+        client.send(foo.clone(), &[3; 128]).unwrap();
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        client.nack(message.id, 0).unwrap();
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        client.delete(dead).unwrap();
+        client.nack(message.id, 0).unwrap();
+
+        let message = client.read_ms(foo, 1000).unwrap();
+        assert_eq!(&*message.data, [3; 128].as_ref());
+        assert_eq!(message.deliveries, 3);
+        client.confirm(message.id).unwrap();
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_ttl() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+
+        let dead = client.create("dead").unwrap();
+        let foo = client.create_with_options("foo", QueueOptions {
+            max_deliveries: 0,
+            dead_letter: Some("dead".to_string()),
+            ttl: 50,
+            ..Default::default()
+        }).unwrap();
+
+        client.send(foo.clone(), &[1; 128]).unwrap();
+        client.send_with_ttl(foo.clone(), &[2; 128], 60 * 1000).unwrap();
+
+        thread::sleep_ms(100);
+
+        // The first object expired, using the default of the queue.
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [2; 128].as_ref());
+        client.confirm(message.id).unwrap();
+
+        if let ClientError::Empty = client.read_ms(foo, 1000).unwrap_err() {}
+        else { panic!("Read an expired object.") }
+
+        let message = client.read_ms(dead, 1000).unwrap();
+        assert_eq!(&*message.data, [1; 128].as_ref());
+        client.confirm(message.id).unwrap();
+
+        server.shutdown().await().unwrap();
+
+        // Queues used without a Server skip expired entries too, and hold
+        // them for the dead-letter queue.
+        let queue = ConcurrentQueue::new(16);
+        let mut expired = Entry::new(Uuid::new_v4(), vec![3; 16]);
+        expired.expires = Some(0);
+        let id = expired.id.clone();
+
+        queue.enqueue(expired).unwrap();
+        queue.enqueue(Entry::new(Uuid::new_v4(), vec![4; 16])).unwrap();
+
+        assert!(queue.peek(0, 16).iter().all(|entry| entry.id != id));
+        assert_eq!(queue.dequeue().unwrap().data, vec![4; 16]);
+        let skipped = queue.expired().into_iter().map(|entry| entry.id).collect::<Vec<_>>();
+        assert_eq!(skipped, vec![id]);
+        assert!(queue.dequeue().is_none() && queue.expired().is_empty());
+    }
+
+    #[test]
+    fn test_priorities() {
+        let addr = sock();
+        let server = Server::with_priorities(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+
+        let foo = client.create("foo").unwrap();
+        client.send(foo.clone(), &[1; 128]).unwrap();
+        client.send(foo.clone(), &[2; 128]).unwrap();
+        client.send_with_priority(foo.clone(), &[3; 128], 10).unwrap();
+
+        // Requeued objects keep their priority.
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [3; 128].as_ref());
+        client.nack(message.id, 0).unwrap();
+
+        for expected in [[3; 128], [1; 128], [2; 128]].iter() {
+            let message = client.read_ms(foo.clone(), 1000).unwrap();
+            assert_eq!(&*message.data, expected.as_ref());
+            client.confirm(message.id).unwrap();
+        }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_priorities() {
+        let queues = ConcurrentPriorityQueues::new(3);
+
+        let (first, second) = (sock(), sock());
+        let server = Server::with_queues(|x| { thread::spawn(x); }, timer_config(), 128,
+                                         queues.clone()).unwrap();
+        let other = Server::with_queues(|x| { thread::spawn(x); }, timer_config(), 128,
+                                        queues).unwrap();
+        server.listen(listener(&first)).await().unwrap();
+        other.listen(listener(&second)).await().unwrap();
+
+        let mut client = Client::connect(first).unwrap();
+        let mut reader = Client::connect(second).unwrap();
+
+        let foo = client.create("foo").unwrap();
+        client.send(foo.clone(), &[1; 128]).unwrap();
+        client.send(foo.clone(), &[2; 128]).unwrap();
+        client.send_with_priority(foo.clone(), &[3; 128], 10).unwrap();
+
+        // The queues are bounded by their capacity.
+        if let ClientError::Full(..) = client.send(foo.clone(), &[4; 128]).unwrap_err() {}
+        else { panic!("Sent more objects than the capacity of the queue.") }
+
+        // Objects sent through one server are read in priority order through
+        // the other, and requeued objects keep their priority.
+        let foo = reader.create("foo").unwrap();
+        let message = reader.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(&*message.data, [3; 128].as_ref());
+        reader.nack(message.id, 0).unwrap();
+
+        for expected in [[3; 128], [1; 128], [2; 128]].iter() {
+            let message = reader.read_ms(foo.clone(), 1000).unwrap();
+            assert_eq!(&*message.data, expected.as_ref());
+            reader.confirm(message.id).unwrap();
+        }
+
+        server.shutdown().await().unwrap();
+        other.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_large_objects() {
+        let addr = sock();
+        let limits = Limits {
+            max_object_len: 64 * 1024,
+            max_message_len: 8 * 1024,
+            ..Default::default()
+        };
+        let server = Server::with_limits(|x| { thread::spawn(x); }, timer_config(), 128,
+                                         ConcurrentQueues::new(16), limits).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+
+        let data = (0..10000).map(|i| i as u8).collect::<Vec<u8>>();
+
+        let foo = client.create("foo").unwrap();
+        client.send(foo.clone(), &data).unwrap();
+
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(message.data, data);
+
+        // Only the connection which read the object can fetch its chunks.
+        let mut other = Pipeline::new(net::TcpStream::connect(addr).unwrap());
+        other.handshake().unwrap();
+        let request = other.send(&ClientMessage::ReadChunk(message.id, 0)).unwrap();
+        match other.receive_for(request).unwrap() {
+            ServerMessage::NoSuchEntity => {},
+            x => panic!("Expected NoSuchEntity, received {:?}", x)
+        }
+
+        client.confirm(message.id).unwrap();
+
+        // Messages up to the limit of the server can be sent whole.
+        client.send_with_ttl(foo.clone(), &[2; 4096], 0).unwrap();
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        assert_eq!(message.data, vec![2; 4096]);
+        client.confirm(message.id).unwrap();
+
+        if let ClientError::TooLarge = client.send(foo, &[1; 100 * 1024]).unwrap_err() {}
+        else { panic!("Uploaded an object over the limit.") }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_batches() {
+        let addr = sock();
+        let server = Server::start(|x| { thread::spawn(x); }).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let foo = client.create("foo").unwrap();
+
+        // More objects than fit in a single batch message.
+        let count = MAX_BATCH + 4;
+        let objects = (0..count).map(|i| vec![i as u8; 100]).collect::<Vec<_>>();
+        let slices = objects.iter().map(|object| &**object).collect::<Vec<&[u8]>>();
+        let ids = client.send_batch(foo.clone(), &slices).unwrap();
+        assert_eq!(ids.len(), count);
+
+        let mut messages = client.read_batch(foo.clone(), count as u32, 1000).unwrap();
+        assert_eq!(messages.len(), MAX_BATCH);
+        messages.extend(client.read_batch(foo.clone(), count as u32, 1000).unwrap().into_iter());
+
+        assert_eq!(messages.iter().map(|message| message.id).collect::<Vec<_>>(), ids);
+        assert_eq!(messages.iter().map(|message| message.data.clone()).collect::<Vec<_>>(),
+                   objects);
+
+        let read = messages.iter().map(|message| message.id).collect::<Vec<_>>();
+        client.confirm_batch(&read).unwrap();
+
+        assert_eq!(client.read_batch(foo.clone(), count as u32, 1000).unwrap().len(), 0);
+        if let ClientError::NotConfirmed(ids) = client.confirm_batch(&read[..1]).unwrap_err() {
+            assert_eq!(ids, &read[..1]);
+        } else { panic!("Confirmed an object twice.") }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_handshake() {
+        let addr = sock();
+        let server = Server::configured(|x| { thread::spawn(x); }, timer_config(), 128).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let client = Client::connect(addr).unwrap();
+        assert!(client.features().iter().any(|feature| *feature == "chunked"));
+
+        // An unknown version is refused with the version the server speaks.
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        Hello { version: PROTOCOL_VERSION + 1, features: vec![] }.encode_to(&mut stream).unwrap();
+        assert_eq!(Welcome::decode_from(&mut stream).unwrap(),
+                   Welcome::Unsupported(PROTOCOL_VERSION));
+
+        // Messages sent before the handshake close the connection.
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        ClientMessage::CreateQueue(StrBox::new("foo"))
+            .encode_to(&mut stream, 0, MAX_CLIENT_MESSAGE_LEN).unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+        // Messages of features which were not agreed on close the connection.
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        Hello { version: PROTOCOL_VERSION, features: vec![] }.encode_to(&mut stream).unwrap();
+        match Welcome::decode_from(&mut stream).unwrap() {
+            Welcome::Accepted(_, features, _) => assert!(features.is_empty()),
+            x => panic!("Expected Accepted, received {:?}", x)
+        }
+        ClientMessage::MemoryStats.encode_to(&mut stream, 1, MAX_CLIENT_MESSAGE_LEN).unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+        // Creating a queue with options is part of "config", whichever options
+        // it sets.
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        Hello { version: PROTOCOL_VERSION, features: vec!["dead-letter".to_string()] }
+            .encode_to(&mut stream).unwrap();
+        match Welcome::decode_from(&mut stream).unwrap() {
+            Welcome::Accepted(_, features, _) => assert_eq!(features, vec!["dead-letter"]),
+            x => panic!("Expected Accepted, received {:?}", x)
+        }
+        ClientMessage::CreateQueueWithOptions(StrBox::new("foo"), Default::default())
+            .encode_to(&mut stream, 1, MAX_CLIENT_MESSAGE_LEN).unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+        // So do messages whose body is not of the type in their header.
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        Hello { version: PROTOCOL_VERSION, features: vec![] }.encode_to(&mut stream).unwrap();
+        Welcome::decode_from(&mut stream).unwrap();
+        let mut frame = Vec::new();
+        ClientMessage::CreateQueue(StrBox::new("foo"))
+            .encode_to(&mut frame, 1, MAX_CLIENT_MESSAGE_LEN).unwrap();
+        let mut header = Header::parse(&frame).unwrap();
+        header.tag = ClientMessage::DeleteQueue(StrBox::new("foo")).tag();
+        stream.write_all(&header.bytes()).unwrap();
+        stream.write_all(&frame[HEADER_LEN..]).unwrap();
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+        // Clients refuse to send them in the first place.
+        let mut pipeline = Pipeline::new(net::TcpStream::connect(addr).unwrap());
+        match pipeline.send(&ClientMessage::MemoryStats) {
+            Err(ClientError::Protocol(ProtocolError::Feature(ref feature)))
+                if *feature == "memory" => {},
+            x => panic!("Expected a feature error, received {:?}", x)
+        }
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_request_pipelining() {
+        let data: [&[u8]; 3] = [&[1; 128], &[2; 128], &[3; 128]];
+
+        let requests_phase_1 = [
+            ClientMessage::CreateQueue(StrBox::new("foo")),
+            ClientMessage::Enqueue(StrBox::new("foo"), SliceBox::new(data[0])),
+            ClientMessage::Enqueue(StrBox::new("foo"), SliceBox::new(data[1])),
+            ClientMessage::Read(StrBox::new("foo"), 1000),
+            ClientMessage::Enqueue(StrBox::new("foo"), SliceBox::new(data[2])),
+            ClientMessage::Read(StrBox::new("foo"), 1000),
+            ClientMessage::Read(StrBox::new("foo"), 1000),
+            // We will send Confirm requests once we get the data.
+        ];
+
+        let addr = sock();
+        let server = Server::start(|x| { thread::spawn(x); }).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = PipelinedClient::connect(addr).unwrap();
+
+        // Send all requests without waiting for responses.
+        let requests = requests_phase_1.iter().map(|request| {
+            client.send(request).unwrap()
+        }).collect::<Vec<_>>();
+
+        // Responses can be received in any order.
+        let data3 = unwrap_data_message(client.receive(requests[6]).unwrap()).1;
+        let id3 = unwrap_queued_message(client.receive(requests[4]).unwrap());
+        let data2 = unwrap_data_message(client.receive(requests[5]).unwrap()).1;
+        let data1 = unwrap_data_message(client.receive(requests[3]).unwrap()).1;
+        let id2 = unwrap_queued_message(client.receive(requests[2]).unwrap());
+        let id1 = unwrap_queued_message(client.receive(requests[1]).unwrap());
+        assert_eq!(client.receive(requests[0]).unwrap(), ServerMessage::QueueCreated);
+
+        assert_eq!(&*data1, data[0]);
+        assert_eq!(&*data2, data[1]);
+        assert_eq!(&*data3, data[2]);
+
+        let requests_phase_2 = [
+            ClientMessage::Confirm(id1),
+            ClientMessage::Confirm(id2),
+            ClientMessage::Confirm(id3),
+        ];
+
+        for request in &requests_phase_2 {
+            client.send(request).unwrap();
+        }
+
+        let responses = client.iter().map(|(_, response)| response).collect::<Vec<_>>();
+        assert_eq!(responses, vec![ServerMessage::Confirmed, ServerMessage::Confirmed,
+                                   ServerMessage::Confirmed]);
+
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_async_client() {
+        let addr = sock();
+        let server = Server::start(|x| { thread::spawn(x); }).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let client = AsyncClient::connect(addr).unwrap();
+        let queue = client.create("foo").await().unwrap();
+
+        // Many operations can be in flight at once.
+        let sent = (0..10u8).map(|i| client.send(queue.clone(), &[i; 32]))
+            .collect::<Vec<_>>();
+        let ids = sent.into_iter().map(|f| f.await().unwrap()).collect::<Vec<_>>();
+
+        let reads = (0..10).map(|_| client.read_ms(queue.clone(), 1000))
+            .collect::<Vec<_>>();
+        let mut messages = reads.into_iter().map(|f| f.await().unwrap()).collect::<Vec<_>>();
+        messages.sort_by(|a, b| a.data.cmp(&b.data));
+
+        for (i, message) in messages.iter().enumerate() {
+            assert_eq!(message.id, ids[i]);
+            assert_eq!(message.data, vec![i as u8; 32]);
+        }
+
+        let confirms = messages.iter().map(|message| client.confirm(message.id))
+            .collect::<Vec<_>>();
+        for confirm in confirms { confirm.await().unwrap(); }
+
+        let empty = client.read_ms(queue.clone(), 0).await().unwrap_err();
+        match empty {
+            AsyncError::Failed(ClientError::Empty) => {},
+            x => panic!("Expected Empty, received {:?}", x)
+        }
+
+        // Large objects are uploaded in turn, and fetched off the reader thread.
+        let large = (0..2).map(|i| vec![i as u8; 3000]).collect::<Vec<_>>();
+        let sent = large.iter().map(|data| client.send(queue.clone(), data))
+            .collect::<Vec<_>>();
+        let ids = sent.into_iter().map(|f| f.await().unwrap()).collect::<Vec<_>>();
+
+        for (i, id) in ids.iter().enumerate() {
+            let message = client.read_ms(queue.clone(), 1000).await().unwrap();
+            assert_eq!(message.id, *id);
+            assert_eq!(message.data, large[i]);
+            client.confirm(message.id).await().unwrap();
+        }
+
+        client.delete(queue).await().unwrap();
+        server.shutdown().await().unwrap();
+    }
+
+    #[test]
+    fn test_durable_queues_survive_restart() {
+        let dir = log_dir();
+
+        let addr = sock();
+        let queues = DurableQueues::open(&dir, ConcurrentQueues::new(128),
+                                         SyncPolicy::Always).unwrap();
+        let server = Server::with_queues(|x| { thread::spawn(x); },
+                                         Default::default(), 128, queues).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+
+        let foo = client.create("foo").unwrap();
+        client.send(foo.clone(), &[1; 64]).unwrap();
+        client.send(foo.clone(), &[2; 64]).unwrap();
+        client.send(foo.clone(), &[3; 64]).unwrap();
+        client.send_delayed(foo.clone(), &[4; 64], 500).unwrap();
+
+        // One entry is confirmed, and one is read but left unconfirmed.
+        let message = client.read_ms(foo.clone(), 1000).unwrap();
+        client.confirm(message.id).unwrap();
+        client.read_ms(foo.clone(), 1000).unwrap();
+
+        drop(client);
+        server.shutdown().await().unwrap();
+
+        let addr = sock();
+        let queues = DurableQueues::open(&dir, ConcurrentQueues::new(128),
+                                         SyncPolicy::Always).unwrap();
+        let server = Server::with_queues(|x| { thread::spawn(x); },
+                                         Default::default(), 128, queues).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let foo = client.create("foo").unwrap();
+
+        // The delayed entry is still held back after the restart.
+        assert_eq!(client.stats(foo.clone()).unwrap().delayed, 1);
+
+        // The entry which was read is requeued in front of the others.
+        let recovered = (0..2).map(|_| {
+            let message = client.read_ms(foo.clone(), 1000).unwrap();
+            client.confirm(message.id).unwrap();
+            message.data
+        }).collect::<Vec<_>>();
+
+        assert_eq!(recovered, vec![vec![2; 64], vec![3; 64]]);
+
+        let delayed = client.read_ms(foo.clone(), 2000).unwrap();
+        assert_eq!(delayed.data, vec![4; 64]);
+        client.confirm(delayed.id).unwrap();
+        assert_eq!(client.stats(foo.clone()).unwrap().delayed, 0);
+
+        if let ClientError::Empty = client.read_ms(foo, 0).unwrap_err() {}
+        else { panic!("Confirmed data was recovered after a restart.") }
+
+        server.shutdown().await().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_durable_queues_restart_from_snapshot() {
+        let dir = log_dir();
+
+        let addr = sock();
+        let queues = DurableQueues::configured(&dir, ConcurrentQueues::new(128),
+                                               SyncPolicy::Never, 512, None).unwrap();
+        let server = Server::with_queues(|x| { thread::spawn(x); },
+                                         Default::default(), 128, queues.clone()).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let foo = client.create("foo").unwrap();
+
+        for i in 0..20 {
+            client.send(foo.clone(), &[i; 64]).unwrap();
+        }
+
+        // Confirm the first ten, and leave one read but unconfirmed.
+        for _ in 0..10 {
+            let message = client.read_ms(foo.clone(), 1000).unwrap();
+            client.confirm(message.id).unwrap();
+        }
+        client.read_ms(foo.clone(), 1000).unwrap();
+
+        queues.snapshot().unwrap();
+        client.send(foo.clone(), &[20; 64]).unwrap();
+
+        let numbered = |extension: &str| fs::read_dir(&dir).unwrap().filter_map(|entry| {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(extension) { return None }
+            path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok())
+        }).collect::<Vec<u64>>();
+
+        let snapshots = numbered("snapshot");
+        assert_eq!(snapshots.len(), 1);
+
+        // The segments included in the snapshot were deleted.
+        let segments = numbered("log");
+        assert!(!segments.is_empty());
+        assert!(segments.iter().all(|&n| n >= snapshots[0]));
+
+        drop(client);
+        server.shutdown().await().unwrap();
+
+        let addr = sock();
+        let queues = DurableQueues::configured(&dir, ConcurrentQueues::new(128),
+                                               SyncPolicy::Never, 512, None).unwrap();
+        let server = Server::with_queues(|x| { thread::spawn(x); },
+                                         Default::default(), 128, queues).unwrap();
+        server.listen(listener(&addr)).await().unwrap();
+
+        let mut client = Client::connect(addr).unwrap();
+        let foo = client.create("foo").unwrap();
+
+        let recovered = (0..11).map(|_| {
+            let message = client.read_ms(foo.clone(), 1000).unwrap();
+            client.confirm(message.id).unwrap();
+            message.data[0]
+        }).collect::<Vec<_>>();
+
+        assert_eq!(recovered, (10..21).collect::<Vec<u8>>());
+        if let ClientError::Empty = client.read_ms(foo, 0).unwrap_err() {}
+        else { panic!("More data recovered than was held before the restart.") }
+
+        drop(client);
+        server.shutdown().await().unwrap();
+
+        // A snapshot written in another version of the format is refused.
+        let path = dir.join(format!("{:020}.snapshot", snapshots[0]));
+        let mut bytes = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes[4] += 1;
+        fs::File::create(&path).unwrap().write_all(&bytes).unwrap();
+
+        let queues = DurableQueues::configured(&dir, ConcurrentQueues::new(128),
+                                               SyncPolicy::Never, 512, None).unwrap();
+        match Server::with_queues(|x| { thread::spawn(x); }, Default::default(), 128, queues) {
+            Err(ServerError::UnknownFormat(ref failed, Some(3))) if *failed == path => {},
+            _ => panic!("Recovered from a snapshot in another format.")
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     fn unwrap_queued_message(message: ServerMessage<'static>) -> Uuid {
         match message {
             ServerMessage::ObjectQueued(id) => id,
@@ -202,7 +1574,7 @@ mod tests {
 
     fn unwrap_data_message(message: ServerMessage<'static>) -> (Uuid, Vec<u8>) {
         match message {
-            ServerMessage::Read(id, data) => (id, data.take()),
+            ServerMessage::Read(id, data, _) => (id, data.take()),
             x => panic!("Expected Read, received {:?}", x)
         }
     }